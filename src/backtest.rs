@@ -1,90 +1,221 @@
-use crate::candles::Candle;
+use crate::candles::{find_gaps, subtract_ranges, Candle, CandleSeries};
+use crate::configuration::{BacktestSettings, GapPolicy, StopFill};
 use crate::error::Error;
 use crate::orders::{Order, Side, Transaction, Type};
 use crate::statistics::Statistics;
+use crate::storage::CandleStore;
 use crate::strategies::Action;
 use crate::strategies::SpotSinglePairStrategy;
 use crate::symbol::Symbol;
-use crate::{storage, utils, wallets};
+use crate::{orders, utils, wallets};
 use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
 use std::collections::HashMap;
 
 const STARTING_BALANCE: f64 = 10000.0;
 
-pub async fn backtest_spot_singlepair(
-    storage: storage::Candles,
-    mut strategy: Box<dyn SpotSinglePairStrategy>,
+// the 1m candles of the backtested window, checked for gaps
+// ranges the exchange has no candles for are not gaps
+pub async fn load_minutes(
+    storage: &dyn CandleStore,
+    exchange: &str,
+    sym: &str,
+    start: &NaiveDate,
+    end: &NaiveDate,
+    policy: GapPolicy,
+) -> Vec<Candle> {
+    let (start_t, end_t) = (start.and_time(NaiveTime::default()), end.and_time(NaiveTime::default()));
+    let minutes = storage.get_minutes(exchange, sym, &start_t, &end_t).await;
+    if policy == GapPolicy::Ignore {
+        return minutes;
+    }
+    let empty = storage.empty_ranges(exchange, sym, &start_t, &end_t).await;
+    let gaps = subtract_ranges(&find_gaps(&minutes, &start_t, &end_t), &empty);
+    if gaps.is_empty() {
+        return minutes;
+    }
+    let missing: i64 = gaps.iter().map(|(from, to)| (*to - *from).num_minutes()).sum();
+    println!("{} - {} minutes missing in {} gaps", sym, missing, gaps.len());
+    for (from, to) in gaps.iter().take(10) {
+        println!("  missing {} -> {}", from, to);
+    }
+    if policy == GapPolicy::Refuse {
+        panic!("{} has gaps, run trader gaps --repair first", sym);
+    }
+    minutes
+}
+
+pub fn backtest_spot_singlepair(
+    series: &CandleSeries,
+    strategy: Box<dyn SpotSinglePairStrategy>,
+    start: NaiveDate,
+    end: NaiveDate,
+    settings: &BacktestSettings,
+) -> Result<(Statistics, wallets::SpotWallet), Error> {
+    let mut bar = progress::Bar::new();
+    bar.set_job_title("backtesting");
+    let res = run_spot_singlepair(series, strategy, start, end, settings, &mut |perc| bar.reach_percent(perc));
+    bar.jobs_done();
+    res
+}
+
+// backtest without any output, progress is reported as percentage
+pub fn run_spot_singlepair(
+    series: &CandleSeries,
+    strategy: Box<dyn SpotSinglePairStrategy>,
     start: NaiveDate,
     end: NaiveDate,
+    settings: &BacktestSettings,
+    on_progress: &mut dyn FnMut(i32),
 ) -> Result<(Statistics, wallets::SpotWallet), Error> {
     // set up
     let end_t = end.and_time(NaiveTime::default());
     let start_t = start.and_time(NaiveTime::default());
-    let mut start_time = start_t;
-    let depth = strategy.get_candles_history_size();
-    let mut tstamp = start_time + (*(strategy.time_frame()) * (depth as i32));
+    let mut slot = Slot::new(strategy, series, &start_t);
 
     // preparing the environment
     let mut wallet = wallets::SpotWallet { assets: HashMap::new() };
-    wallet.assets.insert(strategy.symbol().quote.clone(), STARTING_BALANCE);
-    wallet.assets.insert(strategy.symbol().base.clone(), 0.0);
-    let mut outstanding_orders: Vec<Order> = Vec::new();
-    let mut transactions: Vec<Transaction> = Vec::new();
-
-    // strategy init
-    let init_cndl_size = strategy.get_candles_init_size();
-    if init_cndl_size > 0 {
-        let cnds = storage
-            .get(
-                strategy.exchange(),
-                &strategy.symbol().symbol,
-                &start_time,
-                &end_t,
-                strategy.time_frame(),
-                init_cndl_size,
-            )
-            .await;
-        if cnds.len() != init_cndl_size {
-            panic!("could not initialize the strategy");
-        }
-        strategy.initialize(cnds.as_slice());
-        tstamp += *(strategy.time_frame());
-        start_time = tstamp - (*(strategy.time_frame()) * init_cndl_size as i32);
+    wallet.assets.insert(slot.strategy.symbol().quote.clone(), STARTING_BALANCE);
+    wallet.assets.insert(slot.strategy.symbol().base.clone(), 0.0);
+    if let Some(fees_asset) = &settings.fees_asset {
+        *wallet.assets.entry(fees_asset.clone()).or_insert(0.0) += settings.fees_asset_balance;
     }
 
     // performance tracking
     let mut stats = Statistics::new(STARTING_BALANCE);
 
+    while let Some(last) = slot.next_candle(&end_t) {
+        let perc = (last.tstamp - start_t).num_minutes() * 100 / (end_t - start_t).num_minutes();
+        on_progress(perc as i32);
+
+        // check outstanding orders with current candle
+        slot.process_orders(&mut wallet, &mut stats, settings);
+
+        // processing new candle signal
+        let mut price_update: HashMap<String, f64> = HashMap::new();
+        if let Some(fees_asset) = &settings.fees_asset {
+            price_update.insert(fees_asset.clone(), settings.fees_asset_price);
+        }
+        price_update.insert(slot.strategy.symbol().base.clone(), last.close);
+        price_update.insert(slot.strategy.symbol().quote.clone(), 1.0);
+        stats.update_with_last_prices(last.tstamp + last.tframe, &wallet, &price_update);
+        slot.process_candle(&wallet, &mut stats);
+    }
+    Ok((stats, wallet))
+}
+
+// all the strategies trade at the same time out of a shared wallet, like in live trading
+// candles are processed in order of closing time across all the strategies
+// and strategies only see the funds not locked by outstanding orders
+pub fn backtest_portfolio(
+    strategies: Vec<(Box<dyn SpotSinglePairStrategy>, &CandleSeries)>,
+    start: NaiveDate,
+    end: NaiveDate,
+    settings: &BacktestSettings,
+) -> Result<(Statistics, wallets::SpotWallet), Error> {
+    // set up
+    let end_t = end.and_time(NaiveTime::default());
+    let start_t = start.and_time(NaiveTime::default());
+    let mut slots: Vec<Slot> = strategies
+        .into_iter()
+        .map(|(strategy, series)| Slot::new(strategy, series, &start_t))
+        .collect();
+
+    // preparing the environment
+    let mut wallet = wallets::SpotWallet { assets: HashMap::new() };
+    let mut prices: HashMap<String, f64> = HashMap::new();
+    for slot in &slots {
+        let sym = slot.strategy.symbol();
+        wallet.assets.insert(sym.quote.clone(), STARTING_BALANCE);
+        wallet.assets.entry(sym.base.clone()).or_insert(0.0);
+        prices.insert(sym.quote.clone(), 1.0);
+        prices.insert(sym.base.clone(), slot.next_candle(&end_t).map_or(0.0, |cnd| cnd.open));
+    }
+    if let Some(fees_asset) = &settings.fees_asset {
+        *wallet.assets.entry(fees_asset.clone()).or_insert(0.0) += settings.fees_asset_balance;
+        prices.entry(fees_asset.clone()).or_insert(settings.fees_asset_price);
+    }
+
+    // performance tracking
+    let balance_start = wallet
+        .assets
+        .iter()
+        .fold(0.0, |balance, (asset, amount)| balance + prices.get(asset).unwrap_or(&0.0) * amount);
+    let mut stats = Statistics::new(balance_start);
+
     let mut bar = progress::Bar::new();
-    bar.set_job_title("backtesting");
-    while tstamp < end_t {
-        let perc = (tstamp - start_t).num_minutes() * 100 / (end_t - start_t).num_minutes();
+    bar.set_job_title("backtesting portfolio");
+    loop {
+        // the strategy whose next candle closes first
+        let next = slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.next_candle(&end_t).map(|cnd| (i, cnd)))
+            .min_by_key(|(_, cnd)| cnd.tstamp + cnd.tframe);
+        let (i, last) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        let perc = (last.tstamp - start_t).num_minutes() * 100 / (end_t - start_t).num_minutes();
         bar.reach_percent(perc as i32);
 
-        // gather current candles
-        let mut cnds = storage
-            .get(
-                strategy.exchange(),
-                &strategy.symbol().symbol,
-                &start_time,
-                &end_t,
-                strategy.time_frame(),
-                depth,
-            )
-            .await;
-        if cnds.len() < depth {
-            break;
-        }
-        cnds.reverse();
-        let last = cnds.first().unwrap();
-
         // check outstanding orders with current candle
+        slots[i].process_orders(&mut wallet, &mut stats, settings);
+
+        // processing new candle signal
+        prices.insert(slots[i].strategy.symbol().base.clone(), last.close);
+        stats.update_with_last_prices(last.tstamp + last.tframe, &wallet, &prices);
+        let free = free_wallet(&wallet, &slots, &prices);
+        slots[i].process_candle(&free, &mut stats);
+    }
+    bar.jobs_done();
+    Ok((stats, wallet))
+}
 
+// a strategy with its candles and outstanding orders
+struct Slot<'a> {
+    strategy: Box<dyn SpotSinglePairStrategy>,
+    series: &'a CandleSeries,
+    outstanding_orders: Vec<Order>,
+    idx: usize,
+}
+
+impl<'a> Slot<'a> {
+    fn new(mut strategy: Box<dyn SpotSinglePairStrategy>, series: &'a CandleSeries, start_t: &NaiveDateTime) -> Self {
+        let depth = strategy.get_candles_history_size();
+        let init_cndl_size = strategy.get_candles_init_size();
+        // first candle with enough history behind it, candles preceding start are used when available
+        let idx = series.index_at(start_t).max(init_cndl_size).max(depth.max(1) - 1);
+
+        // strategy init
+        if init_cndl_size > 0 {
+            if idx >= series.len() {
+                panic!("could not initialize the strategy");
+            }
+            strategy.initialize(series.init_history(idx, init_cndl_size).as_slice());
+        }
+        Self {
+            strategy,
+            series,
+            outstanding_orders: Vec::new(),
+            idx,
+        }
+    }
+
+    fn next_candle(&self, end_t: &NaiveDateTime) -> Option<&'a Candle> {
+        if self.idx < self.series.len() && self.series.candle(self.idx).tstamp < *end_t {
+            Some(self.series.candle(self.idx))
+        } else {
+            None
+        }
+    }
+
+    fn process_orders(&mut self, wallet: &mut wallets::SpotWallet, stats: &mut Statistics, settings: &BacktestSettings) {
+        let last = self.series.candle(self.idx);
         // any expired orders?
         let mut i = 0;
-        while i < outstanding_orders.len() {
-            if is_expired(&outstanding_orders[i], last) {
-                let ord = outstanding_orders.remove(i);
+        while i < self.outstanding_orders.len() {
+            if is_expired(&self.outstanding_orders[i], last) {
+                let ord = self.outstanding_orders.remove(i);
                 stats.update_with_expired_order(&ord);
             } else {
                 i += 1;
@@ -94,10 +225,10 @@ pub async fn backtest_spot_singlepair(
         loop {
             let mut next_tx: Option<Transaction> = None;
             // find first order that can be fullfilled
-            for or in &outstanding_orders {
+            for or in &self.outstanding_orders {
                 let tx = if order_in_candle(or, last) {
                     // order price limit is within the current candle (or order is MARKET)
-                    generate_tx_from_order(&or, last, &storage).await.expect("process_order")
+                    generate_tx_from_order(or, last, self.series, settings).expect("process_order")
                 } else {
                     Transaction::default()
                 };
@@ -106,53 +237,92 @@ pub async fn backtest_spot_singlepair(
                     next_tx = Some(tx);
                 }
             }
-            if let Some(tx) = next_tx {
-                if let Some(tp_sl_or) = order_from_tp_sl_tx(&tx) {
-                    outstanding_orders.push(tp_sl_or);
+            if let Some(mut tx) = next_tx {
+                let fees = charge_fees(&mut tx, wallet, settings);
+                // strategies sharing the wallet may have spent the funds since the order was placed
+                if !can_afford(&tx, self.strategy.symbol(), wallet) {
+                    self.outstanding_orders.retain(|or| or.id != tx.order.id);
+                    stats.update_with_rejected_order(&tx.order);
+                    continue;
+                }
+                for tp_sl_or in order_from_tp_sl_tx(&tx) {
+                    stats.update_with_order(&tp_sl_or);
+                    self.outstanding_orders.push(tp_sl_or);
                 }
 
-                outstanding_orders.retain(|or| or.id != tx.order.id);
-                let action = strategy.on_new_transaction(outstanding_orders.as_slice(), &tx);
+                self.outstanding_orders
+                    .retain(|or| or.id != tx.order.id && (tx.order.oco_ref == 0 || or.id != tx.order.oco_ref));
+                let action = self.strategy.on_new_transaction(self.outstanding_orders.as_slice(), &tx);
 
-                stats.update_with_transaction(&tx);
-                update_wallet(&tx, strategy.symbol(), &mut wallet);
+                stats.update_with_transaction(&tx, fees);
+                update_wallet(&tx, self.strategy.symbol(), wallet);
 
-                transactions.push(tx);
-                on_action(action, &mut stats, &mut outstanding_orders);
+                on_action(action, stats, &mut self.outstanding_orders);
             } else {
                 break;
             }
         }
+    }
 
-        // processing new candle signal
-        let mut price_update: HashMap<String, f64> = HashMap::new();
-        price_update.insert(strategy.symbol().base.clone(), last.close);
-        price_update.insert(strategy.symbol().quote.clone(), 1.0);
-        stats.update_with_last_prices(&wallet, &price_update);
-        let action = strategy.on_new_candle(&wallet, outstanding_orders.as_slice(), cnds.as_slice());
-        on_action(action, &mut stats, &mut outstanding_orders);
+    fn process_candle(&mut self, wallet: &wallets::SpotWallet, stats: &mut Statistics) {
+        let cnds = self.series.history(self.idx, self.strategy.get_candles_history_size());
+        let action = self.strategy.on_new_candle(wallet, self.outstanding_orders.as_slice(), cnds);
+        on_action(action, stats, &mut self.outstanding_orders);
+        self.idx += 1;
+    }
+}
 
-        tstamp += *(strategy.time_frame());
-        start_time = tstamp - (*(strategy.time_frame()) * depth as i32);
+// the wallet as the exchange reports it, net of the funds locked by outstanding orders
+fn free_wallet(wallet: &wallets::SpotWallet, slots: &[Slot], prices: &HashMap<String, f64>) -> wallets::SpotWallet {
+    let mut free = wallets::SpotWallet {
+        assets: wallet.assets.clone(),
+    };
+    for slot in slots {
+        lock_funds(&mut free, &slot.outstanding_orders, prices);
+    }
+    free
+}
+
+// takes the funds locked by the orders out of the wallet
+pub fn lock_funds(wallet: &mut wallets::SpotWallet, orders: &[Order], prices: &HashMap<String, f64>) {
+    for ord in orders {
+        // both legs of an OCO pair lock the same funds
+        if ord.oco_ref != 0 && ord.oco_ref < ord.id && orders.iter().any(|o| o.id == ord.oco_ref) {
+            continue;
+        }
+        let (asset, amount) = match (&ord.side, &ord.o_type) {
+            (Side::Buy, Type::Limit(price)) | (Side::Buy, Type::StopLoss(price)) => (&ord.symbol.quote, price * ord.volume),
+            (Side::Buy, Type::Market) => (
+                &ord.symbol.quote,
+                prices.get(&ord.symbol.base).unwrap_or(&0.0) * ord.volume,
+            ),
+            (Side::Sell, _) => (&ord.symbol.base, ord.volume),
+        };
+        if let Some(balance) = wallet.assets.get_mut(asset) {
+            *balance = (*balance - amount).max(0.0);
+        }
     }
-    bar.jobs_done();
-    Ok((stats, wallet))
 }
 
-fn order_in_candle(ord: &Order, last: &Candle) -> bool {
+pub fn order_in_candle(ord: &Order, last: &Candle) -> bool {
     match (&ord.o_type, &ord.side) {
         (Type::Market, _) => true,
         (Type::Limit(buy_p), Side::Buy) => *buy_p >= last.low,
         (Type::Limit(sell_p), Side::Sell) => *sell_p <= last.high,
-        (Type::StopLoss(_), _) => unimplemented!("no stop Loss"),
-        //(_, _) => false,
+        (Type::StopLoss(stop_p), Side::Sell) => *stop_p >= last.low,
+        (Type::StopLoss(stop_p), Side::Buy) => *stop_p <= last.high,
     }
 }
-fn is_expired(ord: &Order, last: &Candle) -> bool {
-    ord.expire.map_or(false, |date| last.tstamp > date)
+pub fn is_expired(ord: &Order, last: &Candle) -> bool {
+    ord.expire.is_some_and(|date| last.tstamp > date)
 }
 
-async fn generate_tx_from_order(ord: &Order, last: &Candle, store: &storage::Candles) -> Result<Transaction, Error> {
+fn generate_tx_from_order(
+    ord: &Order,
+    last: &Candle,
+    series: &CandleSeries,
+    settings: &BacktestSettings,
+) -> Result<Transaction, Error> {
     let mut tx = Transaction {
         symbol: ord.symbol.symbol.clone(),
         side: ord.side.clone(),
@@ -167,28 +337,87 @@ async fn generate_tx_from_order(ord: &Order, last: &Candle, store: &storage::Can
     match (&ord.o_type, &ord.side) {
         (Type::Market, _) => Ok(tx),
         (Type::Limit(buy_p), Side::Buy) => {
-            let t = store
-                .find_lower(&ord.exchange, &ord.symbol.symbol, &last.tstamp, &end_t, *buy_p)
-                .await
+            let t = series
+                .find_lower(&last.tstamp, &end_t, *buy_p)
                 .ok_or_else(|| Error::ErrNotFound(format!("can't find lower for {}", *buy_p)))?;
             tx.avg_price = *buy_p;
             tx.tstamp = t;
             Ok(tx)
         }
         (Type::Limit(sell_p), Side::Sell) => {
-            let t = store
-                .find_higher(&ord.exchange, &ord.symbol.symbol, &last.tstamp, &end_t, *sell_p)
-                .await
+            let t = series
+                .find_higher(&last.tstamp, &end_t, *sell_p)
                 .ok_or_else(|| Error::ErrNotFound(format!("can't find higher for {}", *sell_p)))?;
             tx.avg_price = *sell_p;
             tx.tstamp = t;
             Ok(tx)
-        } 
-        (Type::StopLoss(_), _) => unimplemented!("no stop Loss"),
+        }
+        (Type::StopLoss(stop_p), side) => {
+            let t = match side {
+                Side::Sell => series.find_lower(&last.tstamp, &end_t, *stop_p),
+                Side::Buy => series.find_higher(&last.tstamp, &end_t, *stop_p),
+            }
+            .ok_or_else(|| Error::ErrNotFound(format!("can't find stop trigger for {}", *stop_p)))?;
+            let trigger = series
+                .minute(&t)
+                .ok_or_else(|| Error::ErrNotFound(format!("can't find trigger candle at {}", t)))?;
+            tx.avg_price = stop_fill_price(side, *stop_p, trigger.open, settings);
+            tx.tstamp = t;
+            Ok(tx)
+        }
     }
 }
 
-fn update_wallet(tx: &Transaction, sym: &Symbol, wallet: &mut wallets::SpotWallet) {
+// same as generate_tx_from_order, for when only the candle itself is known
+pub fn generate_tx_from_candle(ord: &Order, last: &Candle, settings: &BacktestSettings) -> Transaction {
+    let avg_price = match (&ord.o_type, &ord.side) {
+        (Type::Market, _) => last.open,
+        (Type::Limit(price), _) => *price,
+        (Type::StopLoss(stop_p), side) => stop_fill_price(side, *stop_p, last.open, settings),
+    };
+    Transaction {
+        symbol: ord.symbol.symbol.clone(),
+        side: ord.side.clone(),
+        order: ord.clone(),
+        avg_price,
+        fees: 0.0,
+        fees_asset: ord.symbol.quote.clone(),
+        volume: ord.volume,
+        tstamp: last.tstamp,
+    }
+}
+
+fn stop_fill_price(side: &Side, stop: f64, open: f64, settings: &BacktestSettings) -> f64 {
+    let price = match (settings.stop_fill, side) {
+        (StopFill::Stop, _) => stop,
+        (StopFill::Open, _) => open,
+        (StopFill::Gap, Side::Sell) => stop.min(open),
+        (StopFill::Gap, Side::Buy) => stop.max(open),
+    };
+    let slippage = settings.stop_slippage_perc / 100.0;
+    match side {
+        Side::Sell => price * (1.0 - slippage),
+        Side::Buy => price * (1.0 + slippage),
+    }
+}
+
+// whether the wallet holds enough to pay for the transaction and its fees
+pub fn can_afford(tx: &Transaction, sym: &Symbol, wallet: &wallets::SpotWallet) -> bool {
+    // balance changes, as update_wallet applies them
+    let mut deltas: HashMap<&str, f64> = HashMap::new();
+    let (spent, spent_amount, got, got_amount) = match tx.side {
+        Side::Buy => (&sym.quote, tx.avg_price * tx.volume, &sym.base, tx.volume),
+        Side::Sell => (&sym.base, tx.volume, &sym.quote, tx.avg_price * tx.volume),
+    };
+    *deltas.entry(spent).or_insert(0.0) -= spent_amount;
+    *deltas.entry(got).or_insert(0.0) += got_amount;
+    *deltas.entry(&tx.fees_asset).or_insert(0.0) -= tx.fees;
+    deltas
+        .iter()
+        .all(|(asset, delta)| !(wallet.assets.get(*asset).unwrap_or(&0.0) + delta).is_sign_negative())
+}
+
+pub fn update_wallet(tx: &Transaction, sym: &Symbol, wallet: &mut wallets::SpotWallet) {
     assert_eq!(tx.symbol, sym.symbol);
     match tx.side {
         Side::Buy => {
@@ -200,6 +429,7 @@ fn update_wallet(tx: &Transaction, sym: &Symbol, wallet: &mut wallets::SpotWalle
             *wallet.assets.get_mut(&sym.base).expect("no base in wallet") -= tx.volume;
         }
     };
+    *wallet.assets.entry(tx.fees_asset.clone()).or_insert(0.0) -= tx.fees;
     wallet.assets.values().for_each(|v| {
         if v.is_sign_negative() {
             panic!("wallet is negative {:?} with tx {:?}", wallet, tx)
@@ -207,8 +437,31 @@ fn update_wallet(tx: &Transaction, sym: &Symbol, wallet: &mut wallets::SpotWalle
     });
 }
 
-fn order_from_tp_sl_tx(_tx: &Transaction) -> Option<Order> {
-    None
+// market orders take liquidity, limit orders are resting on the book
+// returns the fees value in the quote asset
+pub fn charge_fees(tx: &mut Transaction, wallet: &wallets::SpotWallet, settings: &BacktestSettings) -> f64 {
+    let rate = match tx.order.o_type {
+        Type::Market | Type::StopLoss(_) => settings.taker_fees(),
+        Type::Limit(_) => settings.maker_fees(),
+    };
+    let value = tx.avg_price * tx.volume * rate;
+    tx.fees = value;
+    tx.fees_asset = tx.order.symbol.quote.clone();
+    // like the exchange does, fall back to the quote asset when the fees asset is exhausted
+    if let Some(fees_asset) = &settings.fees_asset {
+        if settings.fees_asset_price > 0.0 {
+            let fees = value / settings.fees_asset_price;
+            if *wallet.assets.get(fees_asset).unwrap_or(&0.0) >= fees {
+                tx.fees = fees;
+                tx.fees_asset = fees_asset.clone();
+            }
+        }
+    }
+    value
+}
+
+fn order_from_tp_sl_tx(tx: &Transaction) -> Vec<Order> {
+    orders::bracket_orders(&tx.order, tx)
 }
 
 fn on_action(action: Action, stats: &mut Statistics, outstanding_orders: &mut Vec<Order>) {
@@ -225,3 +478,132 @@ fn on_action(action: Action, stats: &mut Statistics, outstanding_orders: &mut Ve
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies::Action;
+
+    // buys a fixed volume at market on its first candle, whatever the wallet holds
+    struct FixedBuy {
+        sym: Symbol,
+        time_frame: Duration,
+        volume: f64,
+        done: bool,
+    }
+
+    impl SpotSinglePairStrategy for FixedBuy {
+        fn name(&self) -> String {
+            format!("FixedBuy-{}", self.sym.symbol)
+        }
+        fn on_new_candle(&mut self, _wallet: &wallets::SpotWallet, _outstanding: &[Order], _history: &[Candle]) -> Action {
+            if self.done {
+                return Action::None;
+            }
+            self.done = true;
+            let mut order = Order::new();
+            order.symbol = self.sym.clone();
+            order.volume = self.volume;
+            Action::NewOrder(order)
+        }
+        fn on_new_transaction(&mut self, _outstanding: &[Order], _tx: &Transaction) -> Action {
+            Action::None
+        }
+        fn get_candles_history_size(&self) -> usize {
+            1
+        }
+        fn exchange(&self) -> &str {
+            "test"
+        }
+        fn symbol(&self) -> &Symbol {
+            &self.sym
+        }
+        fn time_frame(&self) -> &Duration {
+            &self.time_frame
+        }
+    }
+
+    fn symbol(base: &str, quote: &str) -> Symbol {
+        let mut sym = Symbol::new(format!("{}{}", base, quote));
+        sym.base = String::from(base);
+        sym.quote = String::from(quote);
+        sym
+    }
+
+    fn flat_minutes(price: f64, count: i64) -> Vec<Candle> {
+        let start = NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        (0..count)
+            .map(|i| Candle {
+                tstamp: start + Duration::minutes(i),
+                tframe: Duration::minutes(1),
+                open: price,
+                close: price,
+                low: price,
+                high: price,
+                volume: 1.0,
+            })
+            .collect()
+    }
+
+    fn settings() -> BacktestSettings {
+        serde_json::from_value(serde_json::json!({ "fees_perc": 0.1 })).unwrap()
+    }
+
+    #[test]
+    fn portfolio_rejects_fills_the_shared_wallet_cannot_pay() {
+        let btc = CandleSeries::new(flat_minutes(5000.0, 10), Duration::minutes(1));
+        let eth = CandleSeries::new(flat_minutes(5000.0, 10), Duration::minutes(1));
+        let strategies: Vec<(Box<dyn SpotSinglePairStrategy>, &CandleSeries)> = vec![
+            (
+                Box::new(FixedBuy {
+                    sym: symbol("BTC", "USDT"),
+                    time_frame: Duration::minutes(1),
+                    volume: 1.5,
+                    done: false,
+                }),
+                &btc,
+            ),
+            (
+                Box::new(FixedBuy {
+                    sym: symbol("ETH", "USDT"),
+                    time_frame: Duration::minutes(1),
+                    volume: 1.5,
+                    done: false,
+                }),
+                &eth,
+            ),
+        ];
+        let start = NaiveDate::from_ymd_opt(2021, 1, 1).unwrap();
+        let end = NaiveDate::from_ymd_opt(2021, 1, 2).unwrap();
+        let (stats, wallet) = backtest_portfolio(strategies, start, end, &settings()).unwrap();
+        // 1.5 * 5000 twice is more than the 10000 USDT in the wallet
+        assert_eq!(stats.tx_history.len(), 1);
+        assert_eq!(stats.canceled_orders, 1);
+        let usdt = wallet.assets["USDT"];
+        assert!((usdt - (10000.0 - 7500.0 - 7.5)).abs() < 1e-6, "usdt {}", usdt);
+    }
+
+    #[test]
+    fn can_afford_counts_sell_proceeds_for_fees() {
+        let sym = symbol("BTC", "USDT");
+        let mut order = Order::new();
+        order.symbol = sym.clone();
+        order.side = Side::Sell;
+        let tx = Transaction {
+            symbol: sym.symbol.clone(),
+            side: Side::Sell,
+            avg_price: 100.0,
+            volume: 1.0,
+            fees: 0.1,
+            fees_asset: String::from("USDT"),
+            order,
+            ..Transaction::default()
+        };
+        let mut wallet = wallets::SpotWallet::default();
+        wallet.assets.insert(String::from("BTC"), 1.0);
+        wallet.assets.insert(String::from("USDT"), 0.0);
+        assert!(can_afford(&tx, &sym, &wallet));
+        wallet.assets.insert(String::from("BTC"), 0.5);
+        assert!(!can_afford(&tx, &sym, &wallet));
+    }
+}
@@ -1,9 +1,10 @@
-use chrono::{Duration, NaiveDateTime};
+use crate::utils;
+use chrono::{Datelike, Duration, Months, NaiveDate, NaiveDateTime};
 use serde::Deserialize;
 use std::convert::TryFrom;
 use std::fmt;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Candle {
     pub tstamp: NaiveDateTime, // refers to start timestamp
     pub tframe: Duration,
@@ -17,13 +18,13 @@ pub struct Candle {
 
 impl fmt::Display for Candle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        return write!(f, "[{} [{} -> {}]", self.tstamp, self.open, self.close);
+        write!(f, "[{} [{} -> {}]", self.tstamp, self.open, self.close)
     }
 }
 
 impl Candle {
     pub fn get_time_interval(&self) -> (NaiveDateTime, NaiveDateTime) {
-        (self.tstamp.clone(), self.tstamp + self.tframe)
+        (self.tstamp, self.tstamp + self.tframe)
     }
 }
 
@@ -47,3 +48,341 @@ impl TryFrom<&Candle> for ta::DataItem {
             .build()
     }
 }
+
+// a humantime month, 30.44 days
+const MONTH_SECS: i64 = 2_630_016;
+
+// number of months of a time frame made of humantime months
+fn months(tframe: &Duration) -> Option<u32> {
+    let secs = tframe.num_seconds();
+    if secs > 0 && secs % MONTH_SECS == 0 && *tframe == Duration::seconds(secs) {
+        Some((secs / MONTH_SECS) as u32)
+    } else {
+        None
+    }
+}
+
+// start of the time frame bucket the timestamp belongs to
+// buckets are aligned to the epoch, weeks start on monday and months on the 1st like on the exchanges
+pub fn bucket_start(tstamp: &NaiveDateTime, tframe: &Duration) -> NaiveDateTime {
+    if let Some(months) = months(tframe) {
+        let elapsed = (tstamp.year() - 1970) * 12 + tstamp.month0() as i32;
+        let start = elapsed - elapsed.rem_euclid(months as i32);
+        return NaiveDate::from_ymd_opt(1970 + start.div_euclid(12), start.rem_euclid(12) as u32 + 1, 1)
+            .expect("first of the month")
+            .and_hms_opt(0, 0, 0)
+            .expect("midnight");
+    }
+    let week = Duration::weeks(1);
+    let origin = if tframe.num_seconds() % week.num_seconds() == 0 {
+        NaiveDate::from_ymd_opt(1970, 1, 5).expect("first monday").and_hms_opt(0, 0, 0).expect("midnight")
+    } else {
+        utils::from_timestamp(0, 0).expect("epoch")
+    };
+    let elapsed = tstamp.signed_duration_since(origin).num_seconds();
+    *tstamp - Duration::seconds(elapsed.rem_euclid(tframe.num_seconds()))
+}
+
+// calendar months are not all the same length, the other buckets are
+pub fn bucket_length(start: &NaiveDateTime, tframe: &Duration) -> Duration {
+    match months(tframe) {
+        Some(months) => start.checked_add_months(Months::new(months)).expect("in adding months") - *start,
+        None => *tframe,
+    }
+}
+
+// groups consecutive candles (oldest first) into time frame buckets
+pub fn aggregate(cnds: &[Candle], tframe: &Duration) -> Vec<Candle> {
+    let mut aggr: Vec<Candle> = Vec::new();
+    for cnd in cnds {
+        let start = bucket_start(&cnd.tstamp, tframe);
+        match aggr.last_mut() {
+            Some(last) if last.tstamp == start => {
+                last.low = last.low.min(cnd.low);
+                last.high = last.high.max(cnd.high);
+                last.close = cnd.close;
+                last.volume += cnd.volume;
+            }
+            _ => aggr.push(Candle {
+                tstamp: start,
+                tframe: bucket_length(&start, tframe),
+                ..*cnd
+            }),
+        }
+    }
+    aggr
+}
+
+// adds a closed candle to the time frame candle being built
+// a candle from another bucket starts a new one
+pub fn merge(partial: &mut Option<Candle>, cnd: &Candle, tframe: &Duration) {
+    let start = bucket_start(&cnd.tstamp, tframe);
+    match partial {
+        Some(last) if last.tstamp == start => {
+            last.low = last.low.min(cnd.low);
+            last.high = last.high.max(cnd.high);
+            last.close = cnd.close;
+            last.volume += cnd.volume;
+        }
+        _ => {
+            *partial = Some(Candle {
+                tstamp: start,
+                tframe: bucket_length(&start, tframe),
+                ..*cnd
+            })
+        }
+    }
+}
+
+// ranges [from, to) with no 1m candle within [start, end)
+pub fn find_gaps(minutes: &[Candle], start: &NaiveDateTime, end: &NaiveDateTime) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let mut gaps = Vec::new();
+    let mut expected = *start;
+    for cnd in minutes.iter().filter(|cnd| cnd.tstamp >= *start && cnd.tstamp < *end) {
+        if cnd.tstamp > expected {
+            gaps.push((expected, cnd.tstamp));
+        }
+        expected = cnd.tstamp + Duration::minutes(1);
+    }
+    if expected < *end {
+        gaps.push((expected, *end));
+    }
+    gaps
+}
+
+// the parts of the ranges not covered by the cuts, both sorted
+pub fn subtract_ranges(
+    ranges: &[(NaiveDateTime, NaiveDateTime)],
+    cuts: &[(NaiveDateTime, NaiveDateTime)],
+) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let mut left = Vec::new();
+    for (from, to) in ranges {
+        let mut cursor = *from;
+        for (cut_from, cut_to) in cuts.iter().filter(|(cut_from, cut_to)| cut_to > from && cut_from < to) {
+            if *cut_from > cursor {
+                left.push((cursor, *cut_from));
+            }
+            cursor = cursor.max(*cut_to);
+        }
+        if cursor < *to {
+            left.push((cursor, *to));
+        }
+    }
+    left
+}
+
+// all the candles of a symbol, loaded once
+// time frame candles are aggregated from the 1m ones and kept newest first
+// so that strategy histories are plain slices
+pub struct CandleSeries {
+    tframe: Duration,
+    minutes: Vec<Candle>,
+    candles: Vec<Candle>,
+}
+
+impl CandleSeries {
+    // minutes: 1m candles, oldest first
+    pub fn new(minutes: Vec<Candle>, tframe: Duration) -> Self {
+        let mut candles = aggregate(&minutes, &tframe);
+        candles.reverse();
+        Self {
+            tframe,
+            minutes,
+            candles,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.candles.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.candles.is_empty()
+    }
+    pub fn time_frame(&self) -> &Duration {
+        &self.tframe
+    }
+
+    // idx: 0 -> oldest candle
+    pub fn candle(&self, idx: usize) -> &Candle {
+        &self.candles[self.candles.len() - 1 - idx]
+    }
+
+    // index of the first candle starting at or after tstamp
+    pub fn index_at(&self, tstamp: &NaiveDateTime) -> usize {
+        self.candles.len() - self.candles.partition_point(|cnd| cnd.tstamp >= *tstamp)
+    }
+
+    // depth candles ending with the one at idx
+    // history: 0 -> newest candle
+    pub fn history(&self, idx: usize, depth: usize) -> &[Candle] {
+        let newest = self.candles.len() - 1 - idx;
+        &self.candles[newest..newest + depth]
+    }
+
+    // size candles preceding idx
+    // history: 0 -> oldest candle
+    pub fn init_history(&self, idx: usize, size: usize) -> Vec<Candle> {
+        let newest = self.candles.len() - idx;
+        self.candles[newest..newest + size].iter().rev().cloned().collect()
+    }
+
+    // 1m candles within [start, end)
+    pub fn minutes(&self, start: &NaiveDateTime, end: &NaiveDateTime) -> &[Candle] {
+        let first = self.minutes.partition_point(|cnd| cnd.tstamp < *start);
+        let last = self.minutes.partition_point(|cnd| cnd.tstamp < *end);
+        &self.minutes[first..last.max(first)]
+    }
+
+    pub fn minute(&self, tstamp: &NaiveDateTime) -> Option<&Candle> {
+        self.minutes(tstamp, &(*tstamp + Duration::minutes(1))).first()
+    }
+
+    pub fn find_lower(&self, start: &NaiveDateTime, end: &NaiveDateTime, price: f64) -> Option<NaiveDateTime> {
+        self.minutes(start, end).iter().find(|cnd| cnd.low <= price).map(|cnd| cnd.tstamp)
+    }
+
+    pub fn find_higher(&self, start: &NaiveDateTime, end: &NaiveDateTime, price: f64) -> Option<NaiveDateTime> {
+        self.minutes(start, end).iter().find(|cnd| cnd.high >= price).map(|cnd| cnd.tstamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2021, 1, day).unwrap().and_hms_opt(hour, min, 0).unwrap()
+    }
+
+    fn minute(tstamp: NaiveDateTime, price: f64) -> Candle {
+        Candle {
+            tstamp,
+            tframe: Duration::minutes(1),
+            open: price,
+            close: price,
+            low: price,
+            high: price,
+            volume: 1.0,
+        }
+    }
+
+    // minutes priced by their minute of the hour
+    fn minutes(from: u32, count: u32) -> Vec<Candle> {
+        (from..from + count).map(|min| minute(at(1, 0, min), min as f64)).collect()
+    }
+
+    #[test]
+    fn series_index_oldest_first_and_slice_newest_first() {
+        let series = CandleSeries::new(minutes(0, 20), Duration::minutes(5));
+        assert_eq!(series.len(), 4);
+        assert_eq!(series.candle(0).tstamp, at(1, 0, 0));
+        assert_eq!((series.candle(1).open, series.candle(1).close), (5.0, 9.0));
+        assert_eq!(series.candle(3).volume, 5.0);
+        assert_eq!(series.index_at(&at(1, 0, 10)), 2);
+        assert_eq!(series.index_at(&at(1, 0, 7)), 2);
+        assert_eq!(series.index_at(&at(1, 1, 0)), 4);
+        let history: Vec<NaiveDateTime> = series.history(2, 2).iter().map(|cnd| cnd.tstamp).collect();
+        assert_eq!(history, vec![at(1, 0, 10), at(1, 0, 5)]);
+        let init: Vec<NaiveDateTime> = series.init_history(3, 2).iter().map(|cnd| cnd.tstamp).collect();
+        assert_eq!(init, vec![at(1, 0, 5), at(1, 0, 10)]);
+    }
+
+    #[test]
+    fn series_minutes_within_ranges() {
+        let series = CandleSeries::new(minutes(0, 20), Duration::minutes(5));
+        assert_eq!(series.minutes(&at(1, 0, 3), &at(1, 0, 6)).len(), 3);
+        assert!(series.minutes(&at(1, 0, 30), &at(1, 0, 40)).is_empty());
+        assert_eq!(series.minute(&at(1, 0, 4)).map(|cnd| cnd.close), Some(4.0));
+        assert_eq!(series.find_lower(&at(1, 0, 10), &at(1, 0, 20), 12.0), Some(at(1, 0, 10)));
+        assert_eq!(series.find_higher(&at(1, 0, 10), &at(1, 0, 20), 12.0), Some(at(1, 0, 12)));
+        assert_eq!(series.find_higher(&at(1, 0, 10), &at(1, 0, 12), 12.0), None);
+    }
+
+    #[test]
+    fn buckets_align_to_the_epoch_and_weeks_to_mondays() {
+        assert_eq!(bucket_start(&at(1, 10, 7), &Duration::minutes(5)), at(1, 10, 5));
+        assert_eq!(bucket_start(&at(1, 10, 5), &Duration::minutes(5)), at(1, 10, 5));
+        assert_eq!(bucket_start(&at(1, 10, 7), &Duration::hours(4)), at(1, 8, 0));
+        assert_eq!(bucket_start(&at(1, 10, 7), &Duration::days(1)), at(1, 0, 0));
+        // 2021-01-01 is a friday
+        assert_eq!(
+            bucket_start(&at(1, 10, 7), &Duration::weeks(1)),
+            NaiveDate::from_ymd_opt(2020, 12, 28).unwrap().and_hms_opt(0, 0, 0).unwrap()
+        );
+        assert_eq!(bucket_start(&at(4, 0, 0), &Duration::weeks(1)), at(4, 0, 0));
+    }
+
+    #[test]
+    fn month_buckets_follow_the_calendar() {
+        let month = Duration::seconds(MONTH_SECS);
+        let day = |y: i32, m: u32, d: u32| NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        assert_eq!(bucket_start(&at(31, 23, 59), &month), day(2021, 1, 1));
+        assert_eq!(bucket_start(&day(2021, 2, 28), &month), day(2021, 2, 1));
+        assert_eq!(bucket_start(&day(2021, 5, 17), &(month * 3)), day(2021, 4, 1));
+        assert_eq!(bucket_start(&day(1969, 12, 5), &month), day(1969, 12, 1));
+        assert_eq!(bucket_length(&day(2021, 2, 1), &month), Duration::days(28));
+        // binance 1M kline of january 2021: opens at 1609459200000, closes at 1612137599999
+        let january = Duration::milliseconds(1612137599999 - 1609459200000 + 1);
+        let aggr = aggregate(&[minute(at(1, 0, 0), 1.0), minute(at(31, 23, 59), 2.0)], &month);
+        assert_eq!(aggr.len(), 1);
+        assert_eq!((aggr[0].tstamp, aggr[0].tframe), (day(2021, 1, 1), january));
+        let mut partial = None;
+        merge(&mut partial, &minute(at(31, 23, 59), 2.0), &month);
+        assert_eq!(partial.map(|cnd| cnd.tstamp + cnd.tframe), Some(day(2021, 2, 1)));
+    }
+
+    #[test]
+    fn aggregation_and_merging_agree() {
+        let mut cnds = minutes(3, 10);
+        cnds[1].low = 1.0;
+        cnds[1].high = 20.0;
+        let aggr = aggregate(&cnds, &Duration::minutes(5));
+        assert_eq!(aggr.len(), 3);
+        assert_eq!(aggr[0].tstamp, at(1, 0, 0));
+        assert_eq!(aggr[0].tframe, Duration::minutes(5));
+        assert_eq!((aggr[0].open, aggr[0].high, aggr[0].low, aggr[0].close), (3.0, 20.0, 1.0, 4.0));
+        assert_eq!(aggr[0].volume, 2.0);
+        assert_eq!(aggr[2].tstamp, at(1, 0, 10));
+        let mut partial = None;
+        for cnd in &cnds[..2] {
+            merge(&mut partial, cnd, &Duration::minutes(5));
+        }
+        assert_eq!(partial, Some(aggr[0]));
+        // the next bucket starts over
+        for cnd in &cnds[2..7] {
+            merge(&mut partial, cnd, &Duration::minutes(5));
+        }
+        assert_eq!(partial, Some(aggr[1]));
+    }
+
+    #[test]
+    fn gaps_between_and_around_minutes() {
+        let cnds: Vec<Candle> = [1, 2, 5, 9].iter().map(|min| minute(at(1, 0, *min), 1.0)).collect();
+        assert_eq!(
+            find_gaps(&cnds, &at(1, 0, 0), &at(1, 0, 8)),
+            vec![(at(1, 0, 0), at(1, 0, 1)), (at(1, 0, 3), at(1, 0, 5)), (at(1, 0, 6), at(1, 0, 8))]
+        );
+        assert!(find_gaps(&cnds, &at(1, 0, 1), &at(1, 0, 3)).is_empty());
+        assert_eq!(find_gaps(&[], &at(1, 0, 0), &at(1, 0, 5)), vec![(at(1, 0, 0), at(1, 0, 5))]);
+    }
+
+    #[test]
+    fn subtracting_ranges_keeps_the_uncovered_parts() {
+        let ranges = vec![(at(1, 0, 0), at(1, 0, 10)), (at(1, 0, 20), at(1, 0, 30))];
+        let cuts = vec![
+            (at(1, 0, 2), at(1, 0, 4)),
+            (at(1, 0, 3), at(1, 0, 6)),
+            (at(1, 0, 8), at(1, 0, 22)),
+        ];
+        assert_eq!(
+            subtract_ranges(&ranges, &cuts),
+            vec![
+                (at(1, 0, 0), at(1, 0, 2)),
+                (at(1, 0, 6), at(1, 0, 8)),
+                (at(1, 0, 22), at(1, 0, 30))
+            ]
+        );
+        assert_eq!(subtract_ranges(&ranges, &[]), ranges);
+        assert!(subtract_ranges(&ranges, &[(at(1, 0, 0), at(1, 0, 30))]).is_empty());
+    }
+}
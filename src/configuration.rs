@@ -3,14 +3,96 @@ use std::convert::TryInto;
 
 #[derive(Debug, serde::Deserialize, Clone)]
 pub struct ExchangeSettings {
+    #[serde(default)]
     pub api_key: String,
+    #[serde(default)]
     pub secret_key: String,
     pub backtest: BacktestSettings,
+    // milliseconds a signed request stays valid, the exchange default when missing
+    #[serde(default)]
+    pub recv_window: Option<u64>,
+    // only used by the sim exchange
+    #[serde(default)]
+    pub sim: Option<SimSettings>,
+}
+
+// replay of recorded 1m candles, for running the live loop offline
+#[derive(Debug, serde::Deserialize, Clone)]
+pub struct SimSettings {
+    // csv file with symbol,tstamp,open,high,low,close,volume rows
+    #[serde(default)]
+    pub csv: Option<String>,
+    // otherwise candles are read from the candle storage, in the table of the given exchange
+    #[serde(default)]
+    pub candle_storage: Option<String>,
+    #[serde(default)]
+    pub exchange: Option<String>,
+    pub start: chrono::NaiveDate,
+    pub end: chrono::NaiveDate,
+    // replayed minutes per real minute, 0 replays as fast as possible
+    #[serde(default)]
+    pub speed: f64,
+    // starting balances, symbols are made of two of these assets
+    pub balances: HashMap<String, f64>,
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
 pub struct BacktestSettings {
-    fees_perc : f64,
+    // percentages, i.e. 0.1 means 0.1% of the traded value
+    pub fees_perc: f64,
+    // maker/taker rates override fees_perc when present
+    #[serde(default)]
+    pub maker_fees_perc: Option<f64>,
+    #[serde(default)]
+    pub taker_fees_perc: Option<f64>,
+    // fees are charged in the quote asset unless a fees asset (i.e. BNB) is configured
+    #[serde(default)]
+    pub fees_asset: Option<String>,
+    // price of the fees asset expressed in the quote asset
+    #[serde(default)]
+    pub fees_asset_price: f64,
+    #[serde(default)]
+    pub fees_asset_balance: f64,
+    #[serde(default)]
+    pub stop_fill: StopFill,
+    // adverse slippage applied to stop fills, as percentage
+    #[serde(default)]
+    pub stop_slippage_perc: f64,
+    // what to do when minutes are missing from the backtested window
+    #[serde(default)]
+    pub gaps: GapPolicy,
+}
+
+#[derive(Debug, serde::Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StopFill {
+    // filled at the stop price
+    #[default]
+    Stop,
+    // filled at the open of the minute the stop triggered in
+    Open,
+    // filled at the stop price, or at the open when the market gapped through the stop
+    Gap,
+}
+
+#[derive(Debug, serde::Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GapPolicy {
+    Ignore,
+    // lists the missing ranges and goes on
+    #[default]
+    Warn,
+    // stops before backtesting
+    Refuse,
+}
+
+impl BacktestSettings {
+    pub fn maker_fees(&self) -> f64 {
+        self.maker_fees_perc.unwrap_or(self.fees_perc) / 100.0
+    }
+    pub fn taker_fees(&self) -> f64 {
+        self.taker_fees_perc.unwrap_or(self.fees_perc) / 100.0
+    }
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
@@ -21,6 +103,42 @@ pub struct StrategySettings {
     #[serde(deserialize_with = "chrono_duration_de")]
     pub time_frame: chrono::Duration,
     pub settings: HashMap<String,String>,
+    // tells our orders apart, required when the exchange runs more than one strategy
+    #[serde(default)]
+    pub id: Option<u16>,
+}
+
+impl StrategySettings {
+    // changes whenever the strategy would behave differently, settings in key order
+    pub fn fingerprint(&self) -> String {
+        let mut settings: Vec<String> = self.settings.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        settings.sort();
+        format!(
+            "{}|{}|{}|{}|{}",
+            self.name,
+            self.exchange,
+            self.symbol,
+            self.time_frame.num_seconds(),
+            settings.join(",")
+        )
+    }
+}
+
+// instance ids of the strategies of an exchange, in config order
+// they end up in the client order ids, the snapshots and the positions each strategy owns,
+// so they are never derived from the config order: a lone strategy may leave it out, otherwise all set it
+pub fn instance_ids(strategies: &[StrategySettings]) -> Result<Vec<u16>, String> {
+    let ids: Vec<u16> = strategies.iter().filter_map(|st| st.id).collect();
+    if ids.is_empty() && strategies.len() == 1 {
+        return Ok(vec![0]);
+    }
+    if let Some(st) = strategies.iter().find(|st| st.id.is_none()) {
+        return Err(format!("strategy {} on {} has no id, every strategy of the exchange needs one", st.name, st.symbol));
+    }
+    if let Some(id) = ids.iter().find(|id| ids.iter().filter(|other| other == id).count() > 1) {
+        return Err(format!("strategy id {} used twice", id));
+    }
+    Ok(ids)
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -48,3 +166,30 @@ D: serde::Deserializer<'de>,
     use serde::de::Error;
     chrono::Duration::from_std(humantime_serde::deserialize(des)?).map_err(|_| D::Error::custom("out of range"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strategy(name: &str, id: Option<u16>) -> StrategySettings {
+        StrategySettings {
+            name: String::from(name),
+            exchange: String::from("binance"),
+            symbol: String::from("BTCUSDT"),
+            time_frame: chrono::Duration::minutes(15),
+            settings: HashMap::new(),
+            id,
+        }
+    }
+
+    #[test]
+    fn instance_ids_never_come_from_the_config_order() {
+        assert_eq!(instance_ids(&[strategy("macd1", None)]), Ok(vec![0]));
+        assert_eq!(instance_ids(&[strategy("macd1", Some(7))]), Ok(vec![7]));
+        assert_eq!(instance_ids(&[strategy("macd1", Some(7)), strategy("macd2", Some(3))]), Ok(vec![7, 3]));
+        assert!(instance_ids(&[strategy("macd1", None), strategy("macd2", None)]).is_err());
+        // mixing explicit and implicit ids
+        assert!(instance_ids(&[strategy("macd1", Some(1)), strategy("macd2", None)]).is_err());
+        assert!(instance_ids(&[strategy("macd1", Some(1)), strategy("macd2", Some(1))]).is_err());
+    }
+}
@@ -1,11 +1,12 @@
 use crate::candles;
 use crate::drivers::{LiveEvent, LiveFeed, RestApi, Tick};
-use crate::error::Error;
+use crate::error::{Error, INVALID_TIMESTAMP, NO_SUCH_ORDER, TOO_MANY_REQUESTS};
 use crate::orders;
 use crate::orders::Transaction;
 use crate::symbol::Symbol;
 use crate::wallets::SpotWallet;
 use async_trait::async_trait;
+use awc::http::Method;
 use awc::ws::Message;
 use awc::ws::{Codec, Frame};
 use awc::{BoxedSocket, Client};
@@ -17,21 +18,30 @@ use log::{debug, info, warn, error};
 use openssl::hash::MessageDigest;
 use openssl::pkey::{PKey, Private};
 use openssl::sign::Signer;
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::rc::Rc;
 
+use super::binance_limits::RateLimiter;
 use super::binance_types::*;
 
+const CLOCK_SYNC_MINUTES: i64 = 10;
+
 #[derive(Clone)]
 pub struct Rest {
     url: String,
     api_key: String,
     secret: PKey<Private>,
     client: Client,
+    limiter: Rc<RefCell<RateLimiter>>,
+    // server time minus local time, and when it was measured
+    clock: Rc<RefCell<Option<(Duration, NaiveDateTime)>>>,
+    recv_window: Option<u64>,
 }
 
 impl Rest {
-    pub fn new(api_key: &str, secret_word: &str) -> Rest {
+    pub fn new(api_key: &str, secret_word: &str, recv_window: Option<u64>) -> Rest {
         let secret = PKey::hmac(secret_word.as_bytes()).expect("cannot create private key from secret");
         let client = Client::builder()
             .header("User-Agent", "trader/0.0.1")
@@ -44,58 +54,234 @@ impl Rest {
             client,
             api_key: String::from(api_key),
             secret,
+            limiter: Rc::new(RefCell::new(RateLimiter::new())),
+            clock: Rc::new(RefCell::new(None)),
+            recv_window,
+        }
+    }
+
+    // hex hmac-sha256 of the query string, sent as the signature parameter
+    fn sign(&self, query: &str) -> Result<String, Error> {
+        let digest = Signer::new(MessageDigest::sha256(), &self.secret)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(query.as_bytes()))
+            .map_err(|e| Error::Unexpected(Box::new(e)))?;
+        Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    // server time minus local time, measured again every CLOCK_SYNC_MINUTES
+    async fn clock_offset(&self) -> Result<Duration, Error> {
+        let now = Utc::now().naive_utc();
+        let synced = *self.clock.borrow();
+        match synced {
+            Some((offset, at)) if now - at < Duration::minutes(CLOCK_SYNC_MINUTES) => Ok(offset),
+            _ => self.sync_clock().await,
+        }
+    }
+
+    async fn get_klines(
+        &self,
+        sym: &str,
+        interval: &str,
+        start: Option<&NaiveDateTime>,
+        limit: usize,
+    ) -> Result<Vec<candles::Candle>, Error> {
+        let mut queries: Vec<(String, String)> =
+            start.map_or(Vec::new(), |st| vec![(String::from("startTime"), format!("{}000", st.and_utc().timestamp()))]);
+        queries.push((String::from("symbol"), String::from(sym)));
+        queries.push((String::from("interval"), String::from(interval)));
+        queries.push((String::from("limit"), limit.to_string()));
+        let url = self.url.clone() + "/api/v3/klines";
+        let request = self.client.get(url).query(&queries).map_err(|e| Error::Parse(e.to_string()))?;
+        self.execute::<Vec<Candle>>(request, klines_weight(limit), 0)
+            .await?
+            .drain(0..)
+            .map(candles::Candle::try_from)
+            .collect()
+    }
+
+    // time frames with no klines are built from the largest native interval fitting in them
+    // only closed candles are returned, aligned like in backtests
+    async fn get_aggregated_klines(
+        &self,
+        sym: &str,
+        interval: &Duration,
+        start: Option<&NaiveDateTime>,
+        limit: usize,
+    ) -> Result<Vec<candles::Candle>, Error> {
+        let (base, base_interval) = base_interval(interval).ok_or(Error::ErrTimeFrameNotSupported)?;
+        let now = Utc::now().naive_utc();
+        let span = *interval * limit as i32;
+        let from = match start {
+            Some(start) => candles::bucket_start(start, interval),
+            None => candles::bucket_start(&now, interval) - span,
+        };
+        let to = (from + span).min(now);
+        let mut cnds = Vec::new();
+        let mut next = from;
+        while next < to {
+            let chunk = self.get_klines(sym, base, Some(&next), 1000).await?;
+            match chunk.last() {
+                Some(last) => next = last.tstamp + base_interval,
+                None => break,
+            }
+            cnds.extend(chunk);
+        }
+        Ok(candles::aggregate(&cnds, interval)
+            .into_iter()
+            .filter(|cnd| cnd.tstamp >= from && cnd.tstamp + cnd.tframe <= to)
+            .take(limit)
+            .collect())
+    }
+
+    // the offset assumes the server stamped the response halfway through the round trip,
+    // which is timed once the limiter lets the request through
+    async fn sync_clock(&self) -> Result<Duration, Error> {
+        let url = self.url.clone() + "/api/v3/time";
+        self.load_limits().await?;
+        self.throttle(1, 0).await;
+        let before = Utc::now();
+        let server = self.send_request::<ServerTime>(self.client.get(url)).await?;
+        let after = Utc::now();
+        let local = before + (after - before) / 2;
+        let offset = Duration::milliseconds(server.server_time - local.timestamp_millis());
+        info!("binance - server clock offset {}ms", offset.num_milliseconds());
+        *self.clock.borrow_mut() = Some((offset, after.naive_utc()));
+        Ok(offset)
+    }
+
+    // the limits come with exchangeInfo, they are read once before the first request
+    async fn load_limits(&self) -> Result<(), Error> {
+        if !self.limiter.borrow().is_loaded() {
+            let url = self.url.clone() + "/api/v3/exchangeInfo";
+            self.throttle(20, 0).await;
+            let info = self.send_request::<ExchangeInfo>(self.client.get(url)).await?;
+            self.limiter.borrow_mut().set_limits(&info.rate_limits);
+        }
+        Ok(())
+    }
+
+    async fn execute<T: serde::de::DeserializeOwned>(
+        &self,
+        request: awc::ClientRequest,
+        weight: u32,
+        orders: u32,
+    ) -> Result<T, Error> {
+        self.load_limits().await?;
+        self.throttle(weight, orders).await;
+        self.send_request(request).await
+    }
+
+    // signed requests are stamped and signed once the limiter lets them through,
+    // a stamp taken before waiting out a weight window or a ban would fall out of the recvWindow
+    async fn execute_signed<T: serde::de::DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        mut queries: Vec<(String, String)>,
+        weight: u32,
+        orders: u32,
+    ) -> Result<T, Error> {
+        self.load_limits().await?;
+        // the clock is synced before waiting, the offset is applied when the request goes
+        let offset = self.clock_offset().await?;
+        self.throttle(weight, orders).await;
+        queries.extend(timing(Utc::now(), offset, self.recv_window));
+        let request = self
+            .client
+            .request(method, self.url.clone() + path)
+            .query(&queries)
+            .map_err(|e| Error::Parse(e.to_string()))?;
+        let signature = self.sign(request.get_uri().query().unwrap_or_default())?;
+        queries.push((String::from("signature"), signature));
+        let request = request.query(&queries).map_err(|e| Error::Parse(e.to_string()))?;
+        self.send_request(request).await
+    }
+
+    // waits for the weight and orders to be available and books them
+    async fn throttle(&self, weight: u32, orders: u32) {
+        loop {
+            let now = Utc::now().naive_utc();
+            let wait = self.limiter.borrow_mut().delay(&now, weight, orders);
+            match wait {
+                Some(wait) => {
+                    warn!("binance - rate limit reached, waiting {}s", wait.num_seconds());
+                    actix_rt::time::delay_for(wait.to_std().unwrap_or_default()).await;
+                }
+                None => {
+                    self.limiter.borrow_mut().spend(&now, weight, orders);
+                    break;
+                }
+            }
+        }
+    }
+
+    // sends the request and parses the response
+    // binance errors come as {"code": -2010, "msg": "..."}, rate limits as 429 / 418 with Retry-After
+    async fn send_request<T: serde::de::DeserializeOwned>(&self, request: awc::ClientRequest) -> Result<T, Error> {
+        let mut response = request.send().await.map_err(|e| Error::Network(format!("{:?}", e)))?;
+        let status = response.status();
+        self.limiter.borrow_mut().update(&Utc::now().naive_utc(), response.headers());
+        let body = response
+            .body()
+            .limit(128_000_000)
+            .await
+            .map_err(|e| Error::Network(format!("{:?}", e)))?;
+        if status.is_success() {
+            return serde_json::from_slice::<T>(&body).map_err(|e| Error::Parse(format!("{} in {:?}", e, body)));
+        }
+        if status.as_u16() == 429 || status.as_u16() == 418 {
+            let retry_after = response
+                .headers()
+                .get("Retry-After")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            warn!("binance - rate limited, retry after {:?}", retry_after);
+            self.limiter
+                .borrow_mut()
+                .ban(&Utc::now().naive_utc(), retry_after.unwrap_or(60));
+            return Err(Error::RateLimited(retry_after));
+        }
+        match serde_json::from_slice::<ErrorResponse>(&body) {
+            Ok(err) if err.code == TOO_MANY_REQUESTS => Err(Error::RateLimited(None)),
+            Ok(err) if err.code == INVALID_TIMESTAMP => {
+                // the clock drifted, it's synced again on the next signed request
+                *self.clock.borrow_mut() = None;
+                Err(Error::Exchange(err.code, err.msg))
+            }
+            Ok(err) => Err(Error::Exchange(err.code, err.msg)),
+            Err(_) => Err(Error::Http(status.as_u16(), String::from_utf8_lossy(&body).to_string())),
         }
     }
 }
 
 #[async_trait(?Send)]
 impl RestApi for Rest {
-    async fn refresh_ws_token(&self, old_token: Option<String>) -> String {
+    async fn refresh_ws_token(&self, old_token: Option<String>) -> Result<String, Error> {
         let url = self.url.clone() + "/api/v3/userDataStream";
         if let Some(token) = old_token {
-            let resp = self
+            let request = self
                 .client
                 .put(url)
                 .query(&[("listenKey", &token)])
-                .expect("in building put ws token")
-                .send()
-                .await
-                .expect("in sending listen_key");
-            if !resp.status().is_success() {
-                panic!("token refresh failed {:?}", resp)
-            }
-            token
+                .map_err(|e| Error::Parse(e.to_string()))?;
+            self.execute::<serde_json::Value>(request, 2, 0).await?;
+            Ok(token)
         } else {
-            self.client
-                .post(url)
-                .send()
-                .await
-                .expect("in sending listen_key request")
-                .json::<ListenKey>()
-                .await
-                .expect("in parsing userDataStream response")
-                .listen_key
+            Ok(self.execute::<ListenKey>(self.client.post(url), 2, 0).await?.listen_key)
         }
     }
 
     async fn get_symbol_info(&self, sym: &str) -> Result<Symbol, Error> {
         let url = self.url.clone() + "/api/v3/exchangeInfo";
-        let request = self.client.get(url);
-        let mut info = request
-            .send()
-            .await
-            .expect("in send binance exchange info request")
-            .json::<ExchangeInfo>()
-            .limit(128_000_000)
-            .await
-            .expect("in json::<ExchangeInfo>");
-        let x = info
+        self.throttle(20, 0).await;
+        let info = self.send_request::<ExchangeInfo>(self.client.get(url)).await?;
+        self.limiter.borrow_mut().set_limits(&info.rate_limits);
+        let info = info
             .symbols
-            .drain(0..)
+            .into_iter()
             .find(|sym_info| sym_info.symbol == sym)
-            .map(|info| info.into())
-            .ok_or_else(|| Error::ErrNotFound(format!("can't find symbol {}", sym)));
-        x
+            .ok_or_else(|| Error::ErrNotFound(format!("can't find symbol {}", sym)))?;
+        Symbol::try_from(info)
     }
 
     async fn get_candles(
@@ -104,137 +290,62 @@ impl RestApi for Rest {
         maybe_interval: Option<&Duration>,
         start: Option<&NaiveDateTime>,
         limit: Option<usize>,
-    ) -> Vec<candles::Candle> {
+    ) -> Result<Vec<candles::Candle>, Error> {
         let interval = *maybe_interval.unwrap_or(&Duration::minutes(1));
-        let mut queries: Vec<(String, String)> =
-            start.map_or(Vec::new(), |st| vec![(String::from("startTime"), format!("{}000", st.timestamp()))]);
-        queries.push((String::from("symbol"), String::from(sym)));
-        queries.push((String::from("interval"), to_interval(&interval)));
-        queries.push((String::from("limit"), limit.unwrap_or(1000).to_string()));
-        let url = self.url.clone() + "/api/v3/klines";
-        let request = self.client.get(url).query(&queries).expect("in adding queries");
-        let mut response = request.send().await.expect("in send binance klines request");
-        response
-            .json::<Vec<Candle>>()
-            .limit(128_000_000)
-            .await
-            .expect("in json<Vec<Candle>>")
-            .drain(0..)
-            .filter_map(|cnd| {
-                let candle = candles::Candle::from(cnd);
-                if candle.tframe < interval {
-                    None
-                } else {
-                    Some(candle)
-                }
-            })
-            .collect()
+        let limit = limit.unwrap_or(1000);
+        match to_interval(&interval) {
+            Some(native) => self.get_klines(sym, native, start, limit).await,
+            None => self.get_aggregated_klines(sym, &interval, start, limit).await,
+        }
     }
 
     async fn get_wallet(&self) -> Result<SpotWallet, Error> {
-        let url = self.url.clone() + "/sapi/v1/accountSnapshot";
-        let tstamp_str = format!("{}", Utc::now().timestamp_millis());
-        let mut queries: Vec<(&str, &str)> = vec![("type", "SPOT"), ("timestamp", &tstamp_str)];
-        let mut request = self.client.get(url).query(&queries).expect("in adding queries");
-        let query_str = request.get_uri().query().expect("no query?");
-        let signature = Signer::new(MessageDigest::sha256(), &self.secret)
-            .expect("in creating the signer")
-            .sign_oneshot_to_vec(query_str.as_bytes())
-            .expect("in digesting body")
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join("");
-        queries.push(("signature", &signature));
-        request = request.query(&queries).expect("in setting queries with signature");
-        let mut wl = request
-            .send()
-            .await
-            .expect("in send binance account snapshot request")
-            .json::<AccountStatus>()
-            .limit(128_000_000)
-            .await
-            .expect("in json::<AccountStatus>")
-            .snapshot;
+        let queries: Vec<(String, String)> = vec![(String::from("type"), String::from("SPOT"))];
+        // sapi endpoints are limited apart, nothing to book on the api weight
+        let mut wl = self.execute_signed::<AccountStatus>(Method::GET, "/sapi/v1/accountSnapshot", queries, 0, 0).await?.snapshot;
         wl.sort_by_key(|shot| shot.tstamp);
-        Ok(wl.remove(wl.len() - 1).data.balances.into())
-    }
-
-    async fn send_order(&self, order: orders::Order) -> orders::OrderStatus {
-        let url = self.url.clone() + "/api/v3/order";
-        let mut queries = order_to_query(&order);
-        let mut request = self.client.post(url).query(&queries).expect("in adding queries");
-        let query_str = request.get_uri().query().expect("no query?");
-        let signature = Signer::new(MessageDigest::sha256(), &self.secret)
-            .expect("in creating the signer")
-            .sign_oneshot_to_vec(query_str.as_bytes())
-            .expect("in digesting body")
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join("");
-        queries.push((String::from("signature"), signature));
-        request = request.query(&queries).expect("in setting queries with signature");
-        let mut response = request.send().await.expect("in receiving server response");
-        let resp_code = response.status();
-        if resp_code.is_success() {
-            orders::OrderStatus::Accepted
-        } else {
-            let bd = response
-                .body()
-                .await
-                .map_or_else(|err| format!("Error {:?}", err), |body| format!("body {:?}", body));
-            orders::OrderStatus::Rejected(bd)
-        }
+        let shot = wl.pop().ok_or_else(|| Error::ErrNotFound(String::from("no account snapshot")))?;
+        SpotWallet::try_from(shot.data.balances)
     }
 
-    async fn get_outstanding_orders(&self, symbol: &str) -> Vec<orders::Order> {
-        let url = self.url.clone() + "/api/v3/openOrders";
-        let tstamp_str = format!("{}", Utc::now().timestamp_millis());
-        let mut queries: Vec<(&str, &str)> = vec![("symbol", symbol), ("timestamp", &tstamp_str)];
-        let mut request = self.client.get(url).query(&queries).expect("in adding queries");
-        let query_str = request.get_uri().query().expect("no query?");
-        let signature = Signer::new(MessageDigest::sha256(), &self.secret)
-            .expect("in creating the signer")
-            .sign_oneshot_to_vec(query_str.as_bytes())
-            .expect("in digesting body")
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join("");
-        queries.push(("signature", &signature));
-        request = request.query(&queries).expect("in setting queries with signature");
-        let mut response = request.send().await.expect("in send binance outstanding orders request");
-        let lives: Vec<orders::Order> = response
-            .json::<Vec<LiveOrderUpdate>>()
-            .await
-            .expect("in receiving outstanding orders")
+    async fn send_order(&self, order: orders::Order) -> Result<orders::OrderStatus, Error> {
+        let queries = order_to_query(&order);
+        self.execute_signed::<serde_json::Value>(Method::POST, "/api/v3/order", queries, 1, 1).await?;
+        Ok(orders::OrderStatus::Accepted)
+    }
+
+    async fn send_oco_order(
+        &self,
+        take_profit: orders::Order,
+        stop_loss: orders::Order,
+    ) -> Result<orders::OrderStatus, Error> {
+        let queries = oco_to_query(&take_profit, &stop_loss);
+        self.execute_signed::<serde_json::Value>(Method::POST, "/api/v3/order/oco", queries, 1, 2).await?;
+        Ok(orders::OrderStatus::Accepted)
+    }
+
+    async fn get_outstanding_orders(&self, symbol: &str) -> Result<Vec<orders::Order>, Error> {
+        let queries: Vec<(String, String)> = vec![(String::from("symbol"), String::from(symbol))];
+        self.execute_signed::<Vec<LiveOrderUpdate>>(Method::GET, "/api/v3/openOrders", queries, 6, 0)
+            .await?
             .into_iter()
-            .filter_map(|live_update| live_update.try_into().ok())
-            .collect();
-        lives
-    }
-
-    async fn cancel_order(&self, symbol: String, order_id: u32) -> orders::OrderStatus {
-        let url = self.url.clone() + "/api/v3/order";
-        let mut queries = cancel_query(symbol, order_id);
-        let mut request = self.client.delete(url).query(&queries).expect("in adding queries");
-        let query_str = request.get_uri().query().expect("no query?");
-        let signature = Signer::new(MessageDigest::sha256(), &self.secret)
-            .expect("in creating the signer")
-            .sign_oneshot_to_vec(query_str.as_bytes())
-            .expect("in digesting body")
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join("");
-        queries.push((String::from("signature"), signature));
-        request = request.query(&queries).expect("in setting queries with signature");
-        let resp_code = request.send().await.expect("in receiving server response").status();
-        if resp_code.is_success() {
-            orders::OrderStatus::Canceled
-        } else {
-            orders::OrderStatus::Rejected(String::new())
+            .filter(|live_update| live_update.is_open() && live_update.is_supported())
+            .map(orders::Order::try_from)
+            .collect()
+    }
+
+    async fn cancel_order(&self, order: orders::Order) -> Result<orders::OrderStatus, Error> {
+        let queries = cancel_query(&order);
+        self.execute_signed::<serde_json::Value>(Method::DELETE, "/api/v3/order", queries, 1, 0).await?;
+        Ok(orders::OrderStatus::Canceled)
+    }
+
+    async fn order_exists(&self, order: orders::Order) -> Result<bool, Error> {
+        let queries = cancel_query(&order);
+        match self.execute_signed::<serde_json::Value>(Method::GET, "/api/v3/order", queries, 4, 0).await {
+            Ok(_) => Ok(true),
+            Err(Error::Exchange(NO_SUCH_ORDER, _)) => Ok(false),
+            Err(err) => Err(err),
         }
     }
 }
@@ -245,10 +356,16 @@ pub struct Live {
     ticks: Vec<Tick>,
     token: String,
     url: String,
-    ws_conn: WsConnection,
+    // none until a connection attempt succeeds
+    ws_conn: Option<WsConnection>,
+    // connection attempts failed in a row
+    failures: u32,
     heartbeat: chrono::NaiveDateTime,
     refresh: chrono::NaiveDateTime,
     reconnect: chrono::NaiveDateTime,
+    // candles being built from 1m klines, one per tick
+    partials: Vec<Option<candles::Candle>>,
+    candles: VecDeque<LiveEvent>,
 }
 
 impl Live {
@@ -256,23 +373,59 @@ impl Live {
         let base_url = String::from("wss://stream.binance.com:9443/stream?streams=");
         let stream_list = build_stream_list(ticks.as_slice(), &listen_key);
         let url = base_url.clone() + &stream_list;
-        let (resp, conn) = Client::builder()
-            .max_http_version(awc::http::Version::HTTP_11)
-            .finish()
-            .ws(url)
-            .connect()
+        let ws_conn = ws_connect(&url)
             .await
-            .expect("on ws connecting to binance");
-        debug!("new response {:?}", resp);
+            .map_err(|err| error!("binance - {:?}, reconnecting", err))
+            .ok();
         let now = Utc::now().naive_utc();
         Self {
+            partials: vec![None; ticks.len()],
             ticks,
             token: listen_key,
             url: base_url,
-            ws_conn: conn,
+            failures: if ws_conn.is_some() { 0 } else { 1 },
+            ws_conn,
             heartbeat: now,
             refresh: now,
             reconnect: now,
+            candles: VecDeque::new(),
+        }
+    }
+
+    // closed klines go to the ticks streaming them, either as they are or merged into their time frame
+    fn on_kline(&mut self, msg: LiveCandle) {
+        if !msg.is_closed() {
+            return;
+        }
+        let sym = msg.name();
+        let interval = msg.interval().to_string();
+        let candle = match candles::Candle::try_from(msg) {
+            Ok(candle) => candle,
+            Err(err) => {
+                self.candles.push_back(LiveEvent::Generic(format!("skipping kline of {}: {:?}", sym, err)));
+                return;
+            }
+        };
+        for (tick, partial) in self.ticks.iter().zip(self.partials.iter_mut()) {
+            if tick.sym != sym || stream_interval(&tick.interval) != interval {
+                continue;
+            }
+            if to_interval(&tick.interval).is_some() {
+                self.candles.push_back(LiveEvent::Candle(sym.clone(), tick.interval, candle));
+                continue;
+            }
+            // the first candle is only complete when the feed starts with it
+            if partial.is_none() && candle.tstamp != candles::bucket_start(&candle.tstamp, &tick.interval) {
+                debug!("{} - skipping minute {} of an incomplete candle", sym, candle.tstamp);
+                continue;
+            }
+            candles::merge(partial, &candle, &tick.interval);
+            let end = candle.tstamp + candle.tframe;
+            if candles::bucket_start(&end, &tick.interval) == end {
+                if let Some(cnd) = partial.take() {
+                    self.candles.push_back(LiveEvent::Candle(sym.clone(), tick.interval, cnd));
+                }
+            }
         }
     }
 }
@@ -286,15 +439,17 @@ impl LiveFeed for Live {
     async fn reconnect(&mut self, new_key: String) {
         let stream_list = build_stream_list(self.ticks.as_slice(), &new_key);
         let url = self.url.clone() + &stream_list;
-        let (resp, conn) = Client::builder()
-            .max_http_version(awc::http::Version::HTTP_11)
-            .finish()
-            .ws(url)
-            .connect()
-            .await
-            .expect("on ws connecting to binance");
-        self.ws_conn = conn;
-        debug!("new response {:?}", resp);
+        match ws_connect(&url).await {
+            Ok(conn) => {
+                self.ws_conn = Some(conn);
+                self.failures = 0;
+            }
+            Err(err) => {
+                self.ws_conn = None;
+                self.failures += 1;
+                error!("binance - {:?}, attempt {}", err, self.failures);
+            }
+        }
         self.token = new_key;
         let now = Utc::now().naive_utc();
         self.heartbeat = now;
@@ -307,7 +462,19 @@ impl LiveFeed for Live {
         let refr_interval = Duration::minutes(60);
         let refr_interval_grace = Duration::minutes(45);
         let recon_interval = Duration::hours(24);
+        if let Some(event) = self.candles.pop_front() {
+            return event;
+        }
         loop {
+            let conn = match self.ws_conn.as_mut() {
+                Some(conn) => conn,
+                None => {
+                    // the connection failed, waiting longer after each attempt
+                    let wait = 2u64.pow(self.failures.min(6)).min(60);
+                    actix_rt::time::delay_for(std::time::Duration::from_secs(wait)).await;
+                    return LiveEvent::ReconnectionRequired;
+                }
+            };
             let now = Utc::now().naive_utc();
             // reconnection required
             if now - self.reconnect > recon_interval {
@@ -323,31 +490,47 @@ impl LiveFeed for Live {
             }
             // ping required
             if now - self.heartbeat > hb_interval {
-                self.ws_conn
-                    .send(Message::Ping(Bytes::from("hello")))
-                    .await
-                    .expect("in sending ping");
+                if let Err(err) = conn.send(Message::Ping(Bytes::from("hello"))).await {
+                    warn!("binance - ping failed {:?}", err);
+                    return LiveEvent::ReconnectionRequired;
+                }
                 self.heartbeat = now;
             }
 
-            let nnext = self.ws_conn.next().await;
+            let nnext = conn.next().await;
             debug!("[{}] received a next {:?}", Utc::now(), nnext);
-            if nnext.is_none() {
-                actix_rt::time::delay_for(std::time::Duration::from_secs(5)).await;
-                continue;
-            }
-            let msg = nnext.unwrap();
+            let msg = match nnext {
+                Some(msg) => msg,
+                None => {
+                    warn!("binance - connection dropped");
+                    return LiveEvent::ReconnectionRequired;
+                }
+            };
             match msg {
                 Ok(Frame::Text(text)) => {
-                    let panic_msg = format!("original text {:?}", text);
-                    let mesg = serde_json::from_slice::<LiveMessage>(&text).expect(&panic_msg);
-                    let m_event = interpret_message(mesg);
-                    if let Some(event) = m_event {
-                        return event;
+                    let mesg = match serde_json::from_slice::<LiveMessage>(&text) {
+                        Ok(mesg) => mesg,
+                        Err(err) => return LiveEvent::Generic(format!("{} in {:?}", err, text)),
+                    };
+                    match mesg.data {
+                        LiveMessageType::LiveCandle(candle_msg) => {
+                            self.on_kline(candle_msg);
+                            if let Some(event) = self.candles.pop_front() {
+                                return event;
+                            }
+                        }
+                        data => {
+                            if let Some(event) = interpret_message(data) {
+                                return event;
+                            }
+                        }
                     }
                 }
                 Ok(Frame::Ping(bytes)) => {
-                    self.ws_conn.send(awc::ws::Message::Pong(bytes)).await.expect("when ponging");
+                    if let Err(err) = conn.send(awc::ws::Message::Pong(bytes)).await {
+                        warn!("binance - pong failed {:?}", err);
+                        return LiveEvent::ReconnectionRequired;
+                    }
                 }
                 Ok(Frame::Close(reasons)) => {
                     warn!("connection closed {:?}", reasons);
@@ -364,65 +547,139 @@ impl LiveFeed for Live {
 
 // --------------------------------
 // helper functions
-fn to_interval(interval: &Duration) -> String {
-    if *interval == Duration::minutes(1) {
-        String::from("1m")
-    } else if *interval == Duration::minutes(15) {
-        String::from("15m")
-    } else if *interval == Duration::hours(1) {
-        String::from("1h")
-    } else if *interval == Duration::days(1) {
-        String::from("1d")
-    } else {
-        panic!("duration unknown")
+// native kline intervals, 1M is a humantime month, cut on calendar months like the backtest buckets
+const INTERVALS: [(&str, i64); 15] = [
+    ("1m", 60),
+    ("3m", 180),
+    ("5m", 300),
+    ("15m", 900),
+    ("30m", 1800),
+    ("1h", 3600),
+    ("2h", 7200),
+    ("4h", 14400),
+    ("6h", 21600),
+    ("8h", 28800),
+    ("12h", 43200),
+    ("1d", 86400),
+    ("3d", 259_200),
+    ("1w", 604_800),
+    ("1M", 2_630_016),
+];
+
+fn to_interval(interval: &Duration) -> Option<&'static str> {
+    INTERVALS
+        .iter()
+        .find(|(_, secs)| Duration::seconds(*secs) == *interval)
+        .map(|(name, _)| *name)
+}
+
+// largest native interval (months apart) the time frame is made of
+fn base_interval(interval: &Duration) -> Option<(&'static str, Duration)> {
+    if interval.num_seconds() <= 0 || *interval != Duration::seconds(interval.num_seconds()) {
+        return None;
     }
+    INTERVALS
+        .iter()
+        .rev()
+        .filter(|(name, _)| *name != "1M")
+        .find(|(_, secs)| interval.num_seconds() % secs == 0)
+        .map(|(name, secs)| (*name, Duration::seconds(*secs)))
+}
+
+// the stream a tick is fed from, 1m klines when the time frame is not native
+fn stream_interval(interval: &Duration) -> &'static str {
+    to_interval(interval).unwrap_or("1m")
+}
+
+// timestamp and recvWindow of a signed request sent at now, on the server clock
+fn timing(now: DateTime<Utc>, offset: Duration, recv_window: Option<u64>) -> Vec<(String, String)> {
+    let mut queries = vec![(String::from("timestamp"), (now + offset).timestamp_millis().to_string())];
+    if let Some(recv_window) = recv_window {
+        queries.push((String::from("recvWindow"), recv_window.to_string()));
+    }
+    queries
+}
+
+// klines weight grows with the number of candles asked
+fn klines_weight(limit: usize) -> u32 {
+    match limit {
+        0..=99 => 1,
+        100..=499 => 2,
+        500..=1000 => 5,
+        _ => 10,
+    }
+}
+
+async fn ws_connect(url: &str) -> Result<WsConnection, Error> {
+    let (resp, conn) = Client::builder()
+        .max_http_version(awc::http::Version::HTTP_11)
+        .finish()
+        .ws(url)
+        .connect()
+        .await
+        .map_err(|e| Error::Network(format!("on ws connecting to binance: {:?}", e)))?;
+    debug!("new response {:?}", resp);
+    Ok(conn)
 }
 
 fn build_stream_list(ticks: &[Tick], listen_key: &str) -> String {
     let mut streams: Vec<_> = ticks
         .iter()
-        .map(|tick| format!("{}@kline_{}", tick.sym.to_ascii_lowercase(), to_interval(&tick.interval)))
+        .map(|tick| format!("{}@kline_{}", tick.sym.to_ascii_lowercase(), stream_interval(&tick.interval)))
         .collect();
+    streams.sort();
+    streams.dedup();
     streams.push(String::from(listen_key));
     streams.join("/")
 }
 
-fn interpret_message(mesg: LiveMessage) -> Option<LiveEvent> {
-    match mesg.data {
-        LiveMessageType::LiveCandle(candle_msg) => {
-            if candle_msg.is_closed() {
-                let symbol_name = candle_msg.name();
-                return Some(LiveEvent::Candle(symbol_name, candle_msg.into()));
-            }
-        }
+fn interpret_message(data: LiveMessageType) -> Option<LiveEvent> {
+    match data {
+        // the feed builds the time frame candles
+        LiveMessageType::LiveCandle(_) => {}
+        // bad payloads are skipped, the live loop logs them
         LiveMessageType::OrderUpdate(tx_msg) => {
-            if let Ok(tx) = tx_msg.clone().try_into() {
-                return Some(LiveEvent::Transaction(tx));
-            } else if let Ok(order) = tx_msg.try_into() {
-                return Some(LiveEvent::NewOrder(order));
+            if tx_msg.is_filled() {
+                return Some(match orders::Transaction::try_from(tx_msg) {
+                    Ok(tx) => LiveEvent::Transaction(tx),
+                    Err(err) => LiveEvent::Generic(format!("skipping fill: {:?}", err)),
+                });
+            } else if let Some((symbol, id)) = tx_msg.canceled_order() {
+                return Some(LiveEvent::OrderCanceled(symbol, id));
+            } else if tx_msg.is_new() && tx_msg.is_supported() {
+                return Some(match orders::Order::try_from(tx_msg) {
+                    Ok(order) => LiveEvent::NewOrder(order),
+                    Err(err) => LiveEvent::Generic(format!("skipping new order: {:?}", err)),
+                });
             } else {
                 return None;
             }
         }
         LiveMessageType::AccountUpdate(account_msg) => {
-            return Some(LiveEvent::BalanceUpdate(account_msg.into()));
+            return Some(match SpotWallet::try_from(account_msg) {
+                Ok(wallet) => LiveEvent::BalanceUpdate(wallet),
+                Err(err) => LiveEvent::Generic(format!("skipping account update: {:?}", err)),
+            });
         }
         LiveMessageType::BalanceUpdate(balance_update) => {
-            let delta = balance_update.delta.parse::<f64>().expect("not a delta");
-            return Some(LiveEvent::AssetUpdate {
-                asset: balance_update.asset,
-                delta,
-            });
+            return match balance_update.delta.parse::<f64>() {
+                Ok(delta) => Some(LiveEvent::AssetUpdate {
+                    asset: balance_update.asset,
+                    delta,
+                }),
+                Err(err) => Some(LiveEvent::Generic(format!(
+                    "balance update delta {:?} {}",
+                    balance_update.delta, err
+                ))),
+            };
         }
     }
     None
 }
 
 fn order_to_query(order: &orders::Order) -> Vec<(String, String)> {
-    let tstamp = Utc::now().timestamp_millis() as u64;
     let side: Side = order.side.clone().into();
     let qty = normalize_it(order.volume, order.symbol.min_volume, order.symbol.volume_step);
-    let order_id = format!("{}_{}", order.id.to_string(), order.tx_ref);
     let mut queries: Vec<(String, String)> = vec![
         (String::from("symbol"), order.symbol.symbol.clone()),
         (String::from("side"), side.to_string()),
@@ -430,9 +687,8 @@ fn order_to_query(order: &orders::Order) -> Vec<(String, String)> {
             String::from("quantity"),
             format!("{:.prec$}", qty, prec = order.symbol.base_decimals),
         ),
-        (String::from("newClientOrderId"), order_id),
+        (String::from("newClientOrderId"), orders::client_order_id(order)),
         (String::from("newOrderRespType"), String::from("ACK")),
-        (String::from("timestamp"), tstamp.to_string()),
     ];
     match order.o_type {
         orders::Type::Market => {
@@ -460,12 +716,37 @@ fn order_to_query(order: &orders::Order) -> Vec<(String, String)> {
     queries
 }
 
-fn cancel_query(symbol: String, id: u32) -> Vec<(String, String)> {
-    let tstamp = Utc::now().timestamp_millis() as u64;
+// the stop leg is sent as a plain STOP_LOSS, the take profit one as LIMIT_MAKER
+fn oco_to_query(take_profit: &orders::Order, stop_loss: &orders::Order) -> Vec<(String, String)> {
+    let side: Side = take_profit.side.clone().into();
+    let sym = &take_profit.symbol;
+    let qty = normalize_it(take_profit.volume, sym.min_volume, sym.volume_step);
+    let price = match take_profit.o_type {
+        orders::Type::Limit(price) => price,
+        _ => panic!("take profit leg must be a limit order"),
+    };
+    let stop = match stop_loss.o_type {
+        orders::Type::StopLoss(stop) => stop,
+        _ => panic!("stop loss leg must be a stop loss order"),
+    };
+    let norm_pr = normalize_it(price, sym.min_price, sym.price_tick);
+    let norm_stop = normalize_it(stop, sym.min_price, sym.price_tick);
     vec![
-        (String::from("symbol"), symbol),
-        (String::from("origClientOrderId"), id.to_string()),
-        (String::from("timestamp"), tstamp.to_string()),
+        (String::from("symbol"), sym.symbol.clone()),
+        (String::from("side"), side.to_string()),
+        (String::from("quantity"), format!("{:.prec$}", qty, prec = sym.base_decimals)),
+        (String::from("limitClientOrderId"), orders::client_order_id(take_profit)),
+        (String::from("price"), format!("{:.prec$}", norm_pr, prec = sym.base_decimals)),
+        (String::from("stopClientOrderId"), orders::client_order_id(stop_loss)),
+        (String::from("stopPrice"), format!("{:.prec$}", norm_stop, prec = sym.base_decimals)),
+        (String::from("newOrderRespType"), String::from("ACK")),
+    ]
+}
+
+fn cancel_query(order: &orders::Order) -> Vec<(String, String)> {
+    vec![
+        (String::from("symbol"), order.symbol.symbol.clone()),
+        (String::from("origClientOrderId"), orders::client_order_id(order)),
     ]
 }
 
@@ -477,3 +758,46 @@ fn normalize_it(value: f64, min: f64, tick: f64) -> f64 {
     }
     norm
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    #[test]
+    fn signs_like_the_api_docs() {
+        // example from the binance api documentation
+        let query = "symbol=LTCBTC&side=BUY&type=LIMIT&timeInForce=GTC&quantity=1&price=0.1&recvWindow=5000&timestamp=1499827319559";
+        let signature = actix_rt::System::new("binance-sign").block_on(async move {
+            let rest = Rest::new("", "NhqPtmdSJYdKjVHjA7PZj4Mge3R5YNiP1e3UZjInClVN65XAbvqqM6A7H5fATj0j", None);
+            rest.sign(query)
+        });
+        assert_eq!(signature.unwrap(), "c8db56825ae71d6d79447849e617115f4a920fa2acdcab2b053c4b2838bd6b71");
+    }
+
+    #[test]
+    fn signed_requests_are_stamped_on_the_server_clock() {
+        let now = utils::from_timestamp(1_499_827_319, 559_000_000).unwrap().and_utc();
+        let queries = timing(now, Duration::milliseconds(-1500), Some(5000));
+        assert_eq!(
+            queries,
+            vec![
+                (String::from("timestamp"), String::from("1499827318059")),
+                (String::from("recvWindow"), String::from("5000"))
+            ]
+        );
+        assert_eq!(timing(now, Duration::zero(), None).len(), 1);
+    }
+
+    #[test]
+    fn bad_payloads_are_skipped() {
+        let json = r#"{"e":"executionReport","E":1499405658658,"s":"ETHBTC","c":"mUvoqJxFIILMdfAW5iGSOW","S":"BUY",
+            "o":"LIMIT","q":"1.0x","p":"0.10264410","P":"0.00000000","X":"FILLED","z":"1.00000000","n":"0","Z":"0.10264410"}"#;
+        let event = interpret_message(serde_json::from_str::<LiveMessageType>(json).unwrap());
+        assert!(matches!(event, Some(LiveEvent::Generic(_))));
+        let json = r#"{"e":"outboundAccountPosition","E":1564034571105,"u":1564034571073,
+            "B":[{"a":"ETH","f":"10000.000000","l":"0.000000"},{"a":"BTC","f":"","l":"0.000000"}]}"#;
+        let event = interpret_message(serde_json::from_str::<LiveMessageType>(json).unwrap());
+        assert!(matches!(event, Some(LiveEvent::Generic(_))));
+    }
+}
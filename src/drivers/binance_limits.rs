@@ -0,0 +1,220 @@
+use crate::candles::bucket_start;
+use crate::utils;
+use chrono::{Duration, NaiveDateTime};
+use log::{debug, info};
+
+use super::binance_types::{RateLimit, RateLimitType};
+
+// headroom left to other processes trading from the same IP
+const WEIGHT_USAGE: f64 = 0.9;
+
+// a binance limit over a fixed window, i.e. 6000 weight every minute
+// binance reports the usage of the whole IP / account in the response headers
+struct Counter {
+    limit_type: RateLimitType,
+    window: Duration,
+    limit: u32,
+    header: String,
+    used: u32,
+    start: NaiveDateTime,
+}
+
+impl Counter {
+    fn new(limit_type: RateLimitType, window: Duration, limit: u32) -> Self {
+        let prefix = match limit_type {
+            RateLimitType::Orders => "x-mbx-order-count-",
+            _ => "x-mbx-used-weight-",
+        };
+        Self {
+            header: prefix.to_string() + &header_suffix(&window),
+            limit_type,
+            window,
+            limit,
+            used: 0,
+            start: utils::from_timestamp(0, 0).expect("epoch"),
+        }
+    }
+
+    fn capacity(&self) -> u32 {
+        match self.limit_type {
+            RateLimitType::RequestWeight => (self.limit as f64 * WEIGHT_USAGE) as u32,
+            _ => self.limit,
+        }
+    }
+
+    fn roll(&mut self, now: &NaiveDateTime) {
+        let start = bucket_start(now, &self.window);
+        if start != self.start {
+            self.start = start;
+            self.used = 0;
+        }
+    }
+}
+
+// 1M, 10S, 1D as in the header names
+fn header_suffix(window: &Duration) -> String {
+    let secs = window.num_seconds();
+    if secs % 86400 == 0 {
+        format!("{}d", secs / 86400)
+    } else if secs % 3600 == 0 {
+        format!("{}h", secs / 3600)
+    } else if secs % 60 == 0 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+pub(super) struct RateLimiter {
+    counters: Vec<Counter>,
+    banned_until: Option<NaiveDateTime>,
+    loaded: bool,
+}
+
+impl RateLimiter {
+    // conservative limits until exchangeInfo tells the real ones
+    pub(super) fn new() -> Self {
+        Self {
+            counters: vec![
+                Counter::new(RateLimitType::RequestWeight, Duration::minutes(1), 1200),
+                Counter::new(RateLimitType::Orders, Duration::seconds(10), 50),
+                Counter::new(RateLimitType::Orders, Duration::days(1), 160_000),
+            ],
+            banned_until: None,
+            loaded: false,
+        }
+    }
+
+    pub(super) fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    pub(super) fn set_limits(&mut self, limits: &[RateLimit]) {
+        self.counters = limits
+            .iter()
+            .filter(|limit| limit.limit_type != RateLimitType::Other)
+            .filter_map(|limit| {
+                limit
+                    .window()
+                    .map(|window| Counter::new(limit.limit_type.clone(), window, limit.limit))
+            })
+            .collect();
+        self.loaded = true;
+        info!("binance - rate limits {:?}", limits);
+    }
+
+    // how long to wait before spending the given weight and orders
+    pub(super) fn delay(&mut self, now: &NaiveDateTime, weight: u32, orders: u32) -> Option<Duration> {
+        let mut wait = self.banned_until.map(|until| until - *now).filter(|wait| *wait > Duration::zero());
+        for counter in self.counters.iter_mut() {
+            let amount = match counter.limit_type {
+                RateLimitType::Orders => orders,
+                _ => weight,
+            };
+            counter.roll(now);
+            if amount > 0 && counter.used + amount > counter.capacity() {
+                let until_reset = counter.start + counter.window - *now;
+                wait = Some(wait.map_or(until_reset, |wait| wait.max(until_reset)));
+            }
+        }
+        wait
+    }
+
+    // the request is booked before it's sent, the response headers correct the count
+    pub(super) fn spend(&mut self, now: &NaiveDateTime, weight: u32, orders: u32) {
+        for counter in self.counters.iter_mut() {
+            counter.roll(now);
+            counter.used += match counter.limit_type {
+                RateLimitType::Orders => orders,
+                _ => weight,
+            };
+        }
+    }
+
+    pub(super) fn update(&mut self, now: &NaiveDateTime, headers: &awc::http::HeaderMap) {
+        for counter in self.counters.iter_mut() {
+            let used = headers
+                .get(counter.header.as_str())
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u32>().ok());
+            if let Some(used) = used {
+                counter.roll(now);
+                counter.used = used;
+                debug!("binance - {} {}/{}", counter.header, used, counter.limit);
+            }
+        }
+    }
+
+    // after a 429 / 418 nothing is sent until the ban is over
+    pub(super) fn ban(&mut self, now: &NaiveDateTime, secs: u64) {
+        self.banned_until = Some(*now + Duration::seconds(secs as i64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use awc::http::{HeaderMap, HeaderName, HeaderValue};
+    use chrono::NaiveDate;
+
+    // as in the exchangeInfo response
+    const LIMITS: &str = r#"[
+        {"rateLimitType":"REQUEST_WEIGHT","interval":"MINUTE","intervalNum":1,"limit":6000},
+        {"rateLimitType":"ORDERS","interval":"SECOND","intervalNum":10,"limit":100},
+        {"rateLimitType":"ORDERS","interval":"DAY","intervalNum":1,"limit":200000},
+        {"rateLimitType":"RAW_REQUESTS","interval":"MINUTE","intervalNum":5,"limit":61000}
+    ]"#;
+
+    fn limiter() -> RateLimiter {
+        let mut limiter = RateLimiter::new();
+        limiter.set_limits(&serde_json::from_str::<Vec<RateLimit>>(LIMITS).unwrap());
+        limiter
+    }
+
+    fn at(min: u32, sec: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(0, min, sec).unwrap()
+    }
+
+    #[test]
+    fn limits_come_from_exchange_info() {
+        let limiter = limiter();
+        assert!(limiter.is_loaded());
+        let headers: Vec<&str> = limiter.counters.iter().map(|counter| counter.header.as_str()).collect();
+        assert_eq!(headers, vec!["x-mbx-used-weight-1m", "x-mbx-order-count-10s", "x-mbx-order-count-1d"]);
+    }
+
+    #[test]
+    fn waits_for_the_window_to_roll() {
+        let mut limiter = limiter();
+        // 90% of the weight is ours to use
+        limiter.spend(&at(0, 10), 5000, 0);
+        assert_eq!(limiter.delay(&at(0, 20), 400, 0), None);
+        assert_eq!(limiter.delay(&at(0, 20), 401, 0), Some(Duration::seconds(40)));
+        assert_eq!(limiter.delay(&at(1, 0), 401, 0), None);
+        // orders count on their own windows
+        limiter.spend(&at(1, 0), 1, 100);
+        assert_eq!(limiter.delay(&at(1, 4), 1, 0), None);
+        assert_eq!(limiter.delay(&at(1, 4), 1, 1), Some(Duration::seconds(6)));
+    }
+
+    #[test]
+    fn headers_correct_the_count() {
+        let mut limiter = limiter();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-mbx-used-weight-1m"),
+            HeaderValue::from_static("5390"),
+        );
+        limiter.update(&at(0, 30), &headers);
+        assert_eq!(limiter.delay(&at(0, 31), 10, 0), None);
+        assert_eq!(limiter.delay(&at(0, 31), 11, 0), Some(Duration::seconds(29)));
+    }
+
+    #[test]
+    fn nothing_goes_out_while_banned() {
+        let mut limiter = limiter();
+        limiter.ban(&at(0, 0), 120);
+        assert_eq!(limiter.delay(&at(1, 0), 1, 0), Some(Duration::seconds(60)));
+        assert_eq!(limiter.delay(&at(2, 0), 1, 0), None);
+    }
+}
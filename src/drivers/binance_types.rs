@@ -1,11 +1,14 @@
 use crate::candles;
+use crate::error::Error;
 use crate::orders;
 use crate::symbol::Symbol;
+use crate::utils;
 use crate::wallets::SpotWallet;
 use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use scan_fmt::scan_fmt;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
 
 #[derive(Debug, serde::Deserialize, Clone)]
 #[serde(tag = "filterType")]
@@ -54,8 +57,9 @@ pub(super) struct SymbolInfo {
     quote_precision: usize,
     filters: Vec<SymbolFilter>,
 }
-impl From<SymbolInfo> for Symbol {
-    fn from(info: SymbolInfo) -> Self {
+impl TryFrom<SymbolInfo> for Symbol {
+    type Error = Error;
+    fn try_from(info: SymbolInfo) -> Result<Self, Self::Error> {
         let mut min_volume: f64 = 0.0;
         let mut price_min: f64 = 0.0;
         let mut volume_step: f64 = 0.0;
@@ -67,21 +71,21 @@ impl From<SymbolInfo> for Symbol {
                     max_qty: _,
                     step_size,
                 } => {
-                    min_volume = min_qty.parse::<f64>().expect("min_qty not an f64");
-                    volume_step = step_size.parse::<f64>().expect("step size not an f64");
+                    min_volume = parse_number("minQty", &min_qty)?;
+                    volume_step = parse_number("stepSize", &step_size)?;
                 }
                 SymbolFilter::PriceFilter {
                     min_price,
                     max_price: _,
                     tick_price,
                 } => {
-                    price_min = min_price.parse::<f64>().expect("min_price not an f64");
-                    price_tick = tick_price.parse::<f64>().expect("price tick not an f64");
+                    price_min = parse_number("minPrice", &min_price)?;
+                    price_tick = parse_number("tickSize", &tick_price)?;
                 }
                 _ => {}
             }
         }
-        Self {
+        Ok(Self {
             pretty: format!("{}-{}", &info.base, &info.quote),
             symbol: info.symbol,
             base: info.base,
@@ -92,7 +96,7 @@ impl From<SymbolInfo> for Symbol {
             min_price: price_min,
             volume_step,
             price_tick,
-        }
+        })
     }
 }
 
@@ -124,22 +128,25 @@ pub(super) struct Candle {
     ignore3: String,
     #[serde(alias = "x", default)]
     kline_close: bool,
+    #[serde(alias = "i", default)]
+    interval: String,
 }
-impl From<Candle> for candles::Candle {
-    fn from(cnd: Candle) -> Self {
+impl TryFrom<Candle> for candles::Candle {
+    type Error = Error;
+    fn try_from(cnd: Candle) -> Result<Self, Self::Error> {
         if cnd.tstamp_close < cnd.tstamp_open {
-            panic!("close {}, open {}", cnd.tstamp_close, cnd.tstamp_open);
+            return Err(Error::Parse(format!("kline closes at {} before opening at {}", cnd.tstamp_close, cnd.tstamp_open)));
         }
-        Self {
-            open: cnd.open.parse::<f64>().expect("in cnd.open"),
-            low: cnd.low.parse::<f64>().expect("in cnd.low"),
-            high: cnd.high.parse::<f64>().expect("in cnd.high"),
-            close: cnd.close.parse::<f64>().expect("in cnd.close"),
-            volume: cnd.volume.parse::<f64>().expect("in cnd.volume"),
-            tstamp: NaiveDateTime::from_timestamp_opt((cnd.tstamp_open / 1000) as i64, 0)
-                .expect("in From<Candle> for candles::Candle"),
+        // the close time is the last millisecond of the candle
+        Ok(Self {
+            open: parse_number("open", &cnd.open)?,
+            low: parse_number("low", &cnd.low)?,
+            high: parse_number("high", &cnd.high)?,
+            close: parse_number("close", &cnd.close)?,
+            volume: parse_number("volume", &cnd.volume)?,
+            tstamp: parse_millis("open time", cnd.tstamp_open)?,
             tframe: Duration::milliseconds((cnd.tstamp_close - cnd.tstamp_open) as i64 + 1),
-        }
+        })
     }
 }
 
@@ -166,11 +173,11 @@ impl From<orders::Side> for Side {
         }
     }
 }
-impl ToString for Side {
-    fn to_string(&self) -> String {
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Side::Sell => String::from("SELL"),
-            Side::Buy => String::from("BUY"),
+            Side::Sell => write!(f, "SELL"),
+            Side::Buy => write!(f, "BUY"),
         }
     }
 }
@@ -211,23 +218,15 @@ impl LiveCandle {
     pub(super) fn name(&self) -> String {
         self.symbol.clone()
     }
+    pub(super) fn interval(&self) -> &str {
+        &self.candle.interval
+    }
 }
-impl From<LiveCandle> for candles::Candle {
-    fn from(msg: LiveCandle) -> Self {
-        let start = NaiveDateTime::from_timestamp_opt((msg.candle.tstamp_open / 1000) as i64, 0)
-            .expect("From<LiveCandle> for candles::Candle, start");
-        let stop = NaiveDateTime::from_timestamp_opt((msg.candle.tstamp_close / 1000) as i64, 0)
-            .expect("From<LiveCandle> for candles::Candle, stop");
-        let dur = stop - start + Duration::milliseconds(1);
-        Self {
-            open: msg.candle.open.parse::<f64>().expect("in cnd.open"),
-            low: msg.candle.low.parse::<f64>().expect("in cnd.low"),
-            high: msg.candle.high.parse::<f64>().expect("in cnd.high"),
-            close: msg.candle.close.parse::<f64>().expect("in cnd.close"),
-            volume: msg.candle.volume.parse::<f64>().expect("in cnd.volume"),
-            tstamp: start,
-            tframe: dur,
-        }
+// same as the rest klines
+impl TryFrom<LiveCandle> for candles::Candle {
+    type Error = Error;
+    fn try_from(msg: LiveCandle) -> Result<Self, Self::Error> {
+        candles::Candle::try_from(msg.candle)
     }
 }
 
@@ -239,6 +238,8 @@ pub(super) struct LiveOrderUpdate {
     symbol: String,
     #[serde(alias = "c", alias = "clientOrderId")]
     order_id: String,
+    #[serde(alias = "C", default)]
+    orig_order_id: String,
     #[serde(alias = "X", alias = "status")]
     order_status: OrderStatus,
     #[serde(alias = "S")]
@@ -251,6 +252,8 @@ pub(super) struct LiveOrderUpdate {
     order_quantity: String,
     #[serde(alias = "p", alias = "price")]
     order_price: String,
+    #[serde(alias = "P", alias = "stopPrice", default)]
+    stop_price: String,
     #[serde(alias = "o", alias = "type")]
     order_type: Type,
     #[serde(alias = "n", default)]
@@ -259,36 +262,31 @@ pub(super) struct LiveOrderUpdate {
     commission_asset: Option<String>,
 }
 impl TryFrom<LiveOrderUpdate> for orders::Transaction {
-    type Error = String;
+    type Error = Error;
     fn try_from(msg: LiveOrderUpdate) -> Result<Self, Self::Error> {
-        if matches!(msg.order_status, OrderStatus::Filled) == false {
-            return Err(String::from("order not filled"));
+        if !msg.is_filled() {
+            return Err(Error::Parse(format!("order {} not filled", msg.order_id)));
         }
-        //let mut id: u32 = 0;
-        //let mut tx_ref: u32 = 0;
-        let (id, tx_ref) = if let Ok((sc_id, sc_tx_ref)) = scan_fmt!(&msg.order_id, "{d}_{d}", u32, u32) {
-            (sc_id, sc_tx_ref)
-        } else if let Ok(sc_id) = msg.order_id.parse::<u32>() {
-            (sc_id, 0)
-        } else {
-            return Err(String::from("no order IDs"));
-        };
+        let (owner, id, tx_ref) = parse_order_id(&msg.order_id);
         let order = orders::Order {
             tstamp: None,
-            volume: msg.order_quantity.parse::<f64>().expect("in msg.order_quantity"),
+            volume: parse_number("order quantity", &msg.order_quantity)?,
             exchange: String::from("binance"),
             expire: None,
             side: msg.side.clone().into(),
             symbol: Symbol::new(msg.symbol.clone()),
             id,
-            o_type: to_type(&msg.order_type, msg.order_price.parse::<f64>().expect("in msg.order_price")),
+            o_type: to_type(&msg)?,
             tx_ref,
+            take_profit: None,
+            stop_loss: None,
+            oco_ref: 0,
+            owner,
         };
-        let tot_quantity = msg.cumulative_quantity.parse::<f64>().expect("in cumulative_quantity");
-        let tot_price = msg.cumulative_price.parse::<f64>().expect("in cumulative_price");
-        let fees = msg.commission_amount.parse::<f64>().expect("in commission_asset");
-        let tstamp = NaiveDateTime::from_timestamp_opt((msg.tstamp / 1000) as i64, 0)
-            .expect("TryFrom<LiveOrderUpdate> for orders::Transaction, tstamp");
+        let tot_quantity = parse_number("cumulative quantity", &msg.cumulative_quantity)?;
+        let tot_price = parse_number("cumulative price", &msg.cumulative_price)?;
+        let fees = parse_number("commission amount", &msg.commission_amount)?;
+        let tstamp = parse_millis("event time", msg.tstamp)?;
         let s = Self {
             tstamp,
             symbol: msg.symbol,
@@ -303,43 +301,94 @@ impl TryFrom<LiveOrderUpdate> for orders::Transaction {
     }
 }
 impl TryFrom<LiveOrderUpdate> for orders::Order {
-    type Error = String;
+    type Error = Error;
     fn try_from(msg: LiveOrderUpdate) -> Result<Self, Self::Error> {
-        if matches!(msg.order_status, OrderStatus::New) == false {
-            return Err(String::from("order not new"));
+        if !msg.is_open() {
+            return Err(Error::Parse(format!("order {} not open", msg.order_id)));
         }
-        let (id, tx_ref) = if let Ok((sc_id, sc_tx_ref)) = scan_fmt!(&msg.order_id, "{d}_{d}", u32, u32) {
-            (sc_id, sc_tx_ref)
-        } else if let Ok(sc_id) = msg.order_id.parse::<u32>() {
-            (sc_id, 0)
-        } else {
-            return Err(String::from("no order IDs"));
-        };
-        let tstamp = NaiveDateTime::from_timestamp_opt((msg.tstamp / 1000) as i64, 0)
-            .expect("TryFrom<LiveOrderUpdate> for orders::Order, tstamp");
+        let (owner, id, tx_ref) = parse_order_id(&msg.order_id);
+        let tstamp = parse_millis("event time", msg.tstamp)?;
         let order = orders::Order {
             tstamp: Some(tstamp),
-            volume: msg.order_quantity.parse::<f64>().expect("in msg.order_quantity"),
+            volume: parse_number("order quantity", &msg.order_quantity)?,
             exchange: String::from("binance"),
             expire: None,
             side: msg.side.clone().into(),
             symbol: Symbol::new(msg.symbol.clone()),
             id,
-            o_type: to_type(&msg.order_type, msg.order_price.parse::<f64>().expect("in msg.order_price")),
+            o_type: to_type(&msg)?,
             tx_ref,
+            take_profit: None,
+            stop_loss: None,
+            oco_ref: 0,
+            owner,
         };
         Ok(order)
     }
 }
 
-fn to_type(msg_o_type: &Type, o_price: f64) -> orders::Type {
-    match msg_o_type {
-        Type::Limit => orders::Type::Limit(o_price),
-        Type::Market => orders::Type::Market,
-        _ => panic!("unknown type"),
+impl LiveOrderUpdate {
+    pub(super) fn is_filled(&self) -> bool {
+        matches!(self.order_status, OrderStatus::Filled)
+    }
+    pub(super) fn is_new(&self) -> bool {
+        matches!(self.order_status, OrderStatus::New)
+    }
+    // still in the book, possibly partially filled
+    pub(super) fn is_open(&self) -> bool {
+        matches!(self.order_status, OrderStatus::New | OrderStatus::PartiallyFilled)
+    }
+    // the orders we can't represent, like the ones placed by hand with other types, are left alone
+    pub(super) fn is_supported(&self) -> bool {
+        !matches!(self.order_type, Type::Other)
+    }
+
+    // symbol and id of an order removed from the book without being filled
+    pub(super) fn canceled_order(&self) -> Option<(String, orders::OrderId)> {
+        if !matches!(self.order_status, OrderStatus::Canceled | OrderStatus::Expired) {
+            return None;
+        }
+        // on a cancel request c refers to the cancel itself, C to the original order
+        let order_id = if self.orig_order_id.is_empty() {
+            &self.order_id
+        } else {
+            &self.orig_order_id
+        };
+        let (_, id, _) = parse_order_id(order_id);
+        Some((self.symbol.clone(), id))
     }
 }
 
+// owner, id and tx_ref of an order, orders from the web ui or other bots have no owner
+fn parse_order_id(order_id: &str) -> (Option<u16>, orders::OrderId, orders::OrderId) {
+    if let Some((id, tx_ref)) = orders::parse_client_order_id(order_id) {
+        (orders::order_instance(id), id, tx_ref)
+    } else if let Ok((sc_id, sc_tx_ref)) = scan_fmt!(order_id, "{d}_{d}", u64, u64) {
+        (None, sc_id, sc_tx_ref)
+    } else {
+        (None, order_id.parse::<u64>().unwrap_or(0), 0)
+    }
+}
+
+fn to_type(msg: &LiveOrderUpdate) -> Result<orders::Type, Error> {
+    match msg.order_type {
+        Type::Limit | Type::LimitMaker => Ok(orders::Type::Limit(parse_number("order price", &msg.order_price)?)),
+        Type::Market => Ok(orders::Type::Market),
+        Type::StopLoss => Ok(orders::Type::StopLoss(parse_number("stop price", &msg.stop_price)?)),
+        Type::Other => Err(Error::Parse(format!("unsupported type of order {}", msg.order_id))),
+    }
+}
+
+fn parse_number(field: &str, value: &str) -> Result<f64, Error> {
+    value
+        .parse::<f64>()
+        .map_err(|e| Error::Parse(format!("{} {:?} not a number: {}", field, value, e)))
+}
+
+fn parse_millis(field: &str, millis: u64) -> Result<NaiveDateTime, Error> {
+    utils::from_timestamp((millis / 1000) as i64, 0).ok_or_else(|| Error::Parse(format!("{} {} out of range", field, millis)))
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub(super) struct LiveAccountUpdate {
     #[serde(alias = "E")]
@@ -347,9 +396,10 @@ pub(super) struct LiveAccountUpdate {
     #[serde(alias = "B")]
     balances: Vec<Balance>,
 }
-impl From<LiveAccountUpdate> for SpotWallet {
-    fn from(msg: LiveAccountUpdate) -> Self {
-        msg.balances.into()
+impl TryFrom<LiveAccountUpdate> for SpotWallet {
+    type Error = Error;
+    fn try_from(msg: LiveAccountUpdate) -> Result<Self, Self::Error> {
+        SpotWallet::try_from(msg.balances)
     }
 }
 
@@ -362,14 +412,15 @@ pub(super) struct Balance {
     #[serde(alias = "l")]
     locked: String,
 }
-impl From<Vec<Balance>> for SpotWallet {
-    fn from(mut msg: Vec<Balance>) -> Self {
-        Self {
+impl TryFrom<Vec<Balance>> for SpotWallet {
+    type Error = Error;
+    fn try_from(mut msg: Vec<Balance>) -> Result<Self, Self::Error> {
+        Ok(Self {
             assets: msg
                 .drain(0..)
-                .map(|balance| (balance.asset, balance.free.parse::<f64>().expect("in balance.free")))
-                .collect::<HashMap<_, _>>(),
-        }
+                .map(|balance| Ok((balance.asset, parse_number("free balance", &balance.free)?)))
+                .collect::<Result<HashMap<_, _>, Error>>()?,
+        })
     }
 }
 
@@ -386,6 +437,51 @@ pub(super) struct BalanceUpdate {
 #[derive(Debug, serde::Deserialize, Clone)]
 pub(super) struct ExchangeInfo {
     pub symbols: Vec<SymbolInfo>,
+    #[serde(alias = "rateLimits", default)]
+    pub rate_limits: Vec<RateLimit>,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+pub(super) struct RateLimit {
+    #[serde(alias = "rateLimitType")]
+    pub limit_type: RateLimitType,
+    pub interval: String,
+    #[serde(alias = "intervalNum")]
+    pub interval_num: i64,
+    pub limit: u32,
+}
+
+#[derive(Debug, serde::Deserialize, Clone, PartialEq)]
+pub(super) enum RateLimitType {
+    #[serde(alias = "REQUEST_WEIGHT")]
+    RequestWeight,
+    #[serde(alias = "ORDERS")]
+    Orders,
+    #[serde(other)]
+    Other,
+}
+impl RateLimit {
+    pub(super) fn window(&self) -> Option<Duration> {
+        match self.interval.as_str() {
+            "SECOND" => Some(Duration::seconds(self.interval_num)),
+            "MINUTE" => Some(Duration::minutes(self.interval_num)),
+            "HOUR" => Some(Duration::hours(self.interval_num)),
+            "DAY" => Some(Duration::days(self.interval_num)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+pub(super) struct ErrorResponse {
+    pub code: i64,
+    pub msg: String,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+pub(super) struct ServerTime {
+    #[serde(alias = "serverTime")]
+    pub server_time: i64,
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
@@ -424,6 +520,10 @@ pub(super) enum Type {
     Limit,
     #[serde(alias = "STOP_LOSS")]
     StopLoss,
+    #[serde(alias = "LIMIT_MAKER")]
+    LimitMaker,
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
@@ -443,3 +543,88 @@ pub(super) enum OrderStatus {
     #[serde(alias = "EXPIRED")]
     Expired,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a filled limit buy, as the user data stream reports it
+    fn execution_report(quantity: &str) -> String {
+        format!(
+            r#"{{"e":"executionReport","E":1499405658658,"s":"ETHBTC","c":"mUvoqJxFIILMdfAW5iGSOW","S":"BUY",
+            "o":"LIMIT","f":"GTC","q":"{}","p":"0.10264410","P":"0.00000000","F":"0.00000000","g":-1,
+            "C":"","x":"TRADE","X":"FILLED","r":"NONE","i":4293153,"l":"1.00000000","z":"1.00000000",
+            "L":"0.10264410","n":"0.00100000","N":"ETH","T":1499405658657,"t":1,"I":8641984,"w":false,
+            "m":false,"M":false,"O":1499405658657,"Z":"0.10264410","Y":"0.10264410","Q":"0.00000000"}}"#,
+            quantity
+        )
+    }
+
+    #[test]
+    fn klines_become_candles() {
+        let json = r#"[1499040000000,"0.01634790","0.80000000","0.01575800","0.01577100","148976.11427815",
+            1499040059999,"2434.19055334",308,"1756.87402397","28.46694368","0"]"#;
+        let cnd = candles::Candle::try_from(serde_json::from_str::<Candle>(json).unwrap()).unwrap();
+        assert_eq!(cnd.tstamp, utils::from_timestamp(1499040000, 0).unwrap());
+        assert_eq!(cnd.tframe, Duration::minutes(1));
+        assert_eq!((cnd.open, cnd.high, cnd.low, cnd.close), (0.0163479, 0.8, 0.015758, 0.015771));
+
+        let bad_price = json.replace("0.01634790", "n/a");
+        let err = candles::Candle::try_from(serde_json::from_str::<Candle>(&bad_price).unwrap()).unwrap_err();
+        assert!(matches!(err, Error::Parse(msg) if msg.starts_with("open ")));
+        let backwards = json.replace("1499040059999", "1499039999999");
+        let err = candles::Candle::try_from(serde_json::from_str::<Candle>(&backwards).unwrap()).unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+    }
+
+    #[test]
+    fn execution_reports_become_transactions() {
+        let update: LiveOrderUpdate = serde_json::from_str(&execution_report("1.00000000")).unwrap();
+        let tx = orders::Transaction::try_from(update.clone()).unwrap();
+        assert_eq!((tx.volume, tx.avg_price, tx.fees), (1.0, 0.1026441, 0.001));
+        assert_eq!(tx.fees_asset, "ETH");
+        assert_eq!(tx.order.o_type, orders::Type::Limit(0.1026441));
+        assert!(orders::Order::try_from(update).is_err());
+
+        let update: LiveOrderUpdate = serde_json::from_str(&execution_report("")).unwrap();
+        assert!(matches!(orders::Transaction::try_from(update), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn legacy_client_ids_keep_their_ids() {
+        assert_eq!(parse_order_id("1234567_89"), (None, 1234567, 89));
+        assert_eq!(parse_order_id("1234567"), (None, 1234567, 0));
+        // placed from the web ui
+        assert_eq!(parse_order_id("web_d8a6b3f2c1"), (None, 0, 0));
+        let update: LiveOrderUpdate = serde_json::from_str(
+            &execution_report("1.00000000").replace("mUvoqJxFIILMdfAW5iGSOW", "1234567_89"),
+        )
+        .unwrap();
+        let tx = orders::Transaction::try_from(update).unwrap();
+        assert_eq!((tx.order.owner, tx.order.id, tx.order.tx_ref), (None, 1234567, 89));
+    }
+
+    #[test]
+    fn malformed_balances_and_filters_are_errors() {
+        let balances: Vec<Balance> =
+            serde_json::from_str(r#"[{"a":"ETH","f":"10000.000000","l":"0.000000"},{"a":"BTC","f":"x","l":"0.000000"}]"#)
+                .unwrap();
+        assert!(matches!(SpotWallet::try_from(balances), Err(Error::Parse(_))));
+        let balances: Vec<Balance> = serde_json::from_str(r#"[{"a":"ETH","f":"10000.000000","l":"0.000000"}]"#).unwrap();
+        assert_eq!(SpotWallet::try_from(balances).unwrap().assets["ETH"], 10000.0);
+
+        let info = |tick: &str| {
+            let json = format!(
+                r#"{{"symbol":"ETHBTC","baseAsset":"ETH","baseAssetPrecision":8,"quoteAsset":"BTC","quoteAssetPrecision":8,
+                "filters":[{{"filterType":"PRICE_FILTER","minPrice":"0.00000100","maxPrice":"100000.00000000","tickSize":"{}"}},
+                {{"filterType":"LOT_SIZE","minQty":"0.00100000","maxQty":"100000.00000000","stepSize":"0.00100000"}},
+                {{"filterType":"MAX_NUM_ORDERS","maxNumOrders":25}}]}}"#,
+                tick
+            );
+            serde_json::from_str::<SymbolInfo>(&json).unwrap()
+        };
+        let sym = Symbol::try_from(info("0.00000100")).unwrap();
+        assert_eq!((sym.price_tick, sym.min_volume, sym.volume_step), (0.000001, 0.001, 0.001));
+        assert!(matches!(Symbol::try_from(info("")), Err(Error::Parse(_))));
+    }
+}
@@ -0,0 +1,586 @@
+use crate::candles;
+use crate::drivers::{LiveEvent, LiveFeed, RestApi, Tick};
+use crate::error::Error;
+use crate::orders;
+use crate::symbol::Symbol;
+use crate::wallets::SpotWallet;
+use async_trait::async_trait;
+use awc::ws::Message;
+use awc::ws::{Codec, Frame};
+use awc::{BoxedSocket, Client};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::future::{select, Either};
+use futures_util::sink::SinkExt;
+use futures_util::stream::StreamExt;
+use log::{debug, error, info, warn};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use std::collections::{HashMap, VecDeque};
+
+use super::kraken_types::*;
+
+const REST_URL: &str = "https://api.kraken.com";
+const PUBLIC_WS_URL: &str = "wss://ws.kraken.com";
+const PRIVATE_WS_URL: &str = "wss://ws-auth.kraken.com";
+
+#[derive(Clone)]
+pub struct Rest {
+    url: String,
+    api_key: String,
+    secret: PKey<Private>,
+    client: Client,
+}
+
+impl Rest {
+    pub fn new(api_key: &str, secret_word: &str) -> Rest {
+        let secret_bytes = openssl::base64::decode_block(secret_word).expect("kraken secret is not base64");
+        let secret = PKey::hmac(&secret_bytes).expect("cannot create private key from secret");
+        let client = Client::builder().header("User-Agent", "trader/0.0.1").finish();
+        Rest {
+            url: String::from(REST_URL),
+            client,
+            api_key: String::from(api_key),
+            secret,
+        }
+    }
+
+    // private endpoints are signed with HMAC-SHA512(path + SHA256(nonce + body)) of the decoded secret
+    async fn private<T: serde::de::DeserializeOwned>(&self, path: &str, mut params: Vec<(String, String)>) -> Result<T, Error> {
+        let nonce = Utc::now().timestamp_micros().to_string();
+        params.insert(0, (String::from("nonce"), nonce.clone()));
+        let body = params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        let mut sha = openssl::sha::Sha256::new();
+        sha.update(nonce.as_bytes());
+        sha.update(body.as_bytes());
+        let mut signer = Signer::new(MessageDigest::sha512(), &self.secret).expect("in creating the signer");
+        signer.update(path.as_bytes()).expect("in digesting path");
+        signer.update(&sha.finish()).expect("in digesting body");
+        let signature = openssl::base64::encode_block(&signer.sign_to_vec().expect("in signing"));
+        self.client
+            .post(self.url.clone() + path)
+            .header("API-Key", self.api_key.as_str())
+            .header("API-Sign", signature)
+            .content_type("application/x-www-form-urlencoded")
+            .send_body(body)
+            .await
+            .map_err(|e| Error::Network(format!("in sending {}: {:?}", path, e)))?
+            .json::<Response<T>>()
+            .limit(128_000_000)
+            .await
+            .map_err(|e| Error::Parse(format!("in parsing {}: {:?}", path, e)))?
+            .into_result()
+    }
+
+    async fn asset_pair(&self, sym: &str) -> Result<AssetPair, Error> {
+        get_asset_pairs(&self.client, &self.url, &[sym])
+            .await?
+            .into_iter()
+            .find(|(name, pair)| name == sym || pair.altname == sym)
+            .map(|(_, pair)| pair)
+            .ok_or_else(|| Error::ErrNotFound(format!("can't find symbol {}", sym)))
+    }
+}
+
+#[async_trait(?Send)]
+impl RestApi for Rest {
+    // the websocket token is only needed to subscribe, open connections never expire
+    async fn refresh_ws_token(&self, old_token: Option<String>) -> Result<String, Error> {
+        if let Some(token) = old_token {
+            return Ok(token);
+        }
+        Ok(self.private::<WsToken>("/0/private/GetWebSocketsToken", Vec::new()).await?.token)
+    }
+
+    async fn get_symbol_info(&self, sym: &str) -> Result<Symbol, Error> {
+        Ok(self.asset_pair(sym).await?.to_symbol(sym))
+    }
+
+    // the last candle kraken returns is still in progress, so it's dropped
+    async fn get_candles(
+        &self,
+        sym: &str,
+        maybe_interval: Option<&Duration>,
+        start: Option<&NaiveDateTime>,
+        limit: Option<usize>,
+    ) -> Result<Vec<candles::Candle>, Error> {
+        let interval = *maybe_interval.unwrap_or(&Duration::minutes(1));
+        let mut queries: Vec<(String, String)> =
+            start.map_or(Vec::new(), |st| vec![(String::from("since"), (st.and_utc().timestamp() - 1).to_string())]);
+        queries.push((String::from("pair"), String::from(sym)));
+        queries.push((String::from("interval"), to_interval(&interval)?.to_string()));
+        let url = self.url.clone() + "/0/public/OHLC";
+        let request = self.client.get(url).query(&queries).map_err(|e| Error::Parse(e.to_string()))?;
+        let mut response = request.send().await.map_err(|e| Error::Network(format!("{:?}", e)))?;
+        let mut cnds: Vec<candles::Candle> = response
+            .json::<Response<HashMap<String, OhlcEntry>>>()
+            .limit(128_000_000)
+            .await
+            .map_err(|e| Error::Parse(format!("{:?}", e)))?
+            .into_result()?
+            .into_values()
+            .filter_map(|entry| match entry {
+                OhlcEntry::Candles(cnds) => Some(cnds),
+                OhlcEntry::Last(_) => None,
+            })
+            .flatten()
+            .map(|ohlc| ohlc.to_candle(&interval))
+            .collect();
+        cnds.pop();
+        if let Some(limit) = limit {
+            if cnds.len() > limit {
+                cnds.drain(0..cnds.len() - limit);
+            }
+        }
+        Ok(cnds)
+    }
+
+    async fn get_wallet(&self) -> Result<SpotWallet, Error> {
+        self.private::<HashMap<String, String>>("/0/private/Balance", Vec::new())
+            .await
+            .map(to_wallet)
+    }
+
+    async fn send_order(&self, order: orders::Order) -> Result<orders::OrderStatus, Error> {
+        let resp = self
+            .private::<serde_json::Value>("/0/private/AddOrder", order_to_query(&order))
+            .await?;
+        debug!("kraken - order added {:?}", resp);
+        Ok(orders::OrderStatus::Accepted)
+    }
+
+    // kraken has no oco orders on spot: the legs are sent as two independent orders,
+    // stop loss first so that the position is protected when only one goes through,
+    // the live loop cancels the other leg once one is filled
+    async fn send_oco_order(
+        &self,
+        take_profit: orders::Order,
+        stop_loss: orders::Order,
+    ) -> Result<orders::OrderStatus, Error> {
+        self.send_order(stop_loss).await?;
+        self.send_order(take_profit).await
+    }
+
+    async fn get_outstanding_orders(&self, symbol: &str) -> Result<Vec<orders::Order>, Error> {
+        let pair = self.asset_pair(symbol).await?;
+        let sym = pair.to_symbol(symbol);
+        Ok(self
+            .private::<OpenOrders>("/0/private/OpenOrders", Vec::new())
+            .await?
+            .open
+            .into_values()
+            .filter(|info| {
+                info.descr
+                    .as_ref()
+                    .is_some_and(|descr| descr.pair == pair.altname || descr.pair == symbol)
+            })
+            .filter_map(|info| info.to_order(sym.clone()).ok())
+            .collect())
+    }
+
+    // conditional closes only take a single close order, not a pair
+    fn native_oco(&self) -> bool {
+        false
+    }
+
+    // orders are canceled by their client id
+    async fn cancel_order(&self, order: orders::Order) -> Result<orders::OrderStatus, Error> {
+        let queries = vec![(String::from("cl_ord_id"), client_id(&order))];
+        self.private::<serde_json::Value>("/0/private/CancelOrder", queries).await?;
+        Ok(orders::OrderStatus::Canceled)
+    }
+
+    // open orders first, the closed ones are only kept for a while
+    async fn order_exists(&self, order: orders::Order) -> Result<bool, Error> {
+        let queries = vec![(String::from("cl_ord_id"), client_id(&order))];
+        let open = self.private::<OpenOrders>("/0/private/OpenOrders", queries.clone()).await?;
+        if !open.open.is_empty() {
+            return Ok(true);
+        }
+        let closed = self.private::<ClosedOrders>("/0/private/ClosedOrders", queries).await?;
+        Ok(!closed.closed.is_empty())
+    }
+}
+
+type WsConnection = actix_codec::Framed<BoxedSocket, Codec>;
+
+pub struct Live {
+    ticks: Vec<Tick>,
+    token: String,
+    // public and private connections, none until a connection attempt succeeds
+    conns: Option<(WsConnection, WsConnection)>,
+    // connection attempts failed in a row
+    failures: u32,
+    state: FeedState,
+    events: VecDeque<LiveEvent>,
+    heartbeat: chrono::NaiveDateTime,
+}
+
+impl Live {
+    pub async fn new(ticks: Vec<Tick>, listen_key: String) -> Self {
+        let mut live = Self {
+            ticks,
+            token: String::new(),
+            conns: None,
+            failures: 0,
+            state: FeedState::new(HashMap::new()),
+            events: VecDeque::new(),
+            heartbeat: Utc::now().naive_utc(),
+        };
+        live.reconnect(listen_key).await;
+        live
+    }
+
+    // the ws names are needed to subscribe, they are looked up until found
+    async fn open(&mut self, token: &str) -> Result<(WsConnection, WsConnection), Error> {
+        if self.state.pairs.is_empty() {
+            let client = Client::builder().finish();
+            let names: Vec<&str> = self.ticks.iter().map(|tick| tick.sym.as_str()).collect();
+            self.state.pairs = get_asset_pairs(&client, REST_URL, &names)
+                .await?
+                .into_iter()
+                .filter_map(|(name, pair)| {
+                    names
+                        .iter()
+                        .find(|sym| **sym == name || **sym == pair.altname)
+                        .map(|sym| (pair.wsname.clone(), pair.to_symbol(sym)))
+                })
+                .collect();
+        }
+        connect(&self.ticks, token, &self.state.pairs).await
+    }
+
+    async fn on_frame(&mut self, frame: Option<Result<Frame, awc::error::WsProtocolError>>, public: bool) -> Option<LiveEvent> {
+        match frame {
+            None => {
+                warn!("kraken - connection dropped");
+                Some(LiveEvent::ReconnectionRequired)
+            }
+            Some(Ok(Frame::Text(text))) => match parse_ws_message(&text) {
+                Ok(msg) => {
+                    self.events.extend(self.state.interpret(msg));
+                    None
+                }
+                Err(e) => {
+                    error!("kraken - {} in {:?}", e, text);
+                    None
+                }
+            },
+            Some(Ok(Frame::Ping(bytes))) => {
+                let (public_conn, private_conn) = self.conns.as_mut()?;
+                let conn = if public { public_conn } else { private_conn };
+                if let Err(err) = conn.send(Message::Pong(bytes)).await {
+                    warn!("kraken - pong failed {:?}", err);
+                    return Some(LiveEvent::ReconnectionRequired);
+                }
+                None
+            }
+            Some(Ok(Frame::Close(reasons))) => {
+                warn!("connection closed {:?}", reasons);
+                Some(LiveEvent::ReconnectionRequired)
+            }
+            Some(Err(e)) => {
+                error!("kraken - {:?}", e);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl LiveFeed for Live {
+    fn token(&self) -> String {
+        self.token.clone()
+    }
+
+    async fn reconnect(&mut self, new_key: String) {
+        match self.open(&new_key).await {
+            Ok(conns) => {
+                self.conns = Some(conns);
+                self.failures = 0;
+            }
+            Err(err) => {
+                self.conns = None;
+                self.failures += 1;
+                error!("kraken - {:?}, attempt {}", err, self.failures);
+            }
+        }
+        self.token = new_key;
+        self.heartbeat = Utc::now().naive_utc();
+    }
+
+    async fn next(&mut self) -> LiveEvent {
+        let hb_interval = Duration::minutes(1);
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return event;
+            }
+            let (public, private) = match self.conns.as_mut() {
+                Some(conns) => conns,
+                None => {
+                    // the connection failed, waiting longer after each attempt
+                    let wait = 2u64.pow(self.failures.min(6)).min(60);
+                    actix_rt::time::delay_for(std::time::Duration::from_secs(wait)).await;
+                    return LiveEvent::ReconnectionRequired;
+                }
+            };
+            let now = Utc::now().naive_utc();
+            if now - self.heartbeat > hb_interval {
+                let ping = String::from(r#"{"event":"ping"}"#);
+                if let Err(err) = public.send(Message::Text(ping.clone())).await {
+                    warn!("kraken - ping failed {:?}", err);
+                    return LiveEvent::ReconnectionRequired;
+                }
+                if let Err(err) = private.send(Message::Text(ping)).await {
+                    warn!("kraken - ping failed {:?}", err);
+                    return LiveEvent::ReconnectionRequired;
+                }
+                self.heartbeat = now;
+            }
+            let (frame, public) = match select(public.next(), private.next()).await {
+                Either::Left((frame, _)) => (frame, true),
+                Either::Right((frame, _)) => (frame, false),
+            };
+            debug!("[{}] received a next {:?}", Utc::now(), frame);
+            if let Some(event) = self.on_frame(frame, public).await {
+                return event;
+            }
+        }
+    }
+}
+
+// keeps what the channels don't repeat in every message:
+// the candles in progress and the orders we were told about
+pub(super) struct FeedState {
+    // symbols by ws name
+    pairs: HashMap<String, Symbol>,
+    // ws name and interval minutes to the last update of the candle in progress
+    candles: HashMap<(String, i64), WsOhlc>,
+    // kraken txid to order
+    orders: HashMap<String, orders::Order>,
+}
+
+impl FeedState {
+    pub(super) fn new(pairs: HashMap<String, Symbol>) -> Self {
+        Self {
+            pairs,
+            candles: HashMap::new(),
+            orders: HashMap::new(),
+        }
+    }
+
+    pub(super) fn interpret(&mut self, msg: WsMessage) -> Vec<LiveEvent> {
+        match msg {
+            WsMessage::Ohlc(pair, minutes, ohlc) => self.on_ohlc(pair, minutes, ohlc).into_iter().collect(),
+            WsMessage::OpenOrders(updates, sequence) => updates
+                .into_iter()
+                .flat_map(|update| update.into_iter())
+                .filter_map(|(txid, info)| self.on_order(txid, info, sequence == 1))
+                .collect(),
+            WsMessage::OwnTrades(trades) => {
+                // fills are reported through openOrders, which carries the cumulative values
+                for (txid, trade) in trades.iter().flat_map(|trades| trades.iter()) {
+                    debug!(
+                        "kraken - trade {} of {}: {} {} {} at {}",
+                        txid, trade.ordertxid, trade.pair, trade.side, trade.vol, trade.price
+                    );
+                }
+                Vec::new()
+            }
+            WsMessage::Event(event) => {
+                debug!("kraken - event {}", event);
+                Vec::new()
+            }
+        }
+    }
+
+    // a candle is closed when the first update of the following one arrives
+    fn on_ohlc(&mut self, pair: String, minutes: i64, ohlc: WsOhlc) -> Option<LiveEvent> {
+        let sym = self.pairs.get(&pair)?.symbol.clone();
+        let previous = self.candles.insert((pair, minutes), ohlc.clone())?;
+        if previous.end_time() == ohlc.end_time() {
+            return None;
+        }
+        let interval = Duration::minutes(minutes);
+        Some(LiveEvent::Candle(sym, interval, previous.to_candle(&interval)))
+    }
+
+    // the first message is a snapshot of the open orders, they are tracked without events
+    fn on_order(&mut self, txid: String, info: OrderInfo, snapshot: bool) -> Option<LiveEvent> {
+        match info.status.as_deref() {
+            Some("pending") | Some("open") if info.descr.is_some() => {
+                let pair = info.descr.as_ref().map(|descr| descr.pair.clone()).unwrap_or_default();
+                let sym = self.pairs.get(&pair)?.clone();
+                let order = info
+                    .to_order(sym)
+                    .map_err(|e| debug!("kraken - skipping order {}: {}", txid, e))
+                    .ok()?;
+                self.orders.insert(txid, order.clone());
+                if snapshot {
+                    info!("kraken - found open order {:?}", order);
+                    None
+                } else {
+                    Some(LiveEvent::NewOrder(order))
+                }
+            }
+            Some("closed") => {
+                let order = self.orders.remove(&txid)?;
+                match info.to_transaction(order) {
+                    Ok(tx) => Some(LiveEvent::Transaction(tx)),
+                    Err(e) => {
+                        warn!("kraken - closed order {}: {}", txid, e);
+                        None
+                    }
+                }
+            }
+            Some("canceled") | Some("expired") => {
+                let order = self.orders.remove(&txid)?;
+                Some(LiveEvent::OrderCanceled(order.symbol.symbol, order.id))
+            }
+            _ => None,
+        }
+    }
+}
+
+// --------------------------------
+// helper functions
+fn to_interval(interval: &Duration) -> Result<i64, Error> {
+    let minutes = interval.num_minutes();
+    if [1, 5, 15, 30, 60, 240, 1440, 10080, 21600].contains(&minutes) && *interval == Duration::minutes(minutes) {
+        Ok(minutes)
+    } else {
+        Err(Error::ErrNotFound(format!("no kraken interval of {}", interval)))
+    }
+}
+
+async fn get_asset_pairs(client: &Client, url: &str, names: &[&str]) -> Result<HashMap<String, AssetPair>, Error> {
+    let url = String::from(url) + "/0/public/AssetPairs";
+    client
+        .get(url)
+        .query(&[("pair", names.join(","))])
+        .map_err(|e| Error::Parse(e.to_string()))?
+        .send()
+        .await
+        .map_err(|e| Error::Network(format!("{:?}", e)))?
+        .json::<Response<HashMap<String, AssetPair>>>()
+        .limit(128_000_000)
+        .await
+        .map_err(|e| Error::Parse(format!("{:?}", e)))?
+        .into_result()
+}
+
+async fn connect(ticks: &[Tick], token: &str, pairs: &HashMap<String, Symbol>) -> Result<(WsConnection, WsConnection), Error> {
+    let mut public = ws_connect(PUBLIC_WS_URL).await?;
+    // ticks kraken has no candles for are left out
+    let mut intervals: Vec<i64> = ticks
+        .iter()
+        .filter_map(|tick| {
+            to_interval(&tick.interval)
+                .map_err(|err| error!("kraken - not subscribing {} {:?}", tick.sym, err))
+                .ok()
+        })
+        .collect();
+    intervals.sort_unstable();
+    intervals.dedup();
+    for minutes in intervals {
+        let wsnames: Vec<&String> = ticks
+            .iter()
+            .filter(|tick| to_interval(&tick.interval).ok() == Some(minutes))
+            .filter_map(|tick| pairs.iter().find(|(_, sym)| sym.symbol == tick.sym).map(|(wsname, _)| wsname))
+            .collect();
+        let subscribe = serde_json::json!({
+            "event": "subscribe",
+            "pair": wsnames,
+            "subscription": {"name": "ohlc", "interval": minutes},
+        });
+        public
+            .send(Message::Text(subscribe.to_string()))
+            .await
+            .map_err(|e| Error::Network(format!("in subscribing to ohlc: {:?}", e)))?;
+    }
+    let mut private = ws_connect(PRIVATE_WS_URL).await?;
+    let own_trades = serde_json::json!({
+        "event": "subscribe",
+        "subscription": {"name": "ownTrades", "token": token, "snapshot": false},
+    });
+    let open_orders = serde_json::json!({
+        "event": "subscribe",
+        "subscription": {"name": "openOrders", "token": token},
+    });
+    for subscribe in [own_trades, open_orders].iter() {
+        private
+            .send(Message::Text(subscribe.to_string()))
+            .await
+            .map_err(|e| Error::Network(format!("in subscribing to private channels: {:?}", e)))?;
+    }
+    Ok((public, private))
+}
+
+async fn ws_connect(url: &str) -> Result<WsConnection, Error> {
+    let (resp, conn) = Client::builder()
+        .max_http_version(awc::http::Version::HTTP_11)
+        .finish()
+        .ws(url)
+        .connect()
+        .await
+        .map_err(|e| Error::Network(format!("on ws connecting to kraken: {:?}", e)))?;
+    debug!("new response {:?}", resp);
+    Ok(conn)
+}
+
+fn order_to_query(order: &orders::Order) -> Vec<(String, String)> {
+    let side = match order.side {
+        orders::Side::Buy => "buy",
+        orders::Side::Sell => "sell",
+    };
+    let mut queries: Vec<(String, String)> = vec![
+        (String::from("pair"), order.symbol.symbol.clone()),
+        (String::from("type"), String::from(side)),
+        (
+            String::from("volume"),
+            format!("{:.prec$}", order.volume.max(order.symbol.min_volume), prec = order.symbol.base_decimals),
+        ),
+        (String::from("cl_ord_id"), client_id(order)),
+    ];
+    match order.o_type {
+        orders::Type::Market => {
+            queries.push((String::from("ordertype"), String::from("market")));
+        }
+        orders::Type::Limit(price) => {
+            queries.push((String::from("ordertype"), String::from("limit")));
+            queries.push((
+                String::from("price"),
+                format!("{:.prec$}", price, prec = order.symbol.quote_decimals),
+            ));
+        }
+        orders::Type::StopLoss(stop) => {
+            queries.push((String::from("ordertype"), String::from("stop-loss")));
+            queries.push((
+                String::from("price"),
+                format!("{:.prec$}", stop, prec = order.symbol.quote_decimals),
+            ));
+        }
+    }
+    if let Some(expire) = order.expire {
+        queries.push((String::from("expiretm"), expire.and_utc().timestamp().to_string()));
+    }
+    queries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals_kraken_has_candles_for() {
+        assert_eq!(to_interval(&Duration::minutes(15)).unwrap(), 15);
+        assert_eq!(to_interval(&Duration::days(1)).unwrap(), 1440);
+        assert!(matches!(to_interval(&Duration::minutes(3)), Err(Error::ErrNotFound(_))));
+        assert!(matches!(to_interval(&Duration::seconds(90)), Err(Error::ErrNotFound(_))));
+    }
+}
@@ -0,0 +1,524 @@
+use crate::candles;
+use crate::error::Error;
+use crate::orders;
+use crate::symbol::Symbol;
+use crate::utils;
+use crate::wallets::SpotWallet;
+use chrono::{Duration, NaiveDateTime};
+use scan_fmt::scan_fmt;
+use std::collections::HashMap;
+
+// every rest response is wrapped in {"error": [...], "result": {...}}
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct Response<T> {
+    error: Vec<String>,
+    result: Option<T>,
+}
+impl<T> Response<T> {
+    // kraken answers 200 to everything, the errors are strings like "EOrder:Insufficient funds"
+    pub(super) fn into_result(self) -> Result<T, Error> {
+        if let Some(err) = self.error.first() {
+            let msg = self.error.join(", ");
+            return Err(if err.contains("Rate limit") || err.contains("Too many requests") {
+                Error::RateLimited(None)
+            } else if err.starts_with("EService:") {
+                Error::Http(503, msg)
+            } else if err.starts_with("EAPI:Invalid key") || err.starts_with("EAPI:Invalid signature") {
+                Error::Http(401, msg)
+            } else {
+                Error::Exchange(0, msg)
+            });
+        }
+        self.result.ok_or_else(|| Error::Parse(String::from("no result")))
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+pub(super) struct AssetPair {
+    pub altname: String,
+    #[serde(default)]
+    pub wsname: String,
+    base: String,
+    quote: String,
+    pair_decimals: usize,
+    lot_decimals: usize,
+    #[serde(default)]
+    ordermin: Option<String>,
+    #[serde(default)]
+    tick_size: Option<String>,
+}
+impl AssetPair {
+    pub(super) fn to_symbol(&self, name: &str) -> Symbol {
+        let price_tick = self
+            .tick_size
+            .as_ref()
+            .map_or(0.0, |tick| tick.parse::<f64>().expect("tick_size not an f64"));
+        Symbol {
+            symbol: String::from(name),
+            pretty: format!("{}-{}", self.base, self.quote),
+            base: self.base.clone(),
+            quote: self.quote.clone(),
+            base_decimals: self.lot_decimals,
+            quote_decimals: self.pair_decimals,
+            min_volume: self
+                .ordermin
+                .as_ref()
+                .map_or(0.0, |min| min.parse::<f64>().expect("ordermin not an f64")),
+            volume_step: 0.0,
+            min_price: price_tick,
+            price_tick,
+        }
+    }
+}
+
+// the result holds the candles under the pair name, plus the "last" timestamp
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub(super) enum OhlcEntry {
+    Candles(Vec<Ohlc>),
+    Last(i64),
+}
+
+// time, open, high, low, close, vwap, volume, count
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct Ohlc(i64, String, String, String, String, String, String, i64);
+impl Ohlc {
+    pub(super) fn to_candle(&self, interval: &Duration) -> candles::Candle {
+        candles::Candle {
+            tstamp: utils::from_timestamp(self.0, 0).expect("in Ohlc::to_candle"),
+            tframe: *interval,
+            open: self.1.parse::<f64>().expect("in ohlc.open"),
+            high: self.2.parse::<f64>().expect("in ohlc.high"),
+            low: self.3.parse::<f64>().expect("in ohlc.low"),
+            close: self.4.parse::<f64>().expect("in ohlc.close"),
+            volume: self.6.parse::<f64>().expect("in ohlc.volume"),
+        }
+    }
+}
+
+pub(super) fn to_wallet(balances: HashMap<String, String>) -> SpotWallet {
+    SpotWallet {
+        assets: balances
+            .into_iter()
+            .map(|(asset, balance)| (asset, balance.parse::<f64>().expect("balance not an f64")))
+            .collect(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct WsToken {
+    pub token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct OpenOrders {
+    pub open: HashMap<String, OrderInfo>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct ClosedOrders {
+    pub closed: HashMap<String, OrderInfo>,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+pub(super) struct OrderDescr {
+    pub pair: String,
+    #[serde(rename = "type")]
+    side: String,
+    ordertype: String,
+    #[serde(default)]
+    price: String,
+}
+
+// kraken takes a uuid or up to 18 characters of free text as cl_ord_id, too short for our client ids:
+// the id and tx_ref go as 16 hex digits each, the form of a uuid without hyphens
+pub(super) fn client_id(order: &orders::Order) -> String {
+    format!("{:016x}{:016x}", order.id, order.tx_ref)
+}
+
+// id and tx_ref of a cl_ord_id, kraken may hand it back hyphenated
+pub(super) fn parse_client_id(cl_ord_id: &str) -> Option<(orders::OrderId, orders::OrderId)> {
+    let hex: String = cl_ord_id.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let id = orders::OrderId::from_str_radix(&hex[..16], 16).ok()?;
+    let tx_ref = orders::OrderId::from_str_radix(&hex[16..], 16).ok()?;
+    Some((id, tx_ref))
+}
+
+// shared by the rest open orders and the openOrders channel
+// the channel only sends the fields that changed
+#[derive(Debug, serde::Deserialize, Clone)]
+pub(super) struct OrderInfo {
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    userref: Option<i64>,
+    #[serde(default)]
+    cl_ord_id: Option<String>,
+    #[serde(default)]
+    pub descr: Option<OrderDescr>,
+    #[serde(default)]
+    opentm: Option<serde_json::Value>,
+    #[serde(default)]
+    vol: Option<String>,
+    #[serde(default)]
+    vol_exec: Option<String>,
+    #[serde(default)]
+    fee: Option<String>,
+    #[serde(default)]
+    avg_price: Option<String>,
+    #[serde(default)]
+    lastupdated: Option<serde_json::Value>,
+}
+impl OrderInfo {
+    // our ids travel in cl_ord_id, userref only carried the id of older orders
+    pub(super) fn order_id(&self) -> Option<(Option<u16>, orders::OrderId, orders::OrderId)> {
+        if let Some(cl_ord_id) = &self.cl_ord_id {
+            if let Some((id, tx_ref)) = parse_client_id(cl_ord_id) {
+                return Some((orders::order_instance(id), id, tx_ref));
+            }
+            if let Ok((id, tx_ref)) = scan_fmt!(cl_ord_id, "{d}_{d}", u64, u64) {
+                return Some((None, id, tx_ref));
+            }
+        }
+        self.userref.filter(|userref| *userref > 0).map(|userref| (None, userref as orders::OrderId, 0))
+    }
+
+    pub(super) fn to_order(&self, symbol: Symbol) -> Result<orders::Order, String> {
+        let (owner, id, tx_ref) = self.order_id().ok_or_else(|| String::from("no order IDs"))?;
+        let descr = self.descr.as_ref().ok_or_else(|| String::from("no order description"))?;
+        let side = match descr.side.as_str() {
+            "buy" => orders::Side::Buy,
+            "sell" => orders::Side::Sell,
+            other => return Err(format!("unknown side {}", other)),
+        };
+        let o_type = match descr.ordertype.as_str() {
+            "market" => orders::Type::Market,
+            "limit" => orders::Type::Limit(parse_number("descr.price", &descr.price)?),
+            "stop-loss" => orders::Type::StopLoss(parse_number("descr.price", &descr.price)?),
+            other => return Err(format!("unsupported order type {}", other)),
+        };
+        let volume = match &self.vol {
+            Some(vol) => parse_number("vol", vol)?,
+            None => 0.0,
+        };
+        Ok(orders::Order {
+            tstamp: self.opentm.as_ref().and_then(to_tstamp),
+            volume,
+            exchange: String::from("kraken"),
+            expire: None,
+            side,
+            symbol,
+            id,
+            o_type,
+            tx_ref,
+            take_profit: None,
+            stop_loss: None,
+            oco_ref: 0,
+            owner,
+        })
+    }
+
+    // a closed order, with the order as it was when opened
+    pub(super) fn to_transaction(&self, order: orders::Order) -> Result<orders::Transaction, String> {
+        let volume = parse_number(
+            "vol_exec",
+            self.vol_exec.as_ref().ok_or_else(|| String::from("no executed volume"))?,
+        )?;
+        if volume == 0.0 {
+            return Err(String::from("order not filled"));
+        }
+        let avg_price = parse_number(
+            "avg_price",
+            self.avg_price.as_ref().ok_or_else(|| String::from("no average price"))?,
+        )?;
+        let fees = match &self.fee {
+            Some(fee) => parse_number("fee", fee)?,
+            None => 0.0,
+        };
+        let tstamp = self
+            .lastupdated
+            .as_ref()
+            .and_then(to_tstamp)
+            .unwrap_or_else(|| chrono::Utc::now().naive_utc());
+        Ok(orders::Transaction {
+            tstamp,
+            symbol: order.symbol.symbol.clone(),
+            side: order.side.clone(),
+            avg_price,
+            volume,
+            fees,
+            // fees are charged in the quote currency by default
+            fees_asset: order.symbol.quote.clone(),
+            order,
+        })
+    }
+}
+
+// kraken sends numbers as strings
+fn parse_number(field: &str, value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .map_err(|e| format!("{} {:?} not a number: {}", field, value, e))
+}
+
+// kraken timestamps are seconds with decimals, either as strings or as numbers
+fn to_tstamp(value: &serde_json::Value) -> Option<NaiveDateTime> {
+    let secs = match value {
+        serde_json::Value::String(s) => s.parse::<f64>().ok()?,
+        serde_json::Value::Number(n) => n.as_f64()?,
+        _ => return None,
+    };
+    utils::from_timestamp(secs.trunc() as i64, (secs.fract() * 1e9) as u32)
+}
+
+// time, etime, open, high, low, close, vwap, volume, count
+#[derive(Debug, serde::Deserialize, Clone)]
+pub(super) struct WsOhlc(String, String, String, String, String, String, String, String, i64);
+impl WsOhlc {
+    pub(super) fn end_time(&self) -> &str {
+        &self.1
+    }
+    pub(super) fn to_candle(&self, interval: &Duration) -> candles::Candle {
+        let end = self.1.parse::<f64>().expect("in ohlc.etime") as i64;
+        candles::Candle {
+            tstamp: utils::from_timestamp(end, 0).expect("in WsOhlc::to_candle") - *interval,
+            tframe: *interval,
+            open: self.2.parse::<f64>().expect("in ohlc.open"),
+            high: self.3.parse::<f64>().expect("in ohlc.high"),
+            low: self.4.parse::<f64>().expect("in ohlc.low"),
+            close: self.5.parse::<f64>().expect("in ohlc.close"),
+            volume: self.7.parse::<f64>().expect("in ohlc.volume"),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct WsTrade {
+    pub ordertxid: String,
+    pub pair: String,
+    #[serde(rename = "type")]
+    pub side: String,
+    pub price: String,
+    pub vol: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct WsSequence {
+    pub sequence: u64,
+}
+
+pub(super) enum WsMessage {
+    // pair ws name, interval minutes, candle in progress
+    Ohlc(String, i64, WsOhlc),
+    OpenOrders(Vec<HashMap<String, OrderInfo>>, u64),
+    OwnTrades(Vec<HashMap<String, WsTrade>>),
+    // heartbeats, subscription statuses and the like
+    Event(serde_json::Value),
+}
+
+// public messages: [channel id, data, "ohlc-1", "XBT/EUR"]
+// private messages: [data, "openOrders", {"sequence": 1}]
+pub(super) fn parse_ws_message(text: &[u8]) -> Result<WsMessage, String> {
+    let value = serde_json::from_slice::<serde_json::Value>(text).map_err(|e| e.to_string())?;
+    let mut arr = match value {
+        serde_json::Value::Array(arr) => arr,
+        event => return Ok(WsMessage::Event(event)),
+    };
+    if arr.len() == 3 && arr[1].is_string() {
+        let channel = arr[1].as_str().unwrap_or_default().to_string();
+        let sequence = serde_json::from_value::<WsSequence>(arr.remove(2)).map_or(0, |seq| seq.sequence);
+        let data = arr.remove(0);
+        return match channel.as_str() {
+            "openOrders" => serde_json::from_value(data)
+                .map(|orders| WsMessage::OpenOrders(orders, sequence))
+                .map_err(|e| e.to_string()),
+            "ownTrades" => serde_json::from_value(data).map(WsMessage::OwnTrades).map_err(|e| e.to_string()),
+            other => Err(format!("unknown private channel {}", other)),
+        };
+    }
+    if arr.len() == 4 {
+        let channel = arr[2].as_str().unwrap_or_default();
+        let pair = arr[3].as_str().unwrap_or_default().to_string();
+        if let Ok(minutes) = scan_fmt!(channel, "ohlc-{d}", i64) {
+            let ohlc = serde_json::from_value::<WsOhlc>(arr.remove(1)).map_err(|e| e.to_string())?;
+            return Ok(WsMessage::Ohlc(pair, minutes, ohlc));
+        }
+        return Err(format!("unknown public channel {}", channel));
+    }
+    Err(String::from("unknown message layout"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xbt_usd() -> Symbol {
+        Symbol {
+            symbol: String::from("XBTUSD"),
+            base: String::from("XXBT"),
+            quote: String::from("ZUSD"),
+            ..Symbol::default()
+        }
+    }
+
+    // an order of ours, as the rest OpenOrders endpoint lists it
+    fn open_order(cl_ord_id: &str) -> OrderInfo {
+        let json = format!(
+            r#"{{"refid":null,"userref":0,"cl_ord_id":"{}","status":"open","opentm":1688666559.8974,"starttm":0,
+            "expiretm":0,"descr":{{"pair":"XBTUSD","type":"buy","ordertype":"limit","price":"30010.0","price2":"0",
+            "leverage":"none","order":"buy 1.25000000 XBTUSD @ limit 30010.0","close":""}},"vol":"1.25000000",
+            "vol_exec":"0.37500000","cost":"11253.7","fee":"0.00000","price":"30010.0","stopprice":"0.00000",
+            "limitprice":"0.00000","misc":"","oflags":"fciq"}}"#,
+            cl_ord_id
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn responses_map_kraken_errors() {
+        let ok: Response<WsToken> =
+            serde_json::from_str(r#"{"error":[],"result":{"token":"1Dwc4lzSwNWOAwkMdqhssNNFhs1ed606d1WcF3XfEMw","expires":900}}"#)
+                .unwrap();
+        assert_eq!(ok.into_result().unwrap().token, "1Dwc4lzSwNWOAwkMdqhssNNFhs1ed606d1WcF3XfEMw");
+        let parse = |json: &str| serde_json::from_str::<Response<WsToken>>(json).unwrap().into_result();
+        assert!(matches!(parse(r#"{"error":["EAPI:Rate limit exceeded"]}"#), Err(Error::RateLimited(None))));
+        assert!(matches!(parse(r#"{"error":["EService:Unavailable"]}"#), Err(Error::Http(503, _))));
+        assert!(matches!(parse(r#"{"error":["EAPI:Invalid key"]}"#), Err(Error::Http(401, _))));
+        assert!(matches!(parse(r#"{"error":["EOrder:Insufficient funds"]}"#), Err(Error::Exchange(0, _))));
+        assert!(matches!(parse(r#"{"error":[]}"#), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn ohlc_results_become_candles() {
+        let json = r#"{"error":[],"result":{"XXBTZUSD":[
+            [1688671200,"30306.1","30306.2","30305.7","30305.7","30306.1","3.39243896",23],
+            [1688671260,"30304.5","30304.5","30300.0","30300.3","30301.2","4.42996871",18]
+        ],"last":1688672160}}"#;
+        let result = serde_json::from_str::<Response<HashMap<String, OhlcEntry>>>(json)
+            .unwrap()
+            .into_result()
+            .unwrap();
+        let ohlcs = match &result["XXBTZUSD"] {
+            OhlcEntry::Candles(ohlcs) => ohlcs,
+            OhlcEntry::Last(_) => panic!("candles parsed as last"),
+        };
+        assert!(matches!(result["last"], OhlcEntry::Last(1688672160)));
+        let cnd = ohlcs[1].to_candle(&Duration::minutes(1));
+        assert_eq!(cnd.tstamp, utils::from_timestamp(1688671260, 0).unwrap());
+        assert_eq!(cnd.tframe, Duration::minutes(1));
+        assert_eq!((cnd.open, cnd.high, cnd.low, cnd.close), (30304.5, 30304.5, 30300.0, 30300.3));
+        assert_eq!(cnd.volume, 4.42996871);
+    }
+
+    #[test]
+    fn open_orders_keep_our_ids() {
+        let mut order = orders::Order::new();
+        order.set_owner(3);
+        order.tx_ref = 12345;
+        let info = open_order(&client_id(&order));
+        let parsed = info.to_order(xbt_usd()).unwrap();
+        assert_eq!((parsed.id, parsed.tx_ref, parsed.owner), (order.id, 12345, Some(3)));
+        assert_eq!(parsed.side, orders::Side::Buy);
+        assert_eq!(parsed.o_type, orders::Type::Limit(30010.0));
+        assert_eq!(parsed.volume, 1.25);
+        // orders placed by hand have no ids of ours
+        assert!(open_order("manual").to_order(xbt_usd()).is_err());
+    }
+
+    #[test]
+    fn client_ids_fit_kraken() {
+        let mut order = orders::Order::new();
+        order.set_owner(u16::MAX);
+        order.tx_ref = orders::OrderId::MAX;
+        let cl_ord_id = client_id(&order);
+        // a short uuid: 32 hex digits, anything else must fit in 18 characters
+        assert_eq!(cl_ord_id.len(), 32);
+        assert!(cl_ord_id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert!(orders::client_order_id(&order).len() > 18);
+        assert_eq!(parse_client_id(&cl_ord_id), Some((order.id, order.tx_ref)));
+        let hyphenated = format!(
+            "{}-{}-{}-{}-{}",
+            &cl_ord_id[..8],
+            &cl_ord_id[8..12],
+            &cl_ord_id[12..16],
+            &cl_ord_id[16..20],
+            &cl_ord_id[20..]
+        );
+        let parsed = open_order(&hyphenated).to_order(xbt_usd()).unwrap();
+        assert_eq!((parsed.id, parsed.tx_ref, parsed.owner), (order.id, order.tx_ref, Some(u16::MAX)));
+        assert_eq!(parse_client_id("arb-20240509-00010"), None);
+    }
+
+    #[test]
+    fn malformed_numbers_are_errors() {
+        let mut info = open_order(&client_id(&orders::Order::new()));
+        info.descr.as_mut().unwrap().price = String::from("n/a");
+        assert!(info.to_order(xbt_usd()).is_err());
+        let mut info = open_order(&client_id(&orders::Order::new()));
+        info.vol = Some(String::from(""));
+        assert!(info.to_order(xbt_usd()).unwrap_err().starts_with("vol "));
+    }
+
+    #[test]
+    fn closed_orders_become_transactions() {
+        let mut order = orders::Order::new();
+        order.symbol = xbt_usd();
+        order.side = orders::Side::Sell;
+        let json = r#"[[{"OGTT3Y-C6I3P-XRI6HX":{"status":"closed","vol_exec":"0.50000000","avg_price":"30000.0",
+            "fee":"7.80000","lastupdated":"1688666600.123"}}],"openOrders",{"sequence":5}]"#;
+        let (updates, sequence) = match parse_ws_message(json.as_bytes()).unwrap() {
+            WsMessage::OpenOrders(updates, sequence) => (updates, sequence),
+            _ => panic!("not an openOrders message"),
+        };
+        assert_eq!(sequence, 5);
+        let info = &updates[0]["OGTT3Y-C6I3P-XRI6HX"];
+        assert_eq!(info.status.as_deref(), Some("closed"));
+        let tx = info.to_transaction(order.clone()).unwrap();
+        assert_eq!((tx.side, tx.volume, tx.avg_price, tx.fees), (orders::Side::Sell, 0.5, 30000.0, 7.8));
+        assert_eq!(tx.fees_asset, "ZUSD");
+        // within the precision of the float seconds
+        let lastupdated = utils::from_timestamp(1688666600, 123_000_000).unwrap();
+        assert!((tx.tstamp - lastupdated).num_microseconds().unwrap().abs() < 1000);
+
+        let mut info = info.clone();
+        info.vol_exec = Some(String::from("0.00000000"));
+        assert!(info.to_transaction(order.clone()).is_err());
+        info.vol_exec = Some(String::from("half"));
+        assert!(info.to_transaction(order.clone()).is_err());
+        info.vol_exec = Some(String::from("0.5"));
+        info.fee = Some(String::from("?"));
+        assert!(info.to_transaction(order).is_err());
+    }
+
+    #[test]
+    fn ws_messages_by_channel() {
+        let ohlc = r#"[42,["1542057314.748456","1542057360.435743","3586.70000","3586.70000","3586.60000",
+            "3586.60000","3586.68894","0.03373000",2],"ohlc-5","XBT/USD"]"#;
+        match parse_ws_message(ohlc.as_bytes()).unwrap() {
+            WsMessage::Ohlc(pair, minutes, ohlc) => {
+                assert_eq!((pair.as_str(), minutes), ("XBT/USD", 5));
+                let cnd = ohlc.to_candle(&Duration::minutes(5));
+                assert_eq!(cnd.tstamp, utils::from_timestamp(1542057360 - 300, 0).unwrap());
+                assert_eq!(cnd.close, 3586.6);
+            }
+            _ => panic!("not an ohlc message"),
+        }
+        let trades = r#"[[{"TDLH43-DVQXD-2KHVYY":{"ordertxid":"OGTT3Y-C6I3P-XRI6HX","postxid":"TKH2SE-M7IF5-CFI7LT",
+            "pair":"XBT/USD","time":"1688666600.123","type":"sell","ordertype":"limit","price":"30000.0",
+            "cost":"15000.0","fee":"7.8","vol":"0.5","margin":"0.0"}}],"ownTrades",{"sequence":2}]"#;
+        match parse_ws_message(trades.as_bytes()).unwrap() {
+            WsMessage::OwnTrades(trades) => assert_eq!(trades[0]["TDLH43-DVQXD-2KHVYY"].vol, "0.5"),
+            _ => panic!("not an ownTrades message"),
+        }
+        assert!(matches!(
+            parse_ws_message(br#"{"event":"heartbeat"}"#).unwrap(),
+            WsMessage::Event(_)
+        ));
+        assert!(parse_ws_message(br#"[1,{},"spread","XBT/USD"]"#).is_err());
+        assert!(parse_ws_message(br#"[[],"ownTrades"]"#).is_err());
+        assert!(parse_ws_message(b"not json").is_err());
+    }
+}
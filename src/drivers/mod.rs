@@ -4,11 +4,15 @@ use crate::symbol::Symbol;
 use crate::{candles, orders, wallets};
 use async_trait::async_trait;
 use chrono::{Duration, NaiveDateTime};
-use crate::orders::{Order, Transaction, OrderStatus};
+use crate::orders::{Order, OrderId, Transaction, OrderStatus};
 use std::vec::Vec;
 
 pub mod binance;
+pub mod binance_limits;
 pub mod binance_types;
+pub mod kraken;
+pub mod kraken_types;
+pub mod simulated;
 
 #[async_trait(?Send)]
 pub trait RestApi {
@@ -18,18 +22,32 @@ pub trait RestApi {
         interval: Option<&Duration>,
         start: Option<&NaiveDateTime>,
         limit: Option<usize>,
-    ) -> Vec<candles::Candle>;
+    ) -> Result<Vec<candles::Candle>, Error>;
     async fn get_symbol_info(&self, sym: &str) -> Result<Symbol, Error>;
     async fn get_wallet(&self) -> Result<wallets::SpotWallet,Error>;
-    async fn refresh_ws_token(&self, old_token: Option<String>) -> String;
-    async fn send_order(&self, order : Order) -> OrderStatus;
-    async fn cancel_order(&self, symbol: String, order_id : u32) -> OrderStatus;
-    async fn get_outstanding_orders(&self, symbol: &str) -> Vec<Order>;
+    async fn refresh_ws_token(&self, old_token: Option<String>) -> Result<String, Error>;
+    async fn send_order(&self, order : Order) -> Result<OrderStatus, Error>;
+    async fn send_oco_order(&self, take_profit: Order, stop_loss: Order) -> Result<OrderStatus, Error>;
+    // canceled by client order id, the order as it was sent
+    async fn cancel_order(&self, order: Order) -> Result<OrderStatus, Error>;
+    // whether the exchange has any record of the order, open or closed, by client order id
+    async fn order_exists(&self, order: Order) -> Result<bool, Error>;
+    async fn get_outstanding_orders(&self, symbol: &str) -> Result<Vec<Order>, Error>;
+    // whether the exchange cancels the other leg of an oco pair itself, the live loop does it otherwise
+    fn native_oco(&self) -> bool {
+        true
+    }
 }
 
 pub fn create_rest_client(exchange: &str, config: &ExchangeSettings) -> Result<Box<dyn RestApi>, Error> {
     match exchange {
-        "binance" => Ok(Box::new(binance::Rest::new(&config.api_key, &config.secret_key))),
+        "binance" => Ok(Box::new(binance::Rest::new(
+            &config.api_key,
+            &config.secret_key,
+            config.recv_window,
+        ))),
+        "kraken" => Ok(Box::new(kraken::Rest::new(&config.api_key, &config.secret_key))),
+        "sim" => Ok(Box::new(simulated::Rest::new(config)?)),
         _ => Err(Error::ErrNotFound(format!("can't find driver {}", exchange))),
     }
 }
@@ -42,9 +60,13 @@ pub enum LiveEvent {
     Generic(String),
     Transaction(orders::Transaction),
     NewOrder(orders::Order),
-    Candle(String, candles::Candle),
+    OrderCanceled(String, OrderId),
+    // symbol and interval of the tick the candle closes
+    Candle(String, Duration, candles::Candle),
     BalanceUpdate(wallets::SpotWallet),
     AssetUpdate{asset: String, delta: f64},
+    // SIGINT / SIGTERM, or the end of a simulation
+    Shutdown,
 }
 
 #[derive(Clone)]
@@ -67,6 +89,8 @@ pub async fn create_live_driver(exchange :&str, listen_key: String, ticks: Vec<T
             let live = Box::new(binance::Live::new(ticks, listen_key).await);
             Ok(live)
         }
+        "kraken" => Ok(Box::new(kraken::Live::new(ticks, listen_key).await)),
+        "sim" => Ok(Box::new(simulated::Live::new(ticks, listen_key).await?)),
         _ => Err(Error::ErrNotFound(format!("can't find driver {}", exchange))),
     }
 }
@@ -0,0 +1,381 @@
+use crate::candles::{aggregate, bucket_start, merge, Candle};
+use crate::configuration::{ExchangeSettings, SimSettings};
+use crate::drivers::{LiveEvent, LiveFeed, RestApi, Tick};
+use crate::error::Error;
+use crate::orders::{Order, OrderStatus};
+use crate::paper::PaperBroker;
+use crate::storage::{self, CandleStore};
+use crate::symbol::Symbol;
+use crate::wallets::SpotWallet;
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime};
+use log::{debug, info};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+// state shared by the rest client and the live feed of a simulation
+// orders are matched by the paper trading broker against the replayed 1m candles
+struct Exchange {
+    settings: SimSettings,
+    broker: PaperBroker,
+    // end of the last replayed minute
+    clock: NaiveDateTime,
+    // minutes of the csv file by symbol, oldest first, read on first use
+    csv: Option<Rc<HashMap<String, Vec<Candle>>>>,
+}
+
+thread_local! {
+    // simulations by listen token, the feed gets hold of the rest client state through it
+    static EXCHANGES: RefCell<HashMap<String, Rc<RefCell<Exchange>>>> = RefCell::new(HashMap::new());
+}
+
+pub struct Rest {
+    token: String,
+    exchange: Rc<RefCell<Exchange>>,
+}
+
+impl Rest {
+    pub fn new(config: &ExchangeSettings) -> Result<Rest, Error> {
+        let settings = config
+            .sim
+            .clone()
+            .ok_or_else(|| Error::ErrNotFound(String::from("no sim settings")))?;
+        let wallet = SpotWallet {
+            assets: settings.balances.clone(),
+        };
+        let exchange = Rc::new(RefCell::new(Exchange {
+            clock: settings.start.and_hms_opt(0, 0, 0).expect("midnight"),
+            broker: PaperBroker::new(config.backtest.clone(), wallet),
+            settings,
+            csv: None,
+        }));
+        let token = format!("sim-{}", rand::random::<u32>());
+        EXCHANGES.with(|exchanges| exchanges.borrow_mut().insert(token.clone(), exchange.clone()));
+        Ok(Rest { token, exchange })
+    }
+}
+
+#[async_trait(?Send)]
+impl RestApi for Rest {
+    // candles closed before the replay clock
+    async fn get_candles(
+        &self,
+        sym: &str,
+        interval: Option<&Duration>,
+        start: Option<&NaiveDateTime>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Candle>, Error> {
+        let clock = self.exchange.borrow().clock;
+        let interval = interval.copied().unwrap_or_else(|| Duration::minutes(1));
+        let limit = limit.unwrap_or(500);
+        let span = interval * limit as i32;
+        let (from, to) = match start {
+            Some(start) => (*start, (*start + span).min(clock)),
+            None => (bucket_start(&(clock - span), &interval), clock),
+        };
+        let minutes = load_minutes(&self.exchange, sym, &from, &to).await?;
+        let mut cnds: Vec<Candle> = aggregate(&minutes, &interval)
+            .into_iter()
+            .filter(|cnd| cnd.tstamp + cnd.tframe <= clock)
+            .collect();
+        if cnds.len() > limit {
+            cnds.drain(0..cnds.len() - limit);
+        }
+        Ok(cnds)
+    }
+
+    // symbols are any pair of assets in the configured balances
+    async fn get_symbol_info(&self, sym: &str) -> Result<Symbol, Error> {
+        let exchange = self.exchange.borrow();
+        let balances = &exchange.settings.balances;
+        let (base, quote) = balances
+            .keys()
+            .filter_map(|quote| sym.strip_suffix(quote.as_str()).map(|base| (base, quote)))
+            .find(|(base, _)| balances.contains_key(*base))
+            .ok_or_else(|| Error::ErrNotFound(format!("{} not in sim balances", sym)))?;
+        Ok(Symbol {
+            symbol: String::from(sym),
+            pretty: format!("{}-{}", base, quote),
+            base: String::from(base),
+            quote: quote.clone(),
+            base_decimals: 8,
+            quote_decimals: 8,
+            ..Symbol::default()
+        })
+    }
+
+    async fn get_wallet(&self) -> Result<SpotWallet, Error> {
+        Ok(SpotWallet {
+            assets: self.exchange.borrow().broker.wallet().assets.clone(),
+        })
+    }
+
+    async fn refresh_ws_token(&self, old_token: Option<String>) -> Result<String, Error> {
+        Ok(old_token.unwrap_or_else(|| self.token.clone()))
+    }
+
+    async fn send_order(&self, order: Order) -> Result<OrderStatus, Error> {
+        Ok(self.exchange.borrow_mut().broker.send_order(order))
+    }
+
+    async fn send_oco_order(&self, take_profit: Order, stop_loss: Order) -> Result<OrderStatus, Error> {
+        Ok(self.exchange.borrow_mut().broker.send_oco_order(take_profit, stop_loss))
+    }
+
+    async fn cancel_order(&self, order: Order) -> Result<OrderStatus, Error> {
+        Ok(self.exchange.borrow_mut().broker.cancel_order(order.symbol.symbol, order.id))
+    }
+
+    async fn get_outstanding_orders(&self, symbol: &str) -> Result<Vec<Order>, Error> {
+        Ok(self.exchange.borrow().broker.outstanding_orders(symbol))
+    }
+
+    // sends never fail halfway here, only the outstanding orders are known
+    async fn order_exists(&self, order: Order) -> Result<bool, Error> {
+        let exchange = self.exchange.borrow();
+        let outstanding = exchange.broker.outstanding_orders(&order.symbol.symbol);
+        Ok(outstanding.iter().any(|ord| ord.id == order.id))
+    }
+}
+
+pub struct Live {
+    token: String,
+    exchange: Rc<RefCell<Exchange>>,
+    ticks: Vec<Tick>,
+    // minutes still to be replayed, oldest first
+    minutes: HashMap<String, VecDeque<Candle>>,
+    // candles being built, one per tick
+    partials: Vec<Option<Candle>>,
+    candles: VecDeque<LiveEvent>,
+}
+
+impl Live {
+    pub async fn new(ticks: Vec<Tick>, listen_key: String) -> Result<Live, Error> {
+        let exchange = EXCHANGES
+            .with(|exchanges| exchanges.borrow().get(&listen_key).cloned())
+            .ok_or_else(|| Error::ErrNotFound(format!("no sim exchange for {}", listen_key)))?;
+        let (settings, clock) = {
+            let exchange = exchange.borrow();
+            (exchange.settings.clone(), exchange.clock)
+        };
+        let end = (settings.end + Duration::days(1)).and_hms_opt(0, 0, 0).expect("midnight");
+        let mut minutes: HashMap<String, VecDeque<Candle>> = HashMap::new();
+        for tick in &ticks {
+            if !minutes.contains_key(&tick.sym) {
+                let cnds = load_minutes(&exchange, &tick.sym, &clock, &end).await?;
+                info!("sim - replaying {} minutes of {}", cnds.len(), tick.sym);
+                minutes.insert(tick.sym.clone(), cnds.into_iter().collect());
+            }
+        }
+        Ok(Live {
+            token: listen_key,
+            exchange,
+            partials: vec![None; ticks.len()],
+            ticks,
+            minutes,
+            candles: VecDeque::new(),
+        })
+    }
+
+    // replays the next minute, returns false once all the candles are replayed
+    async fn step(&mut self) -> bool {
+        let tstamp = match self.minutes.values().filter_map(|cnds| cnds.front()).map(|cnd| cnd.tstamp).min() {
+            Some(tstamp) => tstamp,
+            None => return false,
+        };
+        let speed = self.exchange.borrow().settings.speed;
+        if speed > 0.0 {
+            actix_rt::time::delay_for(std::time::Duration::from_secs_f64(60.0 / speed)).await;
+        }
+        let mut exchange = self.exchange.borrow_mut();
+        for (sym, cnds) in self.minutes.iter_mut() {
+            if cnds.front().is_none_or(|cnd| cnd.tstamp != tstamp) {
+                continue;
+            }
+            let minute = cnds.pop_front().expect("no front minute");
+            exchange.broker.match_orders(sym, &minute);
+            for (tick, partial) in self.ticks.iter().zip(self.partials.iter_mut()) {
+                if tick.sym == *sym {
+                    merge(partial, &minute, &tick.interval);
+                }
+            }
+        }
+        exchange.clock = tstamp + Duration::minutes(1);
+        // closed candles are sent once the minute completing them is replayed
+        for (tick, partial) in self.ticks.iter().zip(self.partials.iter_mut()) {
+            if bucket_start(&exchange.clock, &tick.interval) == exchange.clock {
+                if let Some(cnd) = partial.take() {
+                    self.candles.push_back(LiveEvent::Candle(tick.sym.clone(), tick.interval, cnd));
+                }
+            }
+        }
+        true
+    }
+}
+
+#[async_trait(?Send)]
+impl LiveFeed for Live {
+    async fn next(&mut self) -> LiveEvent {
+        loop {
+            if let Some(event) = self.exchange.borrow_mut().broker.next_event() {
+                return event;
+            }
+            if let Some(event) = self.candles.pop_front() {
+                return event;
+            }
+            if !self.step().await {
+                info!("sim - replay is over");
+                return LiveEvent::Shutdown;
+            }
+        }
+    }
+
+    fn token(&self) -> String {
+        self.token.clone()
+    }
+
+    async fn reconnect(&mut self, _new_key: String) {
+        debug!("sim - nothing to reconnect");
+    }
+}
+
+// 1m candles within [start, end), oldest first
+async fn load_minutes(
+    exchange: &Rc<RefCell<Exchange>>,
+    sym: &str,
+    start: &NaiveDateTime,
+    end: &NaiveDateTime,
+) -> Result<Vec<Candle>, Error> {
+    let settings = exchange.borrow().settings.clone();
+    if let Some(path) = &settings.csv {
+        let cached = exchange.borrow().csv.clone();
+        let csv = match cached {
+            Some(csv) => csv,
+            None => {
+                let csv = Rc::new(read_csv(path)?);
+                exchange.borrow_mut().csv = Some(csv.clone());
+                csv
+            }
+        };
+        let minutes = csv.get(sym).map_or(&[][..], |cnds| cnds.as_slice());
+        return Ok(minutes
+            .iter()
+            .filter(|cnd| cnd.tstamp >= *start && cnd.tstamp < *end)
+            .copied()
+            .collect());
+    }
+    match (&settings.candle_storage, &settings.exchange) {
+        (Some(host), Some(exchange)) => {
+            let storage = storage::create_candle_store(host).await?;
+            Ok(storage.get_minutes(exchange, sym, start, end).await)
+        }
+        _ => Err(Error::ErrNotFound(String::from("sim needs either csv or candle_storage and exchange"))),
+    }
+}
+
+// minutes by symbol, oldest first
+fn read_csv(path: &str) -> Result<HashMap<String, Vec<Candle>>, Error> {
+    let content = std::fs::read_to_string(path).map_err(|e| Error::Unexpected(Box::new(e)))?;
+    let mut minutes: HashMap<String, Vec<Candle>> = HashMap::new();
+    // an optional header line names the columns
+    for line in content.lines().filter(|line| !line.trim().is_empty() && !line.starts_with("symbol,")) {
+        let (sym, cnd) = parse_csv_line(line).ok_or_else(|| Error::ErrNotFound(format!("malformed line {}", line)))?;
+        minutes.entry(String::from(sym)).or_default().push(cnd);
+    }
+    for cnds in minutes.values_mut() {
+        cnds.sort_by_key(|cnd| cnd.tstamp);
+    }
+    Ok(minutes)
+}
+
+// symbol,tstamp,open,high,low,close,volume
+fn parse_csv_line(line: &str) -> Option<(&str, Candle)> {
+    let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+    if fields.len() != 7 {
+        return None;
+    }
+    Some((
+        fields[0],
+        Candle {
+            tstamp: NaiveDateTime::parse_from_str(fields[1], "%Y-%m-%d %H:%M:%S").ok()?,
+            tframe: Duration::minutes(1),
+            open: fields[2].parse().ok()?,
+            high: fields[3].parse().ok()?,
+            low: fields[4].parse().ok()?,
+            close: fields[5].parse().ok()?,
+            volume: fields[6].parse().ok()?,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{ExchangeSettings, StrategySettings};
+    use crate::live::{run_live, Mode};
+    use crate::orders::Side;
+    use crate::storage::MemoryTransactions;
+    use std::collections::HashMap;
+
+    // three minutes of history at 100, a dip to 90 under the buy price and a rise to 110 over the sell one
+    const CSV: &str = "symbol,tstamp,open,high,low,close,volume
+BTCUSDT,2020-12-31 23:57:00,100,100,100,100,1
+BTCUSDT,2020-12-31 23:58:00,100,100,100,100,1
+BTCUSDT,2020-12-31 23:59:00,100,100,100,100,1
+BTCUSDT,2021-01-01 00:00:00,90,90,90,90,1
+BTCUSDT,2021-01-01 00:01:00,90,90,90,90,1
+BTCUSDT,2021-01-01 00:02:00,110,110,110,110,1
+BTCUSDT,2021-01-01 00:03:00,110,110,110,110,1
+";
+
+    #[test]
+    fn replays_a_csv_through_the_live_loop() {
+        let path = std::env::temp_dir().join(format!("sim-{}.csv", rand::random::<u32>()));
+        std::fs::write(&path, CSV).unwrap();
+        let exchange_settings: ExchangeSettings = serde_json::from_value(serde_json::json!({
+            "backtest": { "fees_perc": 0.1 },
+            "sim": {
+                "csv": path.to_str().unwrap(),
+                "start": "2021-01-01",
+                "end": "2021-01-01",
+                "balances": { "BTC": 0.0, "USDT": 1000.0 }
+            }
+        }))
+        .unwrap();
+        let strategy = StrategySettings {
+            name: String::from("buyDips"),
+            exchange: String::from("sim"),
+            symbol: String::from("BTCUSDT"),
+            time_frame: chrono::Duration::minutes(1),
+            settings: vec![("period", "3"), ("gain_factor", "0.1"), ("max_ops", "1")]
+                .into_iter()
+                .map(|(key, value)| (String::from(key), String::from(value)))
+                .collect::<HashMap<_, _>>(),
+            id: None,
+        };
+        let (wallet, storage) = actix_rt::System::new("sim-test").block_on(async move {
+            let mut storage = MemoryTransactions::default();
+            let wallet = run_live(vec![strategy], &mut storage, exchange_settings, Mode::Live).await;
+            (wallet, storage)
+        });
+        std::fs::remove_file(&path).unwrap();
+
+        let txs: Vec<_> = storage.transactions().iter().map(|(_, tx)| tx).collect();
+        assert_eq!(txs.len(), 2);
+        let (buy, sell) = (txs[0], txs[1]);
+        assert_eq!(buy.side, Side::Buy);
+        assert_eq!(sell.side, Side::Sell);
+        assert_eq!(sell.order.tx_ref, buy.order.id);
+        assert!(sell.avg_price > buy.avg_price);
+        // the wallet went through both fills and their fees
+        let fees = |tx: &crate::orders::Transaction, asset: &str| if tx.fees_asset == asset { tx.fees } else { 0.0 };
+        let usdt = 1000.0 - buy.avg_price * buy.volume + sell.avg_price * sell.volume
+            - fees(buy, "USDT")
+            - fees(sell, "USDT");
+        let btc = buy.volume - sell.volume - fees(buy, "BTC") - fees(sell, "BTC");
+        assert!((wallet.assets["USDT"] - usdt).abs() < 1e-6, "{:?}", wallet);
+        assert!((wallet.assets["BTC"] - btc).abs() < 1e-9, "{:?}", wallet);
+        // valued at the last price the round trip made money
+        assert!(wallet.assets["USDT"] + wallet.assets["BTC"] * 110.0 > 1000.0);
+    }
+}
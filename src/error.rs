@@ -7,4 +7,46 @@ pub enum Error {
     Unknown,       // to be removed
     Unimplemented, // to be removed
     Done,
+    // the request could not be sent or the response could not be read
+    Network(String),
+    // non 2xx response without an exchange error code
+    Http(u16, String),
+    // error code and message returned by the exchange
+    Exchange(i64, String),
+    // 429 / 418 responses, with the seconds to wait when the exchange tells
+    RateLimited(Option<u64>),
+    // the response is not what we expected
+    Parse(String),
+}
+
+// binance error codes
+pub const DISCONNECTED: i64 = -1001;
+pub const TOO_MANY_REQUESTS: i64 = -1003;
+pub const TIMEOUT: i64 = -1007;
+pub const INVALID_TIMESTAMP: i64 = -1021;
+pub const NEW_ORDER_REJECTED: i64 = -2010;
+pub const CANCEL_REJECTED: i64 = -2011;
+pub const NO_SUCH_ORDER: i64 = -2013;
+pub const BAD_API_KEY_FMT: i64 = -2014;
+pub const REJECTED_MBX_KEY: i64 = -2015;
+
+impl Error {
+    // worth sending the same request again, possibly after a while
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Network(_) | Error::RateLimited(_) => true,
+            Error::Http(status, _) => *status >= 500,
+            Error::Exchange(code, _) => [DISCONNECTED, TOO_MANY_REQUESTS, TIMEOUT, INVALID_TIMESTAMP].contains(code),
+            _ => false,
+        }
+    }
+
+    // the credentials are wrong, nothing is going to work
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::Http(status, _) => *status == 401,
+            Error::Exchange(code, _) => [BAD_API_KEY_FMT, REJECTED_MBX_KEY].contains(code),
+            _ => false,
+        }
+    }
 }
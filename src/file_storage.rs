@@ -0,0 +1,290 @@
+use super::candles::Candle;
+use super::error::Error;
+use super::storage::CandleStore;
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime};
+use log::debug;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+const TSTAMP_FMT: &str = "%Y-%m-%d %H:%M:%S";
+
+// loaded candles by (exchange, symbol)
+type CandleCache = HashMap<(String, String), Rc<Vec<Candle>>>;
+
+// 1m candles kept in {root}/{exchange}/{symbol}.csv, one per line, oldest first
+// tstamp,open,high,low,close,volume, the ohlc order of the sim csv and the exchanges
+// ranges known to be empty go in {root}/{exchange}/{symbol}.empty as from,to
+// files are loaded whole on first use, backtests don't need more than a laptop
+pub struct Candles {
+    root: PathBuf,
+    cache: RefCell<CandleCache>,
+}
+
+impl Candles {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: PathBuf::from(root),
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn path(&self, exchange: &str, symbol: &str) -> PathBuf {
+        self.root.join(exchange).join(format!("{}.csv", symbol))
+    }
+
+    fn load(&self, exchange: &str, symbol: &str) -> Rc<Vec<Candle>> {
+        let key = (String::from(exchange), String::from(symbol));
+        if let Some(cnds) = self.cache.borrow().get(&key) {
+            return cnds.clone();
+        }
+        let path = self.path(exchange, symbol);
+        let cnds: Vec<Candle> = match fs::read_to_string(&path) {
+            Ok(content) => content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|line| parse_line(line).unwrap_or_else(|| panic!("malformed line {} in {:?}", line, path)))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        debug!("file storage - loaded {} candles from {:?}", cnds.len(), path);
+        let cnds = Rc::new(cnds);
+        self.cache.borrow_mut().insert(key, cnds.clone());
+        cnds
+    }
+
+    fn read_empty_ranges(&self, exchange: &str, symbol: &str) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        let path = self.path(exchange, symbol).with_extension("empty");
+        let content = fs::read_to_string(&path).unwrap_or_default();
+        let mut ranges: Vec<(NaiveDateTime, NaiveDateTime)> = content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let mut fields = line.split(',').map(|field| NaiveDateTime::parse_from_str(field.trim(), TSTAMP_FMT));
+                match (fields.next(), fields.next()) {
+                    (Some(Ok(from)), Some(Ok(to))) => (from, to),
+                    _ => panic!("malformed line {} in {:?}", line, path),
+                }
+            })
+            .collect();
+        ranges.sort();
+        ranges
+    }
+
+    fn minutes(&self, exc: &str, sym: &str, start: &NaiveDateTime, end: &NaiveDateTime) -> Vec<Candle> {
+        let cnds = self.load(exc, sym);
+        let first = cnds.partition_point(|cnd| cnd.tstamp < *start);
+        let last = cnds.partition_point(|cnd| cnd.tstamp <= *end);
+        cnds[first..last.max(first)].to_vec()
+    }
+}
+
+#[async_trait(?Send)]
+impl CandleStore for Candles {
+    // candles already stored are kept, like the ON CONFLICT DO NOTHING of postgres
+    async fn store(&self, exchange: &str, symbol: &str, candles: &[Candle]) -> Result<u64, Error> {
+        let stored = self.load(exchange, symbol);
+        let last = stored.last().map(|cnd| cnd.tstamp);
+        let mut new_cnds: Vec<Candle> = candles
+            .iter()
+            .filter(|cnd| stored.binary_search_by_key(&cnd.tstamp, |stored| stored.tstamp).is_err())
+            .cloned()
+            .collect();
+        new_cnds.sort_by_key(|cnd| cnd.tstamp);
+        new_cnds.dedup_by_key(|cnd| cnd.tstamp);
+        if new_cnds.is_empty() {
+            return Ok(0);
+        }
+        let path = self.path(exchange, symbol);
+        fs::create_dir_all(path.parent().expect("no candle directory")).map_err(|e| Error::Unexpected(Box::new(e)))?;
+        let appending = last.is_none_or(|last| new_cnds[0].tstamp > last);
+        let all: Vec<Candle> = if appending {
+            // imports mostly add newer candles, no need to rewrite the whole file
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| Error::Unexpected(Box::new(e)))?;
+            file.write_all(to_lines(&new_cnds).as_bytes())
+                .map_err(|e| Error::Unexpected(Box::new(e)))?;
+            stored.iter().chain(new_cnds.iter()).cloned().collect()
+        } else {
+            let mut all: Vec<Candle> = stored.iter().chain(new_cnds.iter()).cloned().collect();
+            all.sort_by_key(|cnd| cnd.tstamp);
+            let tmp = path.with_extension("csv.tmp");
+            fs::write(&tmp, to_lines(&all)).map_err(|e| Error::Unexpected(Box::new(e)))?;
+            fs::rename(&tmp, &path).map_err(|e| Error::Unexpected(Box::new(e)))?;
+            all
+        };
+        self.cache
+            .borrow_mut()
+            .insert((String::from(exchange), String::from(symbol)), Rc::new(all));
+        Ok(new_cnds.len() as u64)
+    }
+
+    async fn check(&self, exc: &str, sym: &str, start: &NaiveDateTime, end: &NaiveDateTime) -> usize {
+        self.minutes(exc, sym, start, end).len()
+    }
+
+    async fn get_minutes(&self, exc: &str, sym: &str, start: &NaiveDateTime, end: &NaiveDateTime) -> Vec<Candle> {
+        let mut cnds = self.minutes(exc, sym, start, end);
+        cnds.retain(|cnd| cnd.tstamp < *end);
+        cnds
+    }
+
+    async fn find_lower(
+        &self,
+        exc: &str,
+        sym: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        price: f64,
+    ) -> Option<NaiveDateTime> {
+        self.minutes(exc, sym, start, end)
+            .iter()
+            .find(|cnd| cnd.low <= price)
+            .map(|cnd| cnd.tstamp)
+    }
+
+    async fn find_higher(
+        &self,
+        exc: &str,
+        sym: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        price: f64,
+    ) -> Option<NaiveDateTime> {
+        self.minutes(exc, sym, start, end)
+            .iter()
+            .find(|cnd| cnd.high >= price)
+            .map(|cnd| cnd.tstamp)
+    }
+
+    async fn last_tstamp(&self, exc: &str, sym: &str) -> Option<NaiveDateTime> {
+        self.load(exc, sym).last().map(|cnd| cnd.tstamp)
+    }
+
+    async fn mark_empty(&self, exc: &str, sym: &str, from: &NaiveDateTime, to: &NaiveDateTime) -> Result<(), Error> {
+        let path = self.path(exc, sym).with_extension("empty");
+        fs::create_dir_all(path.parent().expect("no candle directory")).map_err(|e| Error::Unexpected(Box::new(e)))?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| Error::Unexpected(Box::new(e)))?;
+        writeln!(file, "{},{}", from.format(TSTAMP_FMT), to.format(TSTAMP_FMT)).map_err(|e| Error::Unexpected(Box::new(e)))
+    }
+
+    async fn empty_ranges(
+        &self,
+        exc: &str,
+        sym: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+    ) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        self.read_empty_ranges(exc, sym)
+            .into_iter()
+            .filter(|(from, to)| to > start && from < end)
+            .collect()
+    }
+}
+
+fn to_lines(cnds: &[Candle]) -> String {
+    cnds.iter().fold(String::new(), |lines, cnd| {
+        lines
+            + &format!(
+                "{},{},{},{},{},{}\n",
+                cnd.tstamp.format(TSTAMP_FMT),
+                cnd.open,
+                cnd.high,
+                cnd.low,
+                cnd.close,
+                cnd.volume
+            )
+    })
+}
+
+fn parse_line(line: &str) -> Option<Candle> {
+    let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+    if fields.len() != 6 {
+        return None;
+    }
+    let (high, low): (f64, f64) = (fields[2].parse().ok()?, fields[3].parse().ok()?);
+    Some(Candle {
+        tstamp: NaiveDateTime::parse_from_str(fields[0], TSTAMP_FMT).ok()?,
+        tframe: Duration::minutes(1),
+        open: fields[1].parse().ok()?,
+        // files written before had low first, the high is never the lower one
+        high: high.max(low),
+        low: low.min(high),
+        close: fields[4].parse().ok()?,
+        volume: fields[5].parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn minute(min: u32, low: f64, high: f64) -> Candle {
+        Candle {
+            tstamp: NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(0, min, 0).unwrap(),
+            tframe: Duration::minutes(1),
+            open: low,
+            high,
+            low,
+            close: high,
+            volume: 2.5,
+        }
+    }
+
+    #[test]
+    fn lines_are_ohlc() {
+        let cnd = minute(3, 9.5, 11.25);
+        assert_eq!(to_lines(&[cnd]), "2021-01-01 00:03:00,9.5,11.25,9.5,11.25,2.5\n");
+        assert_eq!(parse_line("2021-01-01 00:03:00,9.5,11.25,9.5,11.25,2.5"), Some(cnd));
+        // older files had low before high
+        assert_eq!(parse_line("2021-01-01 00:03:00,9.5,9.5,11.25,11.25,2.5"), Some(cnd));
+        assert_eq!(parse_line("2021-01-01 00:03:00,9.5,9.5,11.25,11.25"), None);
+        assert_eq!(parse_line("2021-01-01,9.5,9.5,11.25,11.25,2.5"), None);
+    }
+
+    #[test]
+    fn stores_and_reads_back() {
+        let root = std::env::temp_dir().join(format!("candles-{}", rand::random::<u32>()));
+        let dir = String::from(root.to_str().unwrap());
+        let found = actix_rt::System::new("file-storage").block_on(async move {
+            let store = Candles::new(&dir);
+            let start = minute(0, 0.0, 0.0).tstamp;
+            let end = minute(10, 0.0, 0.0).tstamp;
+            let appended = store.store("exc", "SYM", &[minute(2, 9.0, 11.0), minute(3, 8.0, 10.0)]).await.unwrap();
+            // an older candle rewrites the file, the stored ones are kept
+            let inserted = store.store("exc", "SYM", &[minute(1, 10.0, 12.0), minute(2, 0.0, 0.0)]).await.unwrap();
+            store.mark_empty("exc", "SYM", &minute(4, 0.0, 0.0).tstamp, &end).await.unwrap();
+            // a fresh store reads the files again
+            let reread = Candles::new(&dir);
+            (
+                appended,
+                inserted,
+                reread.get_minutes("exc", "SYM", &start, &end).await,
+                reread.find_lower("exc", "SYM", &start, &end, 8.5).await,
+                reread.find_higher("exc", "SYM", &start, &end, 11.5).await,
+                reread.empty_ranges("exc", "SYM", &start, &end).await,
+                reread.last_tstamp("exc", "SYM").await,
+            )
+        });
+        std::fs::remove_dir_all(&root).unwrap();
+        let (appended, inserted, minutes, lower, higher, empty, last) = found;
+        assert_eq!((appended, inserted), (2, 1));
+        assert_eq!(minutes, vec![minute(1, 10.0, 12.0), minute(2, 9.0, 11.0), minute(3, 8.0, 10.0)]);
+        assert_eq!(lower, Some(minute(3, 0.0, 0.0).tstamp));
+        assert_eq!(higher, Some(minute(1, 0.0, 0.0).tstamp));
+        assert_eq!(empty, vec![(minute(4, 0.0, 0.0).tstamp, minute(10, 0.0, 0.0).tstamp)]);
+        assert_eq!(last, Some(minute(3, 0.0, 0.0).tstamp));
+    }
+}
@@ -1,29 +1,394 @@
+use super::candles::{find_gaps, subtract_ranges, Candle};
 use super::drivers::RestApi;
-use super::storage::Candles;
-use chrono::{Duration, NaiveDate, NaiveTime};
+use super::storage::CandleStore;
+use super::utils;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 const STEP: i64 = 500;
-pub async fn import(driver: &dyn RestApi, storage: &Candles, exchange: &str, sym: &str, start: &NaiveDate, end: &NaiveDate) -> u64 {
-    // TODO: check if candles already exists
-    println!("importing candles for {} days", end.signed_duration_since(*start).num_days());
+pub async fn import(
+    driver: &dyn RestApi,
+    storage: &dyn CandleStore,
+    exchange: &str,
+    sym: &str,
+    start: &NaiveDateTime,
+    end: &NaiveDateTime,
+) -> u64 {
+    println!("importing {} candles from {} to {}", sym, start, end);
+    let mut bar = progress::Bar::new();
+    bar.set_job_title(sym);
     let mut total: u64 = 0;
-    let mut tstamp = start.and_time(NaiveTime::default());
-    let end_t = end.and_time(NaiveTime::default());
-    while tstamp < end_t {
+    let mut tstamp = *start;
+    while tstamp < *end {
         let tstamp_end = tstamp + Duration::minutes(STEP);
         let check = storage.check(exchange, sym, &tstamp, &tstamp_end).await;
-        if check < STEP as usize {
-            println!("found {} candles in storage, expecting {}", check, STEP);
-            let candles = driver.get_candles(sym, None, Some(&tstamp), None).await;
-            if candles.is_empty() {
-                panic!("not getting any candles");
-            }
-            total += storage.store(exchange, sym, &candles).await.expect("in storing data to DB");
-            tstamp = candles.last().expect("last not present").tstamp;
+        // minutes known to be empty are as good as stored
+        let empty: i64 = storage
+            .empty_ranges(exchange, sym, &tstamp, &tstamp_end)
+            .await
+            .iter()
+            .map(|(from, to)| (*to.min(&tstamp_end) - *from.max(&tstamp)).num_minutes())
+            .sum();
+        if check + (empty as usize) < STEP as usize {
+            let (stored, reached) = fetch_from(driver, storage, exchange, sym, &tstamp, end).await;
+            total += stored;
+            tstamp = reached;
         } else {
             tstamp = tstamp_end;
         }
-        println!("{}", tstamp);
+        let done = (tstamp - *start).num_minutes() * 100 / (*end - *start).num_minutes().max(1);
+        bar.reach_percent(done.min(100) as i32);
+    }
+    bar.jobs_done();
+    total
+}
+
+// missing minutes within [start, end), the ranges known to be empty apart
+pub async fn missing_ranges(
+    storage: &dyn CandleStore,
+    exchange: &str,
+    sym: &str,
+    start: &NaiveDateTime,
+    end: &NaiveDateTime,
+) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let minutes = storage.get_minutes(exchange, sym, start, end).await;
+    let empty = storage.empty_ranges(exchange, sym, start, end).await;
+    subtract_ranges(&find_gaps(&minutes, start, end), &empty)
+}
+
+// fetches the missing ranges again, what the exchange doesn't have is marked as empty
+pub async fn repair(
+    driver: &dyn RestApi,
+    storage: &dyn CandleStore,
+    exchange: &str,
+    sym: &str,
+    gaps: &[(NaiveDateTime, NaiveDateTime)],
+) -> u64 {
+    let mut total: u64 = 0;
+    for (from, to) in gaps {
+        let mut tstamp = *from;
+        while tstamp < *to {
+            let (stored, reached) = fetch_from(driver, storage, exchange, sym, &tstamp, to).await;
+            total += stored;
+            tstamp = reached;
+        }
     }
     total
 }
+
+// stores the candles the exchange returns from `from` on, up to `to`
+// minutes with no candle between two candles are marked as empty, i.e. exchange maintenance
+// returns the candles stored and where to fetch from next
+async fn fetch_from(
+    driver: &dyn RestApi,
+    storage: &dyn CandleStore,
+    exchange: &str,
+    sym: &str,
+    from: &NaiveDateTime,
+    to: &NaiveDateTime,
+) -> (u64, NaiveDateTime) {
+    let candles = driver
+        .get_candles(sym, None, Some(from), None)
+        .await
+        .expect("in asking candles to the exchange");
+    let candles: Vec<Candle> = candles
+        .into_iter()
+        .filter(|cnd| cnd.tstamp >= *from && cnd.tstamp < *to)
+        .collect();
+    // no candles at all, the exchange has nothing after from, nothing to tell about the minutes
+    let (first, last) = match (candles.first(), candles.last()) {
+        (Some(first), Some(last)) => (first.tstamp, last.tstamp),
+        _ => return (0, *to),
+    };
+    let reached = last + Duration::minutes(1);
+    // the minutes before the first candle are only bracketed when the one before from is stored
+    let before = *from - Duration::minutes(1);
+    let bracketed_from = if storage.check(exchange, sym, &before, &before).await > 0 {
+        *from
+    } else {
+        first
+    };
+    for (gap_from, gap_to) in find_gaps(&candles, &bracketed_from, &reached) {
+        println!("no candles from {} to {}, marking them as empty", gap_from, gap_to);
+        storage
+            .mark_empty(exchange, sym, &gap_from, &gap_to)
+            .await
+            .expect("in marking empty candles");
+    }
+    let stored = storage.store(exchange, sym, &candles).await.expect("in storing data to DB");
+    (stored, reached)
+}
+
+// binance public data dumps, a kline file or a directory of them
+// i.e. BTCUSDT-1m-2021-01.zip (monthly) or BTCUSDT-1m-2021-01-01.csv (daily)
+// storing is idempotent, files can be imported again
+pub async fn import_file(storage: &dyn CandleStore, exchange: &str, path: &Path, symbol: Option<&str>) -> u64 {
+    let mut files: Vec<PathBuf> = if path.is_dir() {
+        std::fs::read_dir(path)
+            .expect("in listing the dump directory")
+            .map(|entry| entry.expect("in reading the dump directory").path())
+            .filter(|file| file.extension().is_some_and(|ext| ext == "csv" || ext == "zip"))
+            .collect()
+    } else {
+        vec![path.to_path_buf()]
+    };
+    files.sort();
+    let mut total: u64 = 0;
+    // first and last minute imported per symbol, to look for gaps
+    let mut ranges: HashMap<String, (NaiveDateTime, NaiveDateTime)> = HashMap::new();
+    for file in files {
+        let sym = match dump_symbol(&file) {
+            Ok(sym) => sym,
+            Err(reason) => {
+                println!("skipping {}: {}", file.display(), reason);
+                continue;
+            }
+        };
+        if symbol.is_some_and(|symbol| symbol != sym) {
+            println!("skipping {}: not a {} file", file.display(), symbol.unwrap_or_default());
+            continue;
+        }
+        let candles: Vec<Candle> = read_dump(&file)
+            .lines()
+            .filter_map(|line| parse_dump_line(line).unwrap_or_else(|| panic!("malformed line {} in {}", line, file.display())))
+            .collect();
+        if let (Some(first), Some(last)) = (candles.first(), candles.last()) {
+            let range = ranges.entry(sym.clone()).or_insert((first.tstamp, last.tstamp));
+            range.0 = range.0.min(first.tstamp);
+            range.1 = range.1.max(last.tstamp);
+        }
+        let stored = storage.store(exchange, &sym, &candles).await.expect("in storing data to DB");
+        println!("{}: {} candles, {} new", file.display(), candles.len(), stored);
+        total += stored;
+    }
+    for (sym, (start, end)) in ranges {
+        let gaps = missing_ranges(storage, exchange, &sym, &start, &(end + Duration::minutes(1))).await;
+        println!("{}: {} gaps between {} and {}", sym, gaps.len(), start, end);
+        for (from, to) in gaps {
+            println!("  missing {} -> {} ({} minutes)", from, to, (to - from).num_minutes());
+        }
+    }
+    total
+}
+
+// SYMBOL-1m-YYYY-MM[-DD].csv|zip, only 1m klines are stored
+fn dump_symbol(file: &Path) -> Result<String, String> {
+    let stem = file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| String::from("not a file name"))?;
+    let parts: Vec<&str> = stem.split('-').collect();
+    if parts.len() != 4 && parts.len() != 5 {
+        return Err(String::from("not a binance kline dump name"));
+    }
+    if parts[1] != "1m" {
+        return Err(format!("interval {} is not 1m", parts[1]));
+    }
+    if parts[0].is_empty() || !parts[0].chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+        return Err(format!("{} is not a symbol", parts[0]));
+    }
+    if parts[2..].iter().any(|part| part.parse::<u32>().is_err()) {
+        return Err(String::from("no date in the name"));
+    }
+    Ok(String::from(parts[0]))
+}
+
+// the zip archives hold a single csv file named like the archive
+fn read_dump(file: &Path) -> String {
+    let mut content = String::new();
+    if file.extension().is_some_and(|ext| ext == "zip") {
+        let mut archive =
+            zip::ZipArchive::new(File::open(file).expect("in opening the dump")).expect("in reading the zip archive");
+        archive
+            .by_index(0)
+            .expect("empty zip archive")
+            .read_to_string(&mut content)
+            .expect("in unzipping the dump");
+    } else {
+        File::open(file)
+            .expect("in opening the dump")
+            .read_to_string(&mut content)
+            .expect("in reading the dump");
+    }
+    content
+}
+
+// open_time,open,high,low,close,volume,close_time,...
+// recent files may have a header and open times in microseconds
+fn parse_dump_line(line: &str) -> Option<Option<Candle>> {
+    let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+    if line.trim().is_empty() || fields[0].parse::<i64>().is_err() {
+        return Some(None);
+    }
+    if fields.len() < 6 {
+        return None;
+    }
+    let open_time = fields[0].parse::<i64>().ok()?;
+    let millis = if open_time >= 10_000_000_000_000 { open_time / 1000 } else { open_time };
+    Some(Some(Candle {
+        tstamp: utils::from_timestamp(millis / 1000, 0)?,
+        tframe: Duration::minutes(1),
+        open: fields[1].parse().ok()?,
+        high: fields[2].parse().ok()?,
+        low: fields[3].parse().ok()?,
+        close: fields[4].parse().ok()?,
+        volume: fields[5].parse().ok()?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::file_storage;
+    use crate::orders::{Order, OrderStatus};
+    use crate::symbol::Symbol;
+    use crate::wallets::SpotWallet;
+    use async_trait::async_trait;
+
+    fn minute(min: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap() + Duration::minutes(min)
+    }
+
+    fn candle(min: i64) -> Candle {
+        Candle {
+            tstamp: minute(min),
+            tframe: Duration::minutes(1),
+            open: 1.0,
+            high: 1.0,
+            low: 1.0,
+            close: 1.0,
+            volume: 1.0,
+        }
+    }
+
+    // answers like binance, the candles from the start time on
+    struct Exchange {
+        candles: Vec<Candle>,
+    }
+
+    #[async_trait(?Send)]
+    impl RestApi for Exchange {
+        async fn get_candles(
+            &self,
+            _sym: &str,
+            _interval: Option<&Duration>,
+            start: Option<&NaiveDateTime>,
+            limit: Option<usize>,
+        ) -> Result<Vec<Candle>, Error> {
+            let start = *start.unwrap();
+            Ok(self
+                .candles
+                .iter()
+                .filter(|cnd| cnd.tstamp >= start)
+                .take(limit.unwrap_or(3))
+                .cloned()
+                .collect())
+        }
+        async fn get_symbol_info(&self, _sym: &str) -> Result<Symbol, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn get_wallet(&self) -> Result<SpotWallet, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn refresh_ws_token(&self, _old_token: Option<String>) -> Result<String, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn send_order(&self, _order: Order) -> Result<OrderStatus, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn send_oco_order(&self, _take_profit: Order, _stop_loss: Order) -> Result<OrderStatus, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn cancel_order(&self, _order: Order) -> Result<OrderStatus, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn order_exists(&self, _order: Order) -> Result<bool, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn get_outstanding_orders(&self, _symbol: &str) -> Result<Vec<Order>, Error> {
+            Err(Error::Unimplemented)
+        }
+    }
+
+    #[test]
+    fn dump_names_give_the_symbol() {
+        assert_eq!(dump_symbol(Path::new("dumps/BTCUSDT-1m-2021-01.zip")), Ok(String::from("BTCUSDT")));
+        assert_eq!(dump_symbol(Path::new("1INCHBTC-1m-2021-01-31.csv")), Ok(String::from("1INCHBTC")));
+        assert!(dump_symbol(Path::new("BTCUSDT-5m-2021-01.zip")).is_err());
+        assert!(dump_symbol(Path::new("BTCUSDT-1m-2021-Jan.zip")).is_err());
+        assert!(dump_symbol(Path::new("btcusdt-1m-2021-01.zip")).is_err());
+        assert!(dump_symbol(Path::new("BTCUSDT-trades-2021-01.zip")).is_err());
+        assert!(dump_symbol(Path::new("notes.csv")).is_err());
+    }
+
+    #[test]
+    fn dump_lines_in_millis_and_micros() {
+        let millis = "1609459200000,28923.63,28961.66,28913.12,28961.66,27.457032,1609459259999,794382.01,1292,16.777195,485390.03,0";
+        let cnd = parse_dump_line(millis).unwrap().unwrap();
+        assert_eq!(cnd.tstamp, minute(0));
+        assert_eq!((cnd.open, cnd.high, cnd.low, cnd.close), (28923.63, 28961.66, 28913.12, 28961.66));
+        assert_eq!(cnd.volume, 27.457032);
+        let micros = "1609459200000000,28923.63,28961.66,28913.12,28961.66,27.457032,1609459259999999,794382.01,1292,16.777195,485390.03,0";
+        assert_eq!(parse_dump_line(micros).unwrap(), Some(cnd));
+        // headers and blank lines are skipped, broken lines are errors
+        let header = "open_time,open,high,low,close,volume,close_time,quote_volume,count,taker_buy_volume,taker_buy_quote_volume,ignore";
+        assert_eq!(parse_dump_line(header), Some(None));
+        assert_eq!(parse_dump_line(""), Some(None));
+        assert_eq!(parse_dump_line("1609459200000,28923.63,28961.66"), None);
+        assert_eq!(parse_dump_line("1609459200000,28923.63,28961.66,x,28961.66,27.4"), None);
+    }
+
+    type Fetched = (Vec<(u64, NaiveDateTime)>, Vec<(NaiveDateTime, NaiveDateTime)>);
+
+    // fetches pages of 3 candles from `from` to `to`, with the candles before `from` already stored
+    fn fetch_all(candles: &[i64], stored: &[i64], from: i64, to: i64) -> Fetched {
+        let root = std::env::temp_dir().join(format!("import-{}", rand::random::<u32>()));
+        let dir = String::from(root.to_str().unwrap());
+        let exchange = Exchange {
+            candles: candles.iter().map(|min| candle(*min)).collect(),
+        };
+        let stored: Vec<Candle> = stored.iter().map(|min| candle(*min)).collect();
+        let fetched = actix_rt::System::new("import").block_on(async move {
+            let storage = file_storage::Candles::new(&dir);
+            storage.store("exc", "SYM", &stored).await.unwrap();
+            let (from, to) = (minute(from), minute(to));
+            let mut pages = Vec::new();
+            let mut tstamp = from;
+            while tstamp < to {
+                let page = fetch_from(&exchange, &storage, "exc", "SYM", &tstamp, &to).await;
+                tstamp = page.1;
+                pages.push(page);
+            }
+            (pages, storage.empty_ranges("exc", "SYM", &minute(-100), &minute(100)).await)
+        });
+        let _ = std::fs::remove_dir_all(&root);
+        fetched
+    }
+
+    #[test]
+    fn only_bracketed_minutes_are_empty() {
+        let (pages, empty) = fetch_all(&[0, 1, 5, 6, 7], &[], 0, 60);
+        assert_eq!(pages, vec![(3, minute(6)), (2, minute(8)), (0, minute(60))]);
+        // nothing is known after the last candle, the exchange may just not have it yet
+        assert_eq!(empty, vec![(minute(2), minute(5))]);
+    }
+
+    #[test]
+    fn empty_pages_mark_nothing() {
+        let (pages, empty) = fetch_all(&[], &[], 0, 60);
+        assert_eq!(pages, vec![(0, minute(60))]);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn leading_minutes_need_a_stored_candle_before() {
+        let (_, empty) = fetch_all(&[3, 4], &[], 1, 10);
+        assert!(empty.is_empty());
+        let (_, empty) = fetch_all(&[3, 4], &[0], 1, 10);
+        assert_eq!(empty, vec![(minute(1), minute(3))]);
+    }
+}
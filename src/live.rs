@@ -1,22 +1,40 @@
 use crate::candles::Candle;
-use crate::configuration::{ExchangeSettings, Settings, StrategySettings};
+use crate::configuration::{instance_ids, ExchangeSettings, Settings, StrategySettings};
 use crate::drivers::{create_live_driver, create_rest_client, LiveEvent, LiveFeed, RestApi, Tick};
-use crate::orders::Order;
-use crate::storage;
+use crate::error::Error;
+use crate::orders::{bracket_orders, order_instance, Order, OrderId, OrderStatus, Type};
+use crate::paper::PaperBroker;
+use crate::storage::TransactionStore;
 use crate::strategies;
 use crate::strategies::{Action, SpotSinglePairStrategy};
+use crate::wallets::SpotWallet;
 use chrono::Utc;
 use log::{debug, error, info, warn};
 use std::collections::{HashMap, VecDeque};
+use actix_rt::signal::unix::{signal, SignalKind};
+use futures_util::future::{select, Either};
+use std::future::Future;
 use std::iter::Extend;
 
+// in paper mode orders never reach the exchange,
+// they are filled locally against the live candles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Live,
+    Paper,
+}
+
+// returns the wallet as last known when the loop stopped
 pub async fn run_live(
     strategies_settings: Vec<StrategySettings>,
-    mut tx_storage: storage::Transactions,
+    tx_storage: &mut dyn TransactionStore,
     exchange_settings: ExchangeSettings,
-) {
+    mode: Mode,
+) -> SpotWallet {
     if strategies_settings.is_empty() {
-        return;
+        return SpotWallet {
+            assets: HashMap::new(),
+        };
     }
     let exchange = strategies_settings.first().unwrap().exchange.clone();
     if strategies_settings.iter().any(|st| st.exchange != exchange) {
@@ -25,12 +43,29 @@ pub async fn run_live(
     // init rest exchange api
     let rest = create_rest_client(&exchange, &exchange_settings).expect("in create_rest_client");
     // init strategies
-    let mut strategies: HashMap<String, Box<dyn SpotSinglePairStrategy>> = HashMap::new();
-    let mut buffers: HashMap<String, VecDeque<Candle>> = HashMap::new();
-    let mut orders: HashMap<String, Vec<Order>> = HashMap::new();
+    let mut strategies: HashMap<StrategyKey, Box<dyn SpotSinglePairStrategy>> = HashMap::new();
+    let mut buffers: HashMap<StrategyKey, VecDeque<Candle>> = HashMap::new();
+    let mut orders: HashMap<StrategyKey, Vec<Order>> = HashMap::new();
+    // sent orders with take profit / stop loss legs, waiting to be filled
+    let mut brackets: HashMap<OrderId, Order> = HashMap::new();
+    // leg id to the other leg, for the oco pairs the exchange doesn't cancel itself
+    let mut oco_legs: HashMap<OrderId, Order> = HashMap::new();
     let mut ticks: Vec<Tick> = Vec::new();
-    for st in strategies_settings {
-        let sym_info = rest.get_symbol_info(&st.symbol).await.expect("no symbol info");
+    // settings of each strategy, a snapshot is only restored with the same ones
+    let mut fingerprints: HashMap<StrategyKey, String> = HashMap::new();
+    // symbols traded by more than one strategy
+    let shared: Vec<String> = strategies_settings
+        .iter()
+        .filter(|st| strategies_settings.iter().filter(|other| other.symbol == st.symbol).count() > 1)
+        .map(|st| st.symbol.clone())
+        .collect();
+    let ids = instance_ids(&strategies_settings).unwrap_or_else(|err| panic!("{} - {}", exchange, err));
+    for (st, id) in strategies_settings.into_iter().zip(ids) {
+        let fingerprint = st.fingerprint();
+        let key: StrategyKey = (st.symbol.clone(), st.time_frame, id);
+        let sym_info = retry("symbol info", || rest.get_symbol_info(&st.symbol))
+            .await
+            .expect("no symbol info");
         let mut strategy =
             strategies::create(&st.name, st.exchange, sym_info, st.time_frame, st.settings).expect("strategies::create");
         let sym = strategy.symbol().symbol.clone();
@@ -40,117 +75,648 @@ pub async fn run_live(
         if init_size == 0 {
             info!("{}: no init needed ", strategy.name());
         } else {
-            let mut init_cnds = rest.get_candles(&sym, Some(&t_frame), None, Some(init_size)).await;
+            let mut init_cnds = retry("init candles", || rest.get_candles(&sym, Some(&t_frame), None, Some(init_size)))
+                .await
+                .expect("in asking for init candles");
             init_cnds.sort_by_key(|cnd| cnd.tstamp);
             strategy.initialize(init_cnds.as_slice());
             info!("{}: init needed {} - {}", strategy.name(), init_size, init_cnds.len());
         }
         // runtime prep
         let hist_size = strategy.get_candles_history_size();
-        ticks.push(Tick {
-            sym: sym.clone(),
-            interval: t_frame,
-        });
+        // strategies on the same symbol and time frame share the stream
+        if !ticks.iter().any(|tick| tick.sym == sym && tick.interval == t_frame) {
+            ticks.push(Tick {
+                sym: sym.clone(),
+                interval: t_frame,
+            });
+        }
 
-        let mut cnds = rest.get_candles(&sym, Some(&t_frame), None, Some(hist_size)).await;
+        let mut cnds = retry("history candles", || rest.get_candles(&sym, Some(&t_frame), None, Some(hist_size)))
+            .await
+            .expect("in asking for history candles");
         cnds.sort_by_key(|cnd| std::cmp::Reverse(cnd.tstamp));
         let buffer = cnds.drain(0..hist_size).collect::<VecDeque<_>>();
-        info!("strategy {} on {} at {} started", strategy.name(), sym.clone(), t_frame);
-        buffers.insert(sym.clone(), buffer);
-        let outstanding_orders = rest.get_outstanding_orders(&sym).await;
-        info!("found {} outstanding orders for {}", outstanding_orders.len(), sym);
-        orders.insert(sym.clone(), outstanding_orders);
-        strategies.insert(sym, strategy);
+        info!("strategy {} on {} at {} started as {}", strategy.name(), sym.clone(), t_frame, key.2);
+        buffers.insert(key.clone(), buffer);
+        let (outstanding_orders, others): (Vec<Order>, Vec<Order>) = match mode {
+            Mode::Live => retry("outstanding orders", || rest.get_outstanding_orders(&sym))
+                .await
+                .expect("in asking for outstanding orders")
+                .into_iter()
+                .partition(|ord| ord.owner == Some(key.2) || (is_legacy(ord) && !shared.contains(&sym))),
+            Mode::Paper => (Vec::new(), Vec::new()),
+        };
+        info!("found {} outstanding orders for {}", outstanding_orders.len(), strategy.name());
+        for ord in others.iter().filter(|ord| ord.owner.is_none()) {
+            info!(target: FOREIGN, "{} - outstanding order {:?}", sym, ord);
+        }
+        // state saved before a restart
+        let snapshot_name = snapshot_name(strategy.as_ref(), &key);
+        match tx_storage.load_snapshot(&snapshot_name, &fingerprint).await {
+            Ok(Some(state)) => strategy.restore(state),
+            Ok(None) => info!("{}: no snapshot to restore", snapshot_name),
+            Err(err) => error!("{}: could not load snapshot {:?}", snapshot_name, err),
+        }
+        // positions opened before a restart, by the instance in their ids
+        // older ids have none, those positions only go to a strategy alone on its symbol
+        let mut open = tx_storage
+            .open_positions(strategy.exchange(), strategy.symbol())
+            .await
+            .expect("in asking for open positions");
+        let alone = !shared.contains(&sym);
+        open.retain(|tx| match order_instance(tx.order.id) {
+            Some(instance) => instance == key.2,
+            None => alone,
+        });
+        info!("found {} open positions for {}", open.len(), strategy.name());
+        strategy.restore_position(&open);
+        orders.insert(key.clone(), outstanding_orders);
+        fingerprints.insert(key.clone(), fingerprint);
+        strategies.insert(key, strategy);
+    }
+    // pairs sent before a restart
+    if !rest.native_oco() {
+        for (first, second) in orders.values().flat_map(|ords| oco_pairs(ords)) {
+            oco_legs.insert(first.id, second.clone());
+            oco_legs.insert(second.id, first);
+        }
     }
     // init wallet
-    let mut wallet = rest.get_wallet().await.expect("in asking for initial wallet");
+    let mut wallet = retry("wallet", || rest.get_wallet())
+        .await
+        .expect("in asking for initial wallet");
+    // paper trading starts from a copy of the account balances
+    let mut broker = match mode {
+        Mode::Live => None,
+        Mode::Paper => Some(PaperBroker::new(
+            exchange_settings.backtest.clone(),
+            SpotWallet {
+                assets: wallet.assets.clone(),
+            },
+        )),
+    };
 
     // init live feed client
-    let listen_key = rest.refresh_ws_token(None).await;
+    let listen_key = retry("listen token", || rest.refresh_ws_token(None))
+        .await
+        .expect("in asking for a listen token");
     let mut feed = create_live_driver(&exchange, listen_key, ticks)
         .await
         .expect("could not create exchange drivers");
 
     // main loop
+    let mut shutdown = Box::pin(shutdown_signal());
+    let mut stopping = false;
+    let mut last_snapshot = Utc::now();
     loop {
-        let msg = feed.next().await;
-        let action = match msg {
-            LiveEvent::Candle(sym, candle) => {
-                if let Some(st) = strategies.get_mut(&sym) {
-                    let buf = buffers.get_mut(&sym).expect("symbol not found in buffers");
+        if Utc::now() - last_snapshot >= chrono::Duration::minutes(SNAPSHOT_MINUTES) {
+            save_snapshots(tx_storage, &strategies, &fingerprints).await;
+            last_snapshot = Utc::now();
+        }
+        let (msg, from_feed) = match broker.as_mut().and_then(|broker| broker.next_event()) {
+            Some(msg) => (msg, false),
+            // the paper fills still queued are stored before leaving
+            None if stopping => break,
+            None => match select(feed.next(), &mut shutdown).await {
+                Either::Left((msg, _)) => (msg, true),
+                Either::Right(_) => (LiveEvent::Shutdown, false),
+            },
+        };
+        if let (Some(broker), true) = (broker.as_mut(), from_feed) {
+            match msg {
+                LiveEvent::Candle(sym, interval, candle) => {
+                    broker.on_candle(sym, interval, candle);
+                    continue;
+                }
+                LiveEvent::Transaction(_)
+                | LiveEvent::NewOrder(_)
+                | LiveEvent::OrderCanceled(_, _)
+                | LiveEvent::BalanceUpdate(_)
+                | LiveEvent::AssetUpdate { .. } => {
+                    debug!("paper - ignoring account event at {}", Utc::now());
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        let actions: Vec<(StrategyKey, Action)> = match msg {
+            LiveEvent::Candle(sym, interval, candle) => {
+                let keys: Vec<StrategyKey> =
+                    strategies.keys().filter(|key| key.0 == sym && key.1 == interval).cloned().collect();
+                if keys.is_empty() {
+                    debug!("ignoring new candle event at {} {} {}", Utc::now(), sym, interval);
+                }
+                let mut actions = Vec::new();
+                for key in keys {
+                    let st = strategies.get_mut(&key).expect("strategy not found");
+                    let buf = buffers.get_mut(&key).expect("strategy not found in buffers");
                     if candle.tstamp == buf.front().unwrap().tstamp {
                         error!("{} - repeated candle {:?} {:?}", sym, candle, buf.front().unwrap());
                         buf.pop_front();
                     }
-                    debug!("{} - new candle event at {}", sym, Utc::now());
+                    debug!("{} - new candle event at {}", st.name(), Utc::now());
                     buf.pop_back();
                     buf.push_front(candle);
-                    let ords = orders.get(&sym).expect("symbol not found in orders").as_slice();
-                    st.on_new_candle(&wallet, ords, buf.make_contiguous())
-                } else {
-                    debug!("ignoring new candle event at {} {}", Utc::now(), sym);
-                    Action::None
+                    let ords = orders.get(&key).expect("strategy not found in orders").as_slice();
+                    actions.push((key.clone(), st.on_new_candle(&wallet, ords, buf.make_contiguous())));
                 }
+                actions
             }
-            LiveEvent::Transaction(tx) => {
-                if let Some(st) = strategies.get_mut(&tx.symbol) {
+            LiveEvent::Transaction(tx) => match owner(strategies.keys(), &tx.symbol, &tx.order) {
+                Some(key) => {
                     debug!("new transaction event at {}\n\t {:?}", Utc::now(), tx);
-                    let ords = orders.get_mut(&tx.symbol).expect("symbol not found in orders");
+                    let st = strategies.get_mut(&key).expect("strategy not found");
+                    let ords = orders.get_mut(&key).expect("strategy not found in orders");
                     ords.retain(|ord| ord.id != tx.order.id);
                     tx_storage.store(st.exchange(), &tx).await.expect("in storing new transaction");
-                    st.on_new_transaction(ords.as_slice(), &tx)
-                } else {
-                    debug!("ignoring new transaction event at {} {}", Utc::now(), tx.symbol);
-                    Action::None
+                    // one leg of a pair the exchange doesn't cancel itself got filled
+                    if let Some(sibling) = oco_legs.remove(&tx.order.id) {
+                        oco_legs.remove(&sibling.id);
+                        info!("{} - canceling {}, the other leg of {}", tx.symbol, sibling.id, tx.order.id);
+                        match retry("cancel oco leg", || rest.cancel_order(sibling.clone())).await {
+                            Ok(status) => debug!("oco leg canceled {:?}", status),
+                            Err(err) if must_halt("cancel oco leg", &err) => return wallet,
+                            Err(_) => {}
+                        }
+                    }
+                    if let Some(parent) = brackets.remove(&tx.order.id) {
+                        let mut legs = bracket_orders(&parent, &tx);
+                        let status = if legs.len() == 2 {
+                            let stop_loss = legs.pop().unwrap();
+                            let take_profit = legs.pop().unwrap();
+                            match broker.as_mut() {
+                                Some(broker) => Ok(broker.send_oco_order(take_profit, stop_loss)),
+                                None => {
+                                    let legs = vec![take_profit.clone(), stop_loss.clone()];
+                                    let status = send_once("send bracket legs", rest.as_ref(), legs).await;
+                                    if status.is_ok() && !rest.native_oco() {
+                                        oco_legs.insert(take_profit.id, stop_loss.clone());
+                                        oco_legs.insert(stop_loss.id, take_profit);
+                                    }
+                                    status
+                                }
+                            }
+                        } else {
+                            let leg = legs.pop().expect("no bracket legs");
+                            match broker.as_mut() {
+                                Some(broker) => Ok(broker.send_order(leg)),
+                                None => send_once("send bracket leg", rest.as_ref(), vec![leg]).await,
+                            }
+                        };
+                        match status {
+                            Ok(status) => debug!("bracket legs sent {:?}", status),
+                            Err(err) if must_halt("send bracket legs", &err) => return wallet,
+                            Err(_) => {}
+                        }
+                    }
+                    let action = st.on_new_transaction(ords.as_slice(), &tx);
+                    vec![(key, action)]
                 }
-            }
+                None => {
+                    info!(target: FOREIGN, "{} - transaction {:?}", tx.symbol, tx);
+                    Vec::new()
+                }
+            },
             LiveEvent::NewOrder(order) => {
-                if strategies.contains_key(&order.symbol.symbol) {
-                    debug!("new order event at {}\n\t {:?}", Utc::now(), order);
-                    let ords = orders.get_mut(&order.symbol.symbol).expect("symbol not found in orders");
-                    ords.push(order);
-                    Action::None
-                } else {
-                    debug!("ignoring new order event at {} {}", Utc::now(), order.symbol.symbol);
-                    Action::None
+                match owner(strategies.keys(), &order.symbol.symbol, &order) {
+                    Some(key) => {
+                        debug!("new order event at {}\n\t {:?}", Utc::now(), order);
+                        let ords = orders.get_mut(&key).expect("strategy not found in orders");
+                        ords.push(order);
+                    }
+                    None => info!(target: FOREIGN, "{} - new order {:?}", order.symbol.symbol, order),
                 }
+                Vec::new()
+            }
+            LiveEvent::OrderCanceled(sym, id) => {
+                debug!("order canceled event at {} {} {}", Utc::now(), sym, id);
+                // the event only has the id, the order is looked up among the outstanding ones
+                let owned = orders
+                    .iter_mut()
+                    .filter(|(key, _)| key.0 == sym)
+                    .find(|(_, ords)| ords.iter().any(|ord| ord.id == id));
+                match owned {
+                    Some((_, ords)) => ords.retain(|ord| ord.id != id),
+                    None => info!(target: FOREIGN, "{} - order {} canceled", sym, id),
+                }
+                brackets.remove(&id);
+                if let Some(sibling) = oco_legs.remove(&id) {
+                    oco_legs.remove(&sibling.id);
+                }
+                Vec::new()
             }
             LiveEvent::BalanceUpdate(spot_wallet) => {
                 debug!("new balance event at {}", Utc::now());
-                wallet.assets.extend(spot_wallet.assets.into_iter());
-                Action::None
+                wallet.assets.extend(spot_wallet.assets);
+                Vec::new()
             }
             LiveEvent::AssetUpdate { asset, delta } => {
                 debug!("received asset change: {} {}", asset, delta);
-                Action::None
+                Vec::new()
             }
             LiveEvent::TokenRefreshRequired => {
                 debug!("{} - Token refresh required", exchange);
                 let token = feed.token();
-                rest.refresh_ws_token(Some(token)).await;
-                Action::None
+                if let Err(err) = retry("token refresh", || rest.refresh_ws_token(Some(token.clone()))).await {
+                    // the old token is lost, a new one comes with a new connection
+                    warn!("{} - token refresh failed with {:?}, reconnecting", exchange, err);
+                    match retry("listen token", || rest.refresh_ws_token(None)).await {
+                        Ok(new_token) => feed.reconnect(new_token).await,
+                        Err(err) => {
+                            error!("{} - no listen token {:?}, halting", exchange, err);
+                            return wallet;
+                        }
+                    }
+                }
+                Vec::new()
             }
             LiveEvent::ReconnectionRequired => {
                 debug!("{} - ReconnectionRequired", exchange);
-                let new_token = rest.refresh_ws_token(None).await;
-                feed.reconnect(new_token).await;
-                Action::None
+                match retry("listen token", || rest.refresh_ws_token(None)).await {
+                    Ok(new_token) => feed.reconnect(new_token).await,
+                    Err(err) => {
+                        error!("{} - no listen token {:?}, halting", exchange, err);
+                        return wallet;
+                    }
+                }
+                Vec::new()
+            }
+            LiveEvent::Generic(msg) => {
+                warn!("{} - {}", exchange, msg);
+                Vec::new()
+            }
+            LiveEvent::Shutdown => {
+                info!("{} - shutting down", exchange);
+                stopping = true;
+                Vec::new()
             }
             _ => {
                 warn!("unknown  event");
-                Action::None
+                Vec::new()
             }
         };
-        match action {
-            Action::NewOrder(order) => {
-                let status = rest.send_order(order).await;
-                debug!("new order sent {:?}", status);
+        for (key, action) in actions {
+            if stopping && !matches!(action, Action::None) {
+                info!("{} - not acting on {:?} while shutting down", exchange, action);
+                continue;
             }
-            Action::CancelOrder(symbol, id) => {
-                let status = rest.cancel_order(symbol, id).await;
-                debug!("new cancel order sent {:?}", status);
+            match action {
+                Action::NewOrder(mut order) => {
+                    order.set_owner(key.2);
+                    if order.take_profit.is_some() || order.stop_loss.is_some() {
+                        brackets.insert(order.id, order.clone());
+                    }
+                    let id = order.id;
+                    let status = match broker.as_mut() {
+                        Some(broker) => Ok(broker.send_order(order)),
+                        None => send_once("send order", rest.as_ref(), vec![order]).await,
+                    };
+                    match status {
+                        Ok(status) => debug!("new order sent {:?}", status),
+                        Err(err) => {
+                            brackets.remove(&id);
+                            if must_halt("send order", &err) {
+                                return wallet;
+                            }
+                        }
+                    }
+                }
+                Action::CancelOrder(symbol, id) => {
+                    let order = match orders.get(&key).and_then(|ords| ords.iter().find(|ord| ord.id == id)) {
+                        Some(order) => order.clone(),
+                        None => {
+                            warn!("{} - can't cancel order {}, not among the outstanding ones", symbol, id);
+                            continue;
+                        }
+                    };
+                    let status = match broker.as_mut() {
+                        Some(broker) => Ok(broker.cancel_order(symbol, id)),
+                        None => retry("cancel order", || rest.cancel_order(order.clone())).await,
+                    };
+                    match status {
+                        Ok(status) => debug!("new cancel order sent {:?}", status),
+                        Err(err) if must_halt("cancel order", &err) => return wallet,
+                        Err(_) => {}
+                    }
+                }
+                Action::None => {}
             }
-            Action::None => {}
         }
     }
+    save_snapshots(tx_storage, &strategies, &fingerprints).await;
+    let left: usize = orders.values().map(|ords| ords.len()).sum();
+    info!("{} - stopped, {} outstanding orders left", exchange, left);
+    wallet
+}
+
+// log target of the orders and fills that are not ours, see log4rs.yaml
+const FOREIGN: &str = "trader::foreign";
+
+// symbol, time frame and instance id of a running strategy
+type StrategyKey = (String, chrono::Duration, u16);
+
+// the strategy that sent an order, none for orders placed by hand or by other bots
+// orders from before the instance ids go to the strategy alone on their symbol, like the open positions
+fn owner<'a>(mut keys: impl Iterator<Item = &'a StrategyKey>, sym: &str, order: &Order) -> Option<StrategyKey> {
+    if is_legacy(order) {
+        let mut on_sym = keys.filter(|key| key.0 == sym);
+        return match (on_sym.next(), on_sym.next()) {
+            (Some(key), None) => Some(key.clone()),
+            _ => None,
+        };
+    }
+    keys.find(|key| key.0 == sym && Some(key.2) == order.owner).cloned()
+}
+
+// ours, with an id sent before the instance ids: {id}_{tx_ref} or a bare id
+// the orders placed by hand or by other bots have no id we can read
+fn is_legacy(order: &Order) -> bool {
+    order.owner.is_none() && order.id != 0
+}
+
+// take profit and stop loss legs left by the same fill, told apart from other orders by their tx_ref
+fn oco_pairs(orders: &[Order]) -> Vec<(Order, Order)> {
+    orders
+        .iter()
+        .filter(|ord| ord.tx_ref != 0 && matches!(ord.o_type, Type::Limit(_)))
+        .filter(|take_profit| {
+            let same_ref = orders.iter().filter(|ord| ord.tx_ref == take_profit.tx_ref);
+            same_ref.filter(|ord| matches!(ord.o_type, Type::Limit(_))).count() == 1
+        })
+        .filter_map(|take_profit| {
+            let mut stops = orders
+                .iter()
+                .filter(|ord| ord.tx_ref == take_profit.tx_ref && matches!(ord.o_type, Type::StopLoss(_)));
+            match (stops.next(), stops.next()) {
+                (Some(stop_loss), None) => Some((take_profit.clone(), stop_loss.clone())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// snapshots of strategies sharing name, symbol and time frame are kept apart by the instance id
+fn snapshot_name(strategy: &dyn SpotSinglePairStrategy, key: &StrategyKey) -> String {
+    format!("{}-{}", strategy.name(), key.2)
+}
+
+const SNAPSHOT_MINUTES: i64 = 5;
+
+// a failed save only costs a fresh start after the next restart
+async fn save_snapshots(
+    tx_storage: &dyn TransactionStore,
+    strategies: &HashMap<StrategyKey, Box<dyn SpotSinglePairStrategy>>,
+    fingerprints: &HashMap<StrategyKey, String>,
+) {
+    for (key, strategy) in strategies {
+        let state = strategy.snapshot();
+        if state.is_null() {
+            continue;
+        }
+        let fingerprint = fingerprints.get(key).expect("no fingerprint for strategy");
+        let name = snapshot_name(strategy.as_ref(), key);
+        match tx_storage.save_snapshot(&name, fingerprint, &state).await {
+            Ok(_) => debug!("{}: snapshot saved", name),
+            Err(err) => error!("{}: could not save snapshot {:?}", name, err),
+        }
+    }
+}
+
+// SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut term = signal(SignalKind::terminate()).expect("in listening to SIGTERM");
+    select(Box::pin(term.recv()), Box::pin(actix_rt::signal::ctrl_c())).await;
+}
+
+const MAX_ATTEMPTS: u32 = 5;
+
+// transient failures are retried with a growing delay, or as long as the rate limit asks
+// only for requests that are safe to repeat, orders go through send_once
+async fn retry<T, F, Fut>(what: &str, call: F) -> Result<T, Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        match call().await {
+            Err(err) if err.is_transient() && attempt + 1 < MAX_ATTEMPTS => {
+                attempt += 1;
+                let wait = match err {
+                    Error::RateLimited(Some(secs)) => secs,
+                    _ => 2u64.pow(attempt),
+                };
+                warn!("{} failed with {:?}, attempt {} retrying in {}s", what, err, attempt, wait);
+                actix_rt::time::delay_for(std::time::Duration::from_secs(wait)).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+// the exchange only rejects a repeated client order id while the first order is still open,
+// so an order is sent again only when the exchange has no record of it after a failure
+// an order, or the two legs of an oco pair
+async fn send_once(what: &str, rest: &dyn RestApi, mut legs: Vec<Order>) -> Result<OrderStatus, Error> {
+    let mut attempt = 0;
+    loop {
+        let res = if legs.len() == 2 {
+            rest.send_oco_order(legs[0].clone(), legs[1].clone()).await
+        } else {
+            rest.send_order(legs[0].clone()).await
+        };
+        let err = match res {
+            Err(err) if err.is_transient() && attempt + 1 < MAX_ATTEMPTS => err,
+            res => return res,
+        };
+        attempt += 1;
+        let wait = match err {
+            Error::RateLimited(Some(secs)) => secs,
+            _ => 2u64.pow(attempt),
+        };
+        warn!("{} failed with {:?}, attempt {} checking again in {}s", what, err, attempt, wait);
+        actix_rt::time::delay_for(std::time::Duration::from_secs(wait)).await;
+        // rate limited requests are turned down before reaching the order book
+        if let Error::RateLimited(_) = err {
+            continue;
+        }
+        let mut missing = Vec::new();
+        for leg in &legs {
+            match retry("look up order", || rest.order_exists(leg.clone())).await {
+                Ok(true) => info!("{} - order {} went through despite {:?}", what, leg.id, err),
+                Ok(false) => missing.push(leg.clone()),
+                // sending it blindly could fill it twice
+                Err(lookup) => {
+                    error!("{} - can't tell whether order {} went through {:?}", what, leg.id, lookup);
+                    return Err(err);
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(OrderStatus::Accepted);
+        }
+        // the legs of pairs sent as independent orders may go through one at a time
+        legs = missing;
+    }
+}
+
+// a failed request costs the single action unless the account itself is unusable
+fn must_halt(what: &str, err: &Error) -> bool {
+    if err.is_fatal() {
+        error!("{} failed with {:?}, halting", what, err);
+        true
+    } else {
+        error!("{} failed with {:?}, skipping", what, err);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::candles::Candle;
+    use crate::orders::{Side, Transaction};
+    use crate::symbol::Symbol;
+    use async_trait::async_trait;
+    use chrono::{Duration, NaiveDateTime};
+    use std::cell::RefCell;
+
+    // loses the connection on the first send, after the order reached the book when `reached` is set
+    struct FlakyRest {
+        reached: bool,
+        calls: RefCell<u32>,
+        sent: RefCell<Vec<OrderId>>,
+    }
+
+    #[async_trait(?Send)]
+    impl RestApi for FlakyRest {
+        async fn get_candles(
+            &self,
+            _sym: &str,
+            _interval: Option<&Duration>,
+            _start: Option<&NaiveDateTime>,
+            _limit: Option<usize>,
+        ) -> Result<Vec<Candle>, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn get_symbol_info(&self, _sym: &str) -> Result<Symbol, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn get_wallet(&self) -> Result<SpotWallet, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn refresh_ws_token(&self, _old_token: Option<String>) -> Result<String, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn send_order(&self, order: Order) -> Result<OrderStatus, Error> {
+            *self.calls.borrow_mut() += 1;
+            let first = *self.calls.borrow() == 1;
+            if !first || self.reached {
+                self.sent.borrow_mut().push(order.id);
+            }
+            if first {
+                return Err(Error::Network(String::from("connection reset")));
+            }
+            Ok(OrderStatus::Accepted)
+        }
+        async fn send_oco_order(&self, _take_profit: Order, _stop_loss: Order) -> Result<OrderStatus, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn cancel_order(&self, _order: Order) -> Result<OrderStatus, Error> {
+            Err(Error::Unimplemented)
+        }
+        async fn order_exists(&self, order: Order) -> Result<bool, Error> {
+            Ok(self.sent.borrow().contains(&order.id))
+        }
+        async fn get_outstanding_orders(&self, _symbol: &str) -> Result<Vec<Order>, Error> {
+            Err(Error::Unimplemented)
+        }
+    }
+
+    fn send_flaky(reached: bool) -> (Result<OrderStatus, Error>, Vec<OrderId>, OrderId) {
+        let order = Order::new();
+        let id = order.id;
+        let rest = FlakyRest {
+            reached,
+            calls: RefCell::new(0),
+            sent: RefCell::new(Vec::new()),
+        };
+        let res = actix_rt::System::new("send-once").block_on(async move {
+            let res = send_once("send order", &rest, vec![order]).await;
+            (res, rest.sent.into_inner())
+        });
+        (res.0, res.1, id)
+    }
+
+    #[test]
+    fn orders_that_reached_the_exchange_are_not_sent_again() {
+        let (res, sent, id) = send_flaky(true);
+        assert!(matches!(res, Ok(OrderStatus::Accepted)));
+        assert_eq!(sent, vec![id]);
+    }
+
+    #[test]
+    fn orders_the_exchange_never_got_are_sent_again() {
+        let (res, sent, id) = send_flaky(false);
+        assert!(matches!(res, Ok(OrderStatus::Accepted)));
+        assert_eq!(sent, vec![id]);
+    }
+
+    fn sell(o_type: Type, tx_ref: OrderId) -> Order {
+        let mut order = Order::new();
+        order.side = Side::Sell;
+        order.o_type = o_type;
+        order.tx_ref = tx_ref;
+        order
+    }
+
+    #[test]
+    fn oco_pairs_by_fill() {
+        let take_profit = sell(Type::Limit(110.0), 7);
+        let stop_loss = sell(Type::StopLoss(90.0), 7);
+        let orders = vec![
+            sell(Type::Limit(120.0), 8),
+            take_profit.clone(),
+            sell(Type::Limit(105.0), 0),
+            stop_loss.clone(),
+            // two stops for the same fill can't be told apart
+            sell(Type::Limit(130.0), 9),
+            sell(Type::StopLoss(80.0), 9),
+            sell(Type::StopLoss(85.0), 9),
+        ];
+        let pairs = oco_pairs(&orders);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].0.id, pairs[0].1.id), (take_profit.id, stop_loss.id));
+    }
+
+    #[test]
+    fn legacy_orders_and_fills_go_to_the_strategy_alone_on_the_symbol() {
+        let keys: Vec<StrategyKey> = vec![
+            (String::from("BTCUSDT"), Duration::minutes(15), 1),
+            (String::from("ETHUSDT"), Duration::minutes(15), 2),
+            (String::from("ETHUSDT"), Duration::hours(1), 3),
+        ];
+        // as parsed from a {id}_{tx_ref} client order id
+        let mut legacy = Order::new();
+        legacy.id = 1_234_567;
+        legacy.owner = None;
+        assert_eq!(owner(keys.iter(), "BTCUSDT", &legacy), Some(keys[0].clone()));
+        let tx = Transaction {
+            symbol: String::from("BTCUSDT"),
+            order: legacy.clone(),
+            ..Transaction::default()
+        };
+        assert_eq!(owner(keys.iter(), &tx.symbol, &tx.order), Some(keys[0].clone()));
+        // two strategies could have sent it
+        assert_eq!(owner(keys.iter(), "ETHUSDT", &legacy), None);
+        // placed by hand
+        let mut manual = legacy.clone();
+        manual.id = 0;
+        assert_eq!(owner(keys.iter(), "BTCUSDT", &manual), None);
+        let mut ours = Order::new();
+        ours.set_owner(3);
+        assert_eq!(owner(keys.iter(), "ETHUSDT", &ours), Some(keys[2].clone()));
+        assert_eq!(owner(keys.iter(), "BTCUSDT", &ours), None);
+    }
 }
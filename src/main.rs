@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
-use chrono::NaiveDate;
+use chrono::{NaiveDate, NaiveTime};
 use std::future::{ready, Future};
 use std::pin::Pin;
 use structopt::StructOpt;
@@ -11,26 +11,53 @@ mod candles;
 mod configuration;
 mod drivers;
 mod error;
+mod file_storage;
 mod import;
 mod live;
+mod optimize;
 mod orders;
+mod paper;
 mod statistics;
 mod storage;
 mod strategies;
 mod symbol;
 mod utils;
 mod wallets;
-use crate::backtest::backtest_spot_singlepair;
+use crate::backtest::{backtest_portfolio, backtest_spot_singlepair};
+use crate::candles::{Candle, CandleSeries};
+use std::collections::HashMap;
 use crate::configuration::{ExchangeSettings, Settings, StrategySettings};
+use crate::storage::CandleStore;
 
 #[derive(Debug, StructOpt)]
 enum Trade {
     #[structopt(about = "import candles from exchage")]
     Import {
+        exchange: String,
+        #[structopt(help = "symbols to import, all the symbols of the exchange strategies when none or all")]
+        symbols: Vec<String>,
+        #[structopt(long, required_unless = "until-now")]
+        start: Option<NaiveDate>,
+        #[structopt(long, required_unless = "until-now")]
+        end: Option<NaiveDate>,
+        #[structopt(long, help = "resume each symbol from its last stored candle up to now")]
+        until_now: bool,
+    },
+    #[structopt(about = "import candles from binance kline dumps, a csv/zip file or a directory of them")]
+    ImportFile {
+        exchange: String,
+        path: std::path::PathBuf,
+        #[structopt(long, help = "only import the files of this symbol")]
+        symbol: Option<String>,
+    },
+    #[structopt(about = "list the missing 1m candles of a symbol, and fetch them again")]
+    Gaps {
         exchange: String,
         symbol: String,
         start: NaiveDate,
         end: NaiveDate,
+        #[structopt(long, help = "fetch the missing candles, marking what the exchange doesn't have as empty")]
+        repair: bool,
     },
     #[structopt(about = "backtest specific strategy")]
     Backtest {
@@ -40,27 +67,142 @@ enum Trade {
         start: NaiveDate,
         end: NaiveDate,
     },
+    #[structopt(about = "backtest all the strategies of an exchange sharing one wallet")]
+    Portfolio {
+        exchange: String,
+        start: NaiveDate,
+        end: NaiveDate,
+    },
+    #[structopt(about = "optimize strategy settings over a grid of values")]
+    Optimize {
+        strategy: String,
+        exchange: String,
+        symbol: String,
+        start: NaiveDate,
+        end: NaiveDate,
+        #[structopt(short = "p", long = "param", help = "name=v1,v2,.. or name=start..end:step")]
+        params: Vec<optimize::ParamSpec>,
+        #[structopt(long, help = "backtest only a random sample of the grid")]
+        samples: Option<usize>,
+        #[structopt(long, default_value = "4")]
+        jobs: usize,
+        #[structopt(long, default_value = "csv", help = "csv or json")]
+        format: optimize::Format,
+        #[structopt(long)]
+        output: Option<std::path::PathBuf>,
+    },
+    #[structopt(about = "walk forward analysis over rolling in/out of sample windows")]
+    WalkForward {
+        strategy: String,
+        exchange: String,
+        symbol: String,
+        start: NaiveDate,
+        end: NaiveDate,
+        #[structopt(long, help = "in sample window, i.e. 90days")]
+        in_sample: humantime::Duration,
+        #[structopt(long, help = "out of sample window, i.e. 30days")]
+        out_of_sample: humantime::Duration,
+        #[structopt(short = "p", long = "param", help = "name=v1,v2,.. or name=start..end:step")]
+        params: Vec<optimize::ParamSpec>,
+        #[structopt(long, help = "backtest only a random sample of the grid")]
+        samples: Option<usize>,
+        #[structopt(long, default_value = "4")]
+        jobs: usize,
+        #[structopt(long, help = "where to write the stitched out of sample equity curve")]
+        equity: Option<std::path::PathBuf>,
+    },
+    #[structopt(about = "database maintenance")]
+    Db(Db),
     #[structopt(about = "live trading specific strategy")]
     Live {},
+    #[structopt(about = "paper trading on live market data, orders are filled locally")]
+    Paper {},
+}
+
+#[derive(Debug, StructOpt)]
+enum Db {
+    #[structopt(about = "apply the pending schema migrations to candle and transaction storages")]
+    Migrate {},
 }
 
 #[actix_web::main]
 async fn main() {
-    openssl_probe::init_ssl_cert_env_vars();
+    // still single threaded, nothing else reads the environment yet
+    unsafe { openssl_probe::init_openssl_env_vars() };
     let settings = Settings::get_configuration("trader.toml").expect("Failed at reading configuration");
     let opt = Trade::from_args();
     match opt {
         Trade::Import {
             exchange,
-            symbol,
+            mut symbols,
             start,
             end,
+            until_now,
         } => {
             let exc_sett = settings.exchanges.get(&exchange).expect("can't find the exchange in config");
-            let driver = drivers::create_rest_client(&exchange, &exc_sett).expect("exchange not found");
-            let storage = storage::Candles::new(&settings.candle_storage).await;
-            let res = import::import(driver.as_ref(), &storage, &exchange, &symbol, &start, &end).await;
-            println!("downloaded {} candles", res);
+            let driver = drivers::create_rest_client(&exchange, exc_sett).expect("exchange not found");
+            let storage = storage::create_candle_store(&settings.candle_storage)
+                .await
+                .expect("no candle storage");
+            if symbols.is_empty() || symbols == ["all"] {
+                symbols = settings
+                    .strategies
+                    .iter()
+                    .filter(|st| st.exchange == exchange)
+                    .map(|st| st.symbol.clone())
+                    .collect();
+                symbols.sort();
+                symbols.dedup();
+            }
+            let start_t = start.map(|start| start.and_time(NaiveTime::default()));
+            let end_t = match (until_now, end) {
+                (false, Some(end)) => end.and_time(NaiveTime::default()),
+                _ => chrono::Utc::now().naive_utc(),
+            };
+            for symbol in symbols {
+                let last = if until_now {
+                    storage.last_tstamp(&exchange, &symbol).await
+                } else {
+                    None
+                };
+                let from = last
+                    .map(|last| last + chrono::Duration::minutes(1))
+                    .or(start_t)
+                    .unwrap_or_else(|| panic!("no candles stored for {}, a start date is needed", symbol));
+                let res = import::import(driver.as_ref(), storage.as_ref(), &exchange, &symbol, &from, &end_t).await;
+                println!("{} - downloaded {} candles", symbol, res);
+            }
+        }
+        Trade::ImportFile { exchange, path, symbol } => {
+            let storage = storage::create_candle_store(&settings.candle_storage)
+                .await
+                .expect("no candle storage");
+            let res = import::import_file(storage.as_ref(), &exchange, &path, symbol.as_deref()).await;
+            println!("imported {} candles", res);
+        }
+        Trade::Gaps {
+            exchange,
+            symbol,
+            start,
+            end,
+            repair,
+        } => {
+            let storage = storage::create_candle_store(&settings.candle_storage)
+                .await
+                .expect("no candle storage");
+            let (start_t, end_t) = (start.and_time(NaiveTime::default()), end.and_time(NaiveTime::default()));
+            let gaps = import::missing_ranges(storage.as_ref(), &exchange, &symbol, &start_t, &end_t).await;
+            println!("{} - {} gaps between {} and {}", symbol, gaps.len(), start_t, end_t);
+            for (from, to) in &gaps {
+                println!("  missing {} -> {} ({} minutes)", from, to, (*to - *from).num_minutes());
+            }
+            if repair && !gaps.is_empty() {
+                let exc_sett = settings.exchanges.get(&exchange).expect("can't find the exchange in config");
+                let driver = drivers::create_rest_client(&exchange, exc_sett).expect("exchange not found");
+                let res = import::repair(driver.as_ref(), storage.as_ref(), &exchange, &symbol, &gaps).await;
+                let left = import::missing_ranges(storage.as_ref(), &exchange, &symbol, &start_t, &end_t).await;
+                println!("downloaded {} candles, {} gaps left", res, left.len());
+            }
         }
         Trade::Backtest {
             strategy,
@@ -70,7 +212,9 @@ async fn main() {
             end,
         } => {
             log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
-            let storage = storage::Candles::new(&settings.candle_storage).await;
+            let storage = storage::create_candle_store(&settings.candle_storage)
+                .await
+                .expect("no candle storage");
             let cfg = settings
                 .strategies
                 .iter()
@@ -82,29 +226,168 @@ async fn main() {
             let sym_info = drv.get_symbol_info(&symbol).await.expect("no symbol info");
             let strategy =
                 strategies::create(&strategy, exchange, sym_info, cfg.time_frame, cfg.settings.clone()).expect("strategies::create");
-            let res = backtest_spot_singlepair(storage, strategy, start, end)
+            let minutes =
+                backtest::load_minutes(storage.as_ref(), &cfg.exchange, &symbol, &start, &end, exc_sett.backtest.gaps).await;
+            let series = CandleSeries::new(minutes, cfg.time_frame);
+            let res = backtest_spot_singlepair(&series, strategy, start, end, &exc_sett.backtest).expect("backtest epic fail");
+            println!("Backtest final wallet{:?}", res.1);
+            println!("Backtest statistics {}", res.0.report());
+        }
+        Trade::Portfolio { exchange, start, end } => {
+            log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
+            let storage = storage::create_candle_store(&settings.candle_storage)
                 .await
-                .expect("backtest epic fail");
+                .expect("no candle storage");
+            let exc_sett = settings.exchanges.get(&exchange).expect("can't find the exchange in config");
+            let drv = drivers::create_rest_client(&exchange, exc_sett).expect("no exchange driver");
+            let cfgs: Vec<_> = settings.strategies.iter().filter(|st| st.exchange == exchange).collect();
+            if cfgs.is_empty() {
+                panic!("no strategy configured on {}", exchange);
+            }
+            let mut minutes: HashMap<String, Vec<Candle>> = HashMap::new();
+            let mut series: Vec<CandleSeries> = Vec::new();
+            for cfg in &cfgs {
+                if !minutes.contains_key(&cfg.symbol) {
+                    let cnds =
+                        backtest::load_minutes(storage.as_ref(), &exchange, &cfg.symbol, &start, &end, exc_sett.backtest.gaps)
+                            .await;
+                    minutes.insert(cfg.symbol.clone(), cnds);
+                }
+                series.push(CandleSeries::new(minutes[&cfg.symbol].clone(), cfg.time_frame));
+            }
+            let mut strats = Vec::new();
+            for (cfg, series) in cfgs.iter().zip(series.iter()) {
+                let sym_info = drv.get_symbol_info(&cfg.symbol).await.expect("no symbol info");
+                let strategy = strategies::create(&cfg.name, exchange.clone(), sym_info, cfg.time_frame, cfg.settings.clone())
+                    .expect("strategies::create");
+                strats.push((strategy, series));
+            }
+            let res = backtest_portfolio(strats, start, end, &exc_sett.backtest).expect("backtest epic fail");
             println!("Backtest final wallet{:?}", res.1);
             println!("Backtest statistics {}", res.0.report());
         }
-        Trade::Live {} => {
+        Trade::Optimize {
+            strategy,
+            exchange,
+            symbol,
+            start,
+            end,
+            params,
+            samples,
+            jobs,
+            format,
+            output,
+        } => {
+            log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
+            let storage = storage::create_candle_store(&settings.candle_storage)
+                .await
+                .expect("no candle storage");
+            let cfg = settings
+                .strategies
+                .iter()
+                .find(|settings| settings.name == strategy && settings.exchange == exchange && settings.symbol == symbol)
+                .expect("no such strategy configuration");
+
+            let exc_sett = settings.exchanges.get(&exchange).expect("can't find the exchange in config");
+            let drv = drivers::create_rest_client(&exchange, exc_sett).expect("no exchange driver");
+            let sym_info = drv.get_symbol_info(&symbol).await.expect("no symbol info");
+            let minutes =
+                backtest::load_minutes(storage.as_ref(), &exchange, &symbol, &start, &end, exc_sett.backtest.gaps).await;
+            let series = CandleSeries::new(minutes, cfg.time_frame);
+            let combos = optimize::grid(&cfg.settings, &params, samples);
+            println!("optimizing over {} combinations", combos.len());
+            let runs = optimize::optimize(&series, start, end, &exc_sett.backtest, combos, jobs, |sett| {
+                strategies::create(&strategy, exchange.clone(), sym_info.clone(), cfg.time_frame, sett).expect("strategies::create")
+            });
+            let report = optimize::report(&runs, format);
+            match output {
+                Some(path) => std::fs::write(path, report).expect("in writing the optimization report"),
+                None => println!("{}", report),
+            }
+        }
+        Trade::WalkForward {
+            strategy,
+            exchange,
+            symbol,
+            start,
+            end,
+            in_sample,
+            out_of_sample,
+            params,
+            samples,
+            jobs,
+            equity,
+        } => {
+            log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
+            let storage = storage::create_candle_store(&settings.candle_storage)
+                .await
+                .expect("no candle storage");
+            let cfg = settings
+                .strategies
+                .iter()
+                .find(|settings| settings.name == strategy && settings.exchange == exchange && settings.symbol == symbol)
+                .expect("no such strategy configuration");
+
+            let exc_sett = settings.exchanges.get(&exchange).expect("can't find the exchange in config");
+            let drv = drivers::create_rest_client(&exchange, exc_sett).expect("no exchange driver");
+            let sym_info = drv.get_symbol_info(&symbol).await.expect("no symbol info");
+            let minutes =
+                backtest::load_minutes(storage.as_ref(), &exchange, &symbol, &start, &end, exc_sett.backtest.gaps).await;
+            let series = CandleSeries::new(minutes, cfg.time_frame);
+            let in_sample = chrono::Duration::from_std(*in_sample).expect("in_sample out of range");
+            let out_of_sample = chrono::Duration::from_std(*out_of_sample).expect("out_of_sample out of range");
+            if in_sample.num_days() < 1 || out_of_sample.num_days() < 1 {
+                panic!("walk forward windows must be at least one day long");
+            }
+            let windows = optimize::split_windows(
+                start,
+                end,
+                chrono::Duration::days(in_sample.num_days()),
+                chrono::Duration::days(out_of_sample.num_days()),
+            );
+            let combos = optimize::grid(&cfg.settings, &params, samples);
+            println!("walking forward over {} windows, {} combinations each", windows.len(), combos.len());
+            let res = optimize::walk_forward(&series, &windows, &exc_sett.backtest, combos, jobs, |sett| {
+                strategies::create(&strategy, exchange.clone(), sym_info.clone(), cfg.time_frame, sett).expect("strategies::create")
+            });
+            println!("{}", res.report());
+            if let Some(path) = equity {
+                std::fs::write(path, res.equity_csv()).expect("in writing the equity curve");
+            }
+        }
+        Trade::Db(Db::Migrate {}) => {
+            let mut hosts = vec![&settings.candle_storage, &settings.transaction_storage];
+            hosts.dedup();
+            for host in hosts {
+                let applied = storage::migrate(host).await.expect("in migrating storage");
+                println!("applied {} migrations", applied);
+            }
+        }
+        Trade::Live {} | Trade::Paper {} => {
             log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
             let tx_storage: String = settings.transaction_storage.clone();
+            storage::migrate(&tx_storage).await.expect("in migrating transaction storage");
+            let (mode, table) = match opt {
+                Trade::Paper {} => (live::Mode::Paper, "paper_transactions"),
+                _ => (live::Mode::Live, "transactions"),
+            };
             let mut cur_arbiter = actix_rt::Arbiter::current();
+            let mut runs: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
             for (exchange, ex_settings) in settings.exchanges {
                 let strats: Vec<_> = settings.strategies.iter().filter(|st| st.exchange == exchange).cloned().collect();
                 if strats.is_empty() {
                     continue;
                 }
-                let storage = storage::Transactions::new(&tx_storage, &mut cur_arbiter).await;
-
-                actix_rt::Arbiter::spawn(async move {
-                    live::run_live(strats, storage, ex_settings).await;
-                });
-                actix_rt::time::delay_for(std::time::Duration::from_secs(5)).await;
+                let mut storage = storage::Transactions::new(&tx_storage, table, &mut cur_arbiter).await;
+                // exchanges start 5 seconds apart
+                let delay = std::time::Duration::from_secs(5 * runs.len() as u64);
+                runs.push(Box::pin(async move {
+                    actix_rt::time::delay_for(delay).await;
+                    live::run_live(strats, &mut storage, ex_settings, mode).await;
+                }));
             }
-            actix_rt::Arbiter::local_join().await;
+            // every loop returns on SIGINT / SIGTERM
+            futures_util::future::join_all(runs).await;
         }
     };
 }
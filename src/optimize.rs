@@ -0,0 +1,484 @@
+use crate::backtest::run_spot_singlepair;
+use crate::candles::CandleSeries;
+use crate::configuration::BacktestSettings;
+use crate::statistics::{Statistics, Summary};
+use crate::strategies::SpotSinglePairStrategy;
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use log::{error, info};
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// values a strategy setting takes during the optimization
+// name=v1,v2,v3 or name=start..end:step, end included
+#[derive(Debug, Clone)]
+pub struct ParamSpec {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+impl FromStr for ParamSpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, spec) = s.split_once('=').ok_or_else(|| format!("{}: expected name=values", s))?;
+        let values = if let Some((range, step)) = spec.split_once(':') {
+            let (start, end) = range.split_once("..").ok_or_else(|| format!("{}: expected start..end:step", s))?;
+            range_values(start, end, step).ok_or_else(|| format!("{}: invalid range", s))?
+        } else {
+            spec.split(',').map(|v| String::from(v.trim())).filter(|v| !v.is_empty()).collect()
+        };
+        if values.is_empty() {
+            return Err(format!("{}: no values", s));
+        }
+        Ok(Self {
+            name: String::from(name.trim()),
+            values,
+        })
+    }
+}
+
+fn range_values(start: &str, end: &str, step: &str) -> Option<Vec<String>> {
+    if let (Ok(start), Ok(end), Ok(step)) = (start.parse::<i64>(), end.parse::<i64>(), step.parse::<i64>()) {
+        if step <= 0 || end < start {
+            return None;
+        }
+        return Some((start..=end).step_by(step as usize).map(|v| v.to_string()).collect());
+    }
+    let decimals = step.split_once('.').map_or(0, |(_, dec)| dec.len());
+    let (start, end, step) = (start.parse::<f64>().ok()?, end.parse::<f64>().ok()?, step.parse::<f64>().ok()?);
+    if step <= 0.0 || end < start {
+        return None;
+    }
+    let count = ((end - start) / step + 1e-9).floor() as usize + 1;
+    Some(
+        (0..count)
+            .map(|i| format!("{:.prec$}", start + step * i as f64, prec = decimals))
+            .collect(),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+// a failed run keeps its settings and the reason, without statistics
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Run {
+    pub settings: BTreeMap<String, String>,
+    pub statistics: Option<Summary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Run {
+    fn gain_perc(&self) -> f64 {
+        self.statistics.as_ref().map_or(f64::NEG_INFINITY, |st| st.gain_perc)
+    }
+}
+
+// one backtest, a panicking strategy or wallet only fails its own run
+fn run_one<F>(
+    series: &CandleSeries,
+    start: NaiveDate,
+    end: NaiveDate,
+    settings: &BacktestSettings,
+    combo: &HashMap<String, String>,
+    create: &F,
+) -> Result<Statistics, String>
+where
+    F: Fn(HashMap<String, String>) -> Box<dyn SpotSinglePairStrategy> + Sync,
+{
+    let run = catch_unwind(AssertUnwindSafe(|| {
+        let strat = create(combo.clone());
+        run_spot_singlepair(series, strat, start, end, settings, &mut |_| {})
+    }));
+    match run {
+        Ok(Ok((stats, _))) => Ok(stats),
+        Ok(Err(e)) => Err(format!("{:?}", e)),
+        Err(panic) => Err(panic
+            .downcast_ref::<&str>()
+            .map(|msg| String::from(*msg))
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("panicked"))),
+    }
+}
+
+// cartesian product of the parameter values on top of the configured settings
+// with samples, only a random subset of the product is returned
+pub fn grid(base: &HashMap<String, String>, params: &[ParamSpec], samples: Option<usize>) -> Vec<HashMap<String, String>> {
+    let mut combos: Vec<HashMap<String, String>> = vec![base.clone()];
+    for param in params {
+        combos = combos
+            .iter()
+            .flat_map(|combo| {
+                param.values.iter().map(move |value| {
+                    let mut settings = combo.clone();
+                    settings.insert(param.name.clone(), value.clone());
+                    settings
+                })
+            })
+            .collect();
+    }
+    if let Some(size) = samples {
+        combos.shuffle(&mut rand::thread_rng());
+        combos.truncate(size);
+    }
+    combos
+}
+
+// backtests every combination in parallel on the shared candles
+// runs are ranked by gain, best first, failed ones last
+pub fn optimize<F>(
+    series: &CandleSeries,
+    start: NaiveDate,
+    end: NaiveDate,
+    settings: &BacktestSettings,
+    combos: Vec<HashMap<String, String>>,
+    jobs: usize,
+    create: F,
+) -> Vec<Run>
+where
+    F: Fn(HashMap<String, String>) -> Box<dyn SpotSinglePairStrategy> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let runs: Mutex<Vec<Run>> = Mutex::new(Vec::with_capacity(combos.len()));
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= combos.len() {
+                    return;
+                }
+                let combo = &combos[i];
+                let run = match run_one(series, start, end, settings, combo, &create) {
+                    Ok(stats) => Run {
+                        settings: combo.clone().into_iter().collect(),
+                        statistics: Some(stats.summary()),
+                        error: None,
+                    },
+                    Err(e) => {
+                        error!("backtest failed with {:?}: {}", combo, e);
+                        Run {
+                            settings: combo.clone().into_iter().collect(),
+                            statistics: None,
+                            error: Some(e),
+                        }
+                    }
+                };
+                runs.lock().expect("runs lock").push(run);
+                info!("optimize: {}/{} runs done", done.fetch_add(1, Ordering::SeqCst) + 1, combos.len());
+            });
+        }
+    });
+    let mut runs = runs.into_inner().expect("runs lock");
+    runs.sort_by(|a, b| b.gain_perc().partial_cmp(&a.gain_perc()).unwrap_or(std::cmp::Ordering::Equal));
+    runs
+}
+
+pub fn report(runs: &[Run], format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(runs).expect("in serializing runs"),
+        Format::Csv => {
+            let names: Vec<String> = runs.first().map_or(Vec::new(), |run| run.settings.keys().cloned().collect());
+            let names: Vec<String> = names.iter().map(|name| csv_field(name)).collect();
+            let mut lines = vec![format!(
+                "rank,{},gain_perc,lowest_balance,highest_balance,orders,transactions,trades,wins,losses,avg_win,avg_loss,fees,error",
+                names.join(",")
+            )];
+            for (rank, run) in runs.iter().enumerate() {
+                let values: Vec<String> = run.settings.values().map(|value| csv_field(value)).collect();
+                let st = match (&run.statistics, &run.error) {
+                    (Some(st), _) => st,
+                    (None, error) => {
+                        lines.push(format!(
+                            "{},{},,,,,,,,,,,,{}",
+                            rank + 1,
+                            values.join(","),
+                            csv_field(error.as_deref().unwrap_or_default())
+                        ));
+                        continue;
+                    }
+                };
+                lines.push(format!(
+                    "{},{},{:.3},{:.3},{:.3},{},{},{},{},{},{:.5},{:.5},{:.3},",
+                    rank + 1,
+                    values.join(","),
+                    st.gain_perc,
+                    st.lowest_balance,
+                    st.highest_balance,
+                    st.orders,
+                    st.transactions,
+                    st.trades,
+                    st.wins,
+                    st.losses,
+                    st.avg_win,
+                    st.avg_loss,
+                    st.fees,
+                ));
+            }
+            lines.join("\n")
+        }
+    }
+}
+
+// quoted when it holds a separator, a quote or a line break, quotes doubled
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
+
+// in sample start, in sample end / out of sample start, out of sample end
+pub type Window = (NaiveDate, NaiveDate, NaiveDate);
+
+// rolling windows, each out of sample period follows its in sample one
+// and windows move forward by the out of sample length
+pub fn split_windows(start: NaiveDate, end: NaiveDate, in_sample: Duration, out_of_sample: Duration) -> Vec<Window> {
+    let mut windows: Vec<Window> = Vec::new();
+    let mut is_start = start;
+    while is_start + in_sample < end {
+        let is_end = is_start + in_sample;
+        let oos_end = (is_end + out_of_sample).min(end);
+        windows.push((is_start, is_end, oos_end));
+        is_start += out_of_sample;
+    }
+    windows
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct WindowRun {
+    pub in_sample: (NaiveDate, NaiveDate),
+    pub out_of_sample: (NaiveDate, NaiveDate),
+    pub settings: BTreeMap<String, String>,
+    pub in_sample_statistics: Summary,
+    pub statistics: Summary,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct WalkForward {
+    pub windows: Vec<WindowRun>,
+    // out of sample balances, each window compounding on the previous one
+    pub equity: Vec<(NaiveDateTime, f64)>,
+    // runs that panicked or errored, in any window
+    pub failures: Vec<Run>,
+}
+
+// settings are picked by the best in sample gain and evaluated on the next out of sample period
+pub fn walk_forward<F>(
+    series: &CandleSeries,
+    windows: &[Window],
+    settings: &BacktestSettings,
+    combos: Vec<HashMap<String, String>>,
+    jobs: usize,
+    create: F,
+) -> WalkForward
+where
+    F: Fn(HashMap<String, String>) -> Box<dyn SpotSinglePairStrategy> + Sync,
+{
+    let mut result = WalkForward {
+        windows: Vec::new(),
+        equity: Vec::new(),
+        failures: Vec::new(),
+    };
+    let mut carry: Option<f64> = None;
+    for (is_start, is_end, oos_end) in windows {
+        info!("walk forward: in sample {} - {}, out of sample {} - {}", is_start, is_end, is_end, oos_end);
+        let runs = optimize(series, *is_start, *is_end, settings, combos.clone(), jobs, &create);
+        let failures: Vec<Run> = runs.iter().filter(|run| run.error.is_some()).cloned().collect();
+        let (best, best_stats) = match runs.into_iter().find_map(|run| run.statistics.clone().map(|st| (run, st))) {
+            Some(best) => best,
+            None => {
+                error!("walk forward: no successful run in {} - {}", is_start, is_end);
+                result.failures.extend(failures);
+                continue;
+            }
+        };
+        result.failures.extend(failures);
+        let combo: HashMap<String, String> = best.settings.clone().into_iter().collect();
+        let stats = match run_one(series, *is_end, *oos_end, settings, &combo, &create) {
+            Ok(stats) => stats,
+            Err(e) => {
+                error!("walk forward: out of sample backtest failed in {} - {}: {}", is_end, oos_end, e);
+                result.failures.push(Run {
+                    settings: best.settings,
+                    statistics: None,
+                    error: Some(e),
+                });
+                continue;
+            }
+        };
+        let scale = carry.unwrap_or(stats.balance_start) / stats.balance_start;
+        result.equity.extend(stats.equity.iter().map(|(tstamp, balance)| (*tstamp, balance * scale)));
+        carry = Some(stats.balance * scale);
+        result.windows.push(WindowRun {
+            in_sample: (*is_start, *is_end),
+            out_of_sample: (*is_end, *oos_end),
+            settings: best.settings,
+            in_sample_statistics: best_stats,
+            statistics: stats.summary(),
+        });
+    }
+    result
+}
+
+impl WalkForward {
+    // compounded out of sample gain
+    pub fn gain_perc(&self) -> f64 {
+        let growth = self
+            .windows
+            .iter()
+            .fold(1.0, |growth, window| growth * (1.0 + window.statistics.gain_perc / 100.0));
+        (growth - 1.0) * 100.0
+    }
+
+    pub fn report(&self) -> String {
+        let mut lines = vec![String::from(
+            "in_sample_start,out_of_sample_start,out_of_sample_end,settings,in_sample_gain_perc,gain_perc,trades,wins,losses,fees",
+        )];
+        for window in &self.windows {
+            let settings: Vec<String> = window.settings.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            lines.push(format!(
+                "{},{},{},{},{:.3},{:.3},{},{},{},{:.3}",
+                window.in_sample.0,
+                window.out_of_sample.0,
+                window.out_of_sample.1,
+                csv_field(&settings.join(" ")),
+                window.in_sample_statistics.gain_perc,
+                window.statistics.gain_perc,
+                window.statistics.trades,
+                window.statistics.wins,
+                window.statistics.losses,
+                window.statistics.fees,
+            ));
+        }
+        lines.push(format!("out of sample gain %: {:.3}", self.gain_perc()));
+        for failure in &self.failures {
+            let settings: Vec<String> = failure.settings.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            lines.push(format!(
+                "failed: {} - {}",
+                settings.join(" "),
+                failure.error.as_deref().unwrap_or_default()
+            ));
+        }
+        lines.join("\n")
+    }
+
+    pub fn equity_csv(&self) -> String {
+        let mut lines = vec![String::from("tstamp,balance")];
+        lines.extend(
+            self.equity
+                .iter()
+                .map(|(tstamp, balance)| format!("{},{:.3}", tstamp.format("%Y-%m-%d %H:%M:%S"), balance)),
+        );
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::candles::Candle;
+    use crate::strategies::Sample;
+    use crate::symbol::Symbol;
+
+    fn settings() -> BacktestSettings {
+        serde_json::from_value(serde_json::json!({ "fees_perc": 0.1 })).unwrap()
+    }
+
+    fn series() -> CandleSeries {
+        let start = NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let minutes = (0..10)
+            .map(|i| Candle {
+                tstamp: start + Duration::minutes(i),
+                tframe: Duration::minutes(1),
+                open: 1.0,
+                close: 1.0,
+                low: 1.0,
+                high: 1.0,
+                volume: 1.0,
+            })
+            .collect();
+        CandleSeries::new(minutes, Duration::minutes(1))
+    }
+
+    #[test]
+    fn param_spec_lists_and_ranges() {
+        let spec: ParamSpec = "fast=1, 2,3".parse().unwrap();
+        assert_eq!(spec.name, "fast");
+        assert_eq!(spec.values, vec!["1", "2", "3"]);
+        let spec: ParamSpec = "slow=10..20:5".parse().unwrap();
+        assert_eq!(spec.values, vec!["10", "15", "20"]);
+        let spec: ParamSpec = "perc=0.5..1.0:0.25".parse().unwrap();
+        assert_eq!(spec.values, vec!["0.50", "0.75", "1.00"]);
+        assert!("slow".parse::<ParamSpec>().is_err());
+        assert!("slow=".parse::<ParamSpec>().is_err());
+        assert!("slow=20..10:5".parse::<ParamSpec>().is_err());
+        assert!("slow=10..20:0".parse::<ParamSpec>().is_err());
+    }
+
+    #[test]
+    fn grid_is_the_product_over_the_base_settings() {
+        let mut base = HashMap::new();
+        base.insert(String::from("fixed"), String::from("x"));
+        base.insert(String::from("fast"), String::from("0"));
+        let params: Vec<ParamSpec> = vec!["fast=1,2".parse().unwrap(), "slow=3,4,5".parse().unwrap()];
+        let combos = grid(&base, &params, None);
+        assert_eq!(combos.len(), 6);
+        assert!(combos.iter().all(|combo| combo["fixed"] == "x" && combo["fast"] != "0"));
+        assert_eq!(grid(&base, &params, Some(4)).len(), 4);
+    }
+
+    #[test]
+    fn failed_runs_are_reported_not_fatal() {
+        let combos = vec![
+            vec![(String::from("name"), String::from("ok"))].into_iter().collect(),
+            vec![(String::from("name"), String::from("a,\"b\""))].into_iter().collect(),
+        ];
+        let start = NaiveDate::from_ymd_opt(2021, 1, 1).unwrap();
+        let end = NaiveDate::from_ymd_opt(2021, 1, 2).unwrap();
+        let runs = optimize(&series(), start, end, &settings(), combos, 2, |sett| {
+            if sett["name"] != "ok" {
+                panic!("invalid combination");
+            }
+            Box::new(Sample::new(String::from("test"), Symbol::new(String::from("BTCUSDT")), Duration::minutes(1)))
+        });
+        assert_eq!(runs.len(), 2);
+        assert!(runs[0].statistics.is_some());
+        assert_eq!(runs[1].error.as_deref(), Some("invalid combination"));
+        let csv = report(&runs, Format::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("rank,name,gain_perc"));
+        assert_eq!(lines[2], "2,\"a,\"\"b\"\"\",,,,,,,,,,,,invalid combination");
+    }
+
+    #[test]
+    fn windows_roll_by_the_out_of_sample_length() {
+        let day = |d| NaiveDate::from_ymd_opt(2021, 1, d).unwrap();
+        let windows = split_windows(day(1), day(20), Duration::days(7), Duration::days(5));
+        assert_eq!(
+            windows,
+            vec![(day(1), day(8), day(13)), (day(6), day(13), day(18)), (day(11), day(18), day(20))]
+        );
+        assert!(split_windows(day(1), day(5), Duration::days(7), Duration::days(5)).is_empty());
+    }
+}
@@ -1,6 +1,38 @@
-use chrono::NaiveDateTime;
-use rand::prelude::random;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use crate::symbol::Symbol;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// order ids are made of the milliseconds since 2020-01-01 (41 bits), the instance id of the strategy (16 bits)
+// and a counter (6 bits): they grow with time, don't collide across instances and restarts,
+// and fit a postgres bigint
+pub type OrderId = u64;
+
+const INSTANCE_BITS: u32 = 16;
+const COUNTER_BITS: u32 = 6;
+// milliseconds and counter of the last id, shared by all instances
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+
+pub fn new_order_id(instance: u16) -> OrderId {
+    let epoch = NaiveDate::from_ymd_opt(2020, 1, 1).expect("epoch").and_hms_opt(0, 0, 0).expect("midnight");
+    let now = ((Utc::now().naive_utc() - epoch).num_milliseconds() as u64) << COUNTER_BITS;
+    // more than 64 ids in a millisecond borrow from the next ones
+    let last = LAST_TICK
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1)))
+        .expect("in updating the order id tick");
+    let tick = now.max(last + 1);
+    let millis = tick >> COUNTER_BITS;
+    let counter = tick & ((1 << COUNTER_BITS) - 1);
+    millis << (INSTANCE_BITS + COUNTER_BITS) | (instance as u64) << COUNTER_BITS | counter
+}
+
+// instance id encoded in an order id, none for the random ids of older orders
+pub fn order_instance(id: OrderId) -> Option<u16> {
+    if id >> (INSTANCE_BITS + COUNTER_BITS) == 0 {
+        return None;
+    }
+    Some((id >> COUNTER_BITS) as u16)
+}
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum TimeInForce {
@@ -25,15 +57,17 @@ pub enum Side {
     Sell,
 }
 
-impl ToString for Side {
-    fn to_string(&self) -> String {
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Side::Sell => String::from("Sell"),
-            Side::Buy => String::from("Buy"),
+            Side::Sell => write!(f, "Sell"),
+            Side::Buy => write!(f, "Buy"),
         }
     }
 }
 
+// short lived, not worth boxing the transaction
+#[allow(clippy::large_enum_variant)]
 #[derive(PartialEq, Clone, Debug)]
 pub enum OrderStatus {
     Accepted,
@@ -51,8 +85,15 @@ pub struct Order {
     pub o_type: Type,
     pub volume: f64,
     pub expire: Option<chrono::NaiveDateTime>,
-    pub id: u32,
-    pub tx_ref: u32,
+    pub id: OrderId,
+    pub tx_ref: OrderId,
+    // bracket legs spawned when the order is filled
+    pub take_profit: Option<f64>,
+    pub stop_loss: Option<f64>,
+    // the other leg of a one-cancels-the-other pair
+    pub oco_ref: OrderId,
+    // instance id of the strategy that sent it, none for orders placed by hand or by other bots
+    pub owner: Option<u16>,
 }
 impl Order {
     pub fn new() -> Self {
@@ -64,10 +105,20 @@ impl Order {
             o_type: Type::Market,
             volume: 0.0,
             expire: None,
-            id: random::<u16>() as u32,
+            id: new_order_id(0),
             tx_ref : 0,
+            take_profit: None,
+            stop_loss: None,
+            oco_ref: 0,
+            owner: None,
         }
     }
+
+    // an order sent by a strategy instance gets an id encoding it
+    pub fn set_owner(&mut self, owner: u16) {
+        self.owner = Some(owner);
+        self.id = new_order_id(owner);
+    }
 }
 impl Default for Order {
     fn default() -> Self {
@@ -75,6 +126,70 @@ impl Default for Order {
     }
 }
 
+// our client order ids are tr{id}_{tx_ref} in base 36, within the 36 characters allowed by binance
+const CLIENT_ID_PREFIX: &str = "tr";
+
+pub fn client_order_id(order: &Order) -> String {
+    format!("{}{}_{}", CLIENT_ID_PREFIX, to_base36(order.id), to_base36(order.tx_ref))
+}
+
+// id and tx_ref of a client order id, none when it isn't one of ours
+pub fn parse_client_order_id(client_id: &str) -> Option<(OrderId, OrderId)> {
+    let ids = client_id.strip_prefix(CLIENT_ID_PREFIX)?;
+    let mut parts = ids.split('_').map(|part| OrderId::from_str_radix(part, 36).ok());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Some(id)), Some(Some(tx_ref)), None) => Some((id, tx_ref)),
+        _ => None,
+    }
+}
+
+fn to_base36(mut value: u64) -> String {
+    let mut digits: Vec<u8> = Vec::new();
+    loop {
+        digits.push(b"0123456789abcdefghijklmnopqrstuvwxyz"[(value % 36) as usize]);
+        value /= 36;
+        if value == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+    String::from_utf8(digits).expect("in base36 digits")
+}
+
+// take profit and stop loss legs of a filled parent order
+// when both are present they cancel each other
+pub fn bracket_orders(parent: &Order, tx: &Transaction) -> Vec<Order> {
+    let side = match tx.side {
+        Side::Buy => Side::Sell,
+        Side::Sell => Side::Buy,
+    };
+    let leg = |o_type: Type| {
+        let mut order = Order::new();
+        order.exchange = parent.exchange.clone();
+        order.symbol = parent.symbol.clone();
+        order.side = side.clone();
+        order.o_type = o_type;
+        order.volume = tx.volume;
+        order.tx_ref = parent.id;
+        if let Some(owner) = parent.owner {
+            order.set_owner(owner);
+        }
+        order
+    };
+    let mut legs: Vec<Order> = Vec::new();
+    if let Some(price) = parent.take_profit {
+        legs.push(leg(Type::Limit(price)));
+    }
+    if let Some(stop) = parent.stop_loss {
+        legs.push(leg(Type::StopLoss(stop)));
+    }
+    if legs.len() == 2 {
+        legs[0].oco_ref = legs[1].id;
+        legs[1].oco_ref = legs[0].id;
+    }
+    legs
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Transaction {
     pub symbol: String,
@@ -100,3 +215,44 @@ impl Default for Transaction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_grow_and_carry_the_instance() {
+        let ids: Vec<OrderId> = (0..200).map(|i| new_order_id(i % 3)).collect();
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(order_instance(*id), Some((i % 3) as u16));
+        }
+        // in time order within an instance, by the millisecond across them
+        for instance in 0..3 {
+            let own: Vec<&OrderId> = ids.iter().filter(|id| order_instance(**id) == Some(instance)).collect();
+            assert!(own.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        let millis = |id: &OrderId| id >> (INSTANCE_BITS + COUNTER_BITS);
+        assert!(ids.windows(2).all(|pair| millis(&pair[0]) <= millis(&pair[1])));
+        assert_eq!(order_instance(new_order_id(u16::MAX)), Some(u16::MAX));
+        // random ids of older orders fit in the counter and instance bits
+        assert_eq!(order_instance(12345), None);
+        assert!(ids.iter().all(|id| *id < i64::MAX as u64));
+    }
+
+    #[test]
+    fn client_order_ids_round_trip() {
+        let mut order = Order::new();
+        order.set_owner(7);
+        order.tx_ref = new_order_id(7);
+        let client_id = client_order_id(&order);
+        assert!(client_id.starts_with("tr"));
+        assert!(client_id.len() <= 36);
+        assert_eq!(parse_client_order_id(&client_id), Some((order.id, order.tx_ref)));
+        order.tx_ref = 0;
+        assert_eq!(parse_client_order_id(&client_order_id(&order)), Some((order.id, 0)));
+        assert_eq!(parse_client_order_id("web_1234abcd"), None);
+        assert_eq!(parse_client_order_id("tr12_34_56"), None);
+        assert_eq!(parse_client_order_id("tr12"), None);
+        assert_eq!(parse_client_order_id("tr1!_2"), None);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::backtest::{
+    can_afford, charge_fees, generate_tx_from_candle, is_expired, lock_funds, order_in_candle, update_wallet,
+};
+use crate::candles::Candle;
+use crate::configuration::BacktestSettings;
+use crate::drivers::LiveEvent;
+use crate::orders::{Order, OrderId, OrderStatus, Type};
+use crate::wallets::SpotWallet;
+use log::{debug, info};
+use std::collections::{HashMap, VecDeque};
+
+// local matching engine for paper trading
+// orders are filled against the live candles with the backtester logic
+// and the resulting events are fed back into the live loop
+pub struct PaperBroker {
+    settings: BacktestSettings,
+    wallet: SpotWallet,
+    orders: Vec<Order>,
+    last_candles: HashMap<String, Candle>,
+    events: VecDeque<LiveEvent>,
+}
+
+impl PaperBroker {
+    pub fn new(settings: BacktestSettings, wallet: SpotWallet) -> Self {
+        Self {
+            settings,
+            wallet,
+            orders: Vec::new(),
+            last_candles: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn next_event(&mut self) -> Option<LiveEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn wallet(&self) -> &SpotWallet {
+        &self.wallet
+    }
+
+    pub fn outstanding_orders(&self, sym: &str) -> Vec<Order> {
+        self.orders.iter().filter(|ord| ord.symbol.symbol == sym).cloned().collect()
+    }
+
+    pub fn send_order(&mut self, order: Order) -> OrderStatus {
+        if let Err(reason) = self.check_funds(&order) {
+            info!("paper - rejecting {:?}: {}", order, reason);
+            return OrderStatus::Rejected(reason);
+        }
+        if let (Type::Market, Some(last)) = (&order.o_type, self.last_candles.get(&order.symbol.symbol)) {
+            // market orders are filled straight away at the last known price
+            let (_, end) = last.get_time_interval();
+            let cnd = Candle {
+                tstamp: end,
+                tframe: chrono::Duration::zero(),
+                open: last.close,
+                close: last.close,
+                low: last.close,
+                high: last.close,
+                volume: 0.0,
+            };
+            self.fill(&order, &cnd);
+            return OrderStatus::Accepted;
+        }
+        self.events.push_back(LiveEvent::NewOrder(order.clone()));
+        self.orders.push(order);
+        OrderStatus::Accepted
+    }
+
+    // the free balance, net of the outstanding orders, must pay for the order and its fees
+    fn check_funds(&self, order: &Order) -> Result<(), String> {
+        let last = self.last_candles.get(&order.symbol.symbol);
+        let price = match (&order.o_type, last) {
+            (Type::Limit(price), _) | (Type::StopLoss(price), _) => *price,
+            (Type::Market, Some(last)) => last.close,
+            // filled against the first candle, checked then
+            (Type::Market, None) => return Ok(()),
+        };
+        let cnd = Candle {
+            tstamp: last.map_or_else(|| chrono::Utc::now().naive_utc(), |last| last.tstamp),
+            tframe: chrono::Duration::zero(),
+            open: price,
+            close: price,
+            low: price,
+            high: price,
+            volume: 0.0,
+        };
+        let mut tx = generate_tx_from_candle(order, &cnd, &self.settings);
+        tx.avg_price = price;
+        charge_fees(&mut tx, &self.wallet, &self.settings);
+        let prices: HashMap<String, f64> = self
+            .last_candles
+            .iter()
+            .map(|(sym, cnd)| (sym.clone(), cnd.close))
+            .collect();
+        let mut free = SpotWallet {
+            assets: self.wallet.assets.clone(),
+        };
+        // the other leg of an oco pair holds the same funds
+        let others: Vec<Order> = self
+            .orders
+            .iter()
+            .filter(|ord| order.oco_ref == 0 || ord.id != order.oco_ref)
+            .cloned()
+            .collect();
+        lock_funds(&mut free, &others, &prices);
+        if can_afford(&tx, &order.symbol, &free) {
+            Ok(())
+        } else {
+            Err(format!("insufficient funds in {:?}", free.assets))
+        }
+    }
+
+    // both legs or none, the take profit one is withdrawn when the stop loss is rejected
+    pub fn send_oco_order(&mut self, mut take_profit: Order, mut stop_loss: Order) -> OrderStatus {
+        take_profit.oco_ref = stop_loss.id;
+        stop_loss.oco_ref = take_profit.id;
+        let (symbol, tp_id) = (take_profit.symbol.symbol.clone(), take_profit.id);
+        match self.send_order(take_profit) {
+            OrderStatus::Accepted => {}
+            status => return status,
+        }
+        let status = self.send_order(stop_loss);
+        if !matches!(status, OrderStatus::Accepted) {
+            self.cancel_order(symbol, tp_id);
+        }
+        status
+    }
+
+    pub fn cancel_order(&mut self, symbol: String, id: OrderId) -> OrderStatus {
+        if self.orders.iter().any(|ord| ord.id == id) {
+            self.orders.retain(|ord| ord.id != id);
+            self.events.push_back(LiveEvent::OrderCanceled(symbol, id));
+            OrderStatus::Canceled
+        } else {
+            OrderStatus::Rejected(format!("unknown order {}", id))
+        }
+    }
+
+    // the fills are queued ahead of the candle itself, as in the backtester
+    pub fn on_candle(&mut self, sym: String, interval: chrono::Duration, candle: Candle) {
+        self.match_orders(&sym, &candle);
+        self.events.push_back(LiveEvent::Candle(sym, interval, candle));
+    }
+
+    // matches the outstanding orders against a closed candle
+    pub fn match_orders(&mut self, sym: &str, candle: &Candle) {
+        self.last_candles.insert(String::from(sym), *candle);
+        let (matching, others): (Vec<Order>, Vec<Order>) =
+            self.orders.drain(0..).partition(|ord| ord.symbol.symbol == sym);
+        self.orders = others;
+        // oco siblings of the filled orders
+        let mut canceled: Vec<OrderId> = Vec::new();
+        for ord in matching {
+            if canceled.contains(&ord.id) || is_expired(&ord, candle) {
+                self.events.push_back(LiveEvent::OrderCanceled(String::from(sym), ord.id));
+            } else if order_in_candle(&ord, candle) {
+                canceled.push(ord.oco_ref);
+                self.fill(&ord, candle);
+            } else {
+                self.orders.push(ord);
+            }
+        }
+        // siblings already kept before their leg got filled
+        let (siblings, others): (Vec<Order>, Vec<Order>) =
+            self.orders.drain(0..).partition(|ord| canceled.contains(&ord.id));
+        self.orders = others;
+        for ord in siblings {
+            debug!("paper - {} canceled by its oco leg", ord.id);
+            self.events.push_back(LiveEvent::OrderCanceled(String::from(sym), ord.id));
+        }
+    }
+
+    fn fill(&mut self, ord: &Order, candle: &Candle) {
+        let mut tx = generate_tx_from_candle(ord, candle, &self.settings);
+        charge_fees(&mut tx, &self.wallet, &self.settings);
+        self.wallet.assets.entry(ord.symbol.base.clone()).or_insert(0.0);
+        self.wallet.assets.entry(ord.symbol.quote.clone()).or_insert(0.0);
+        // the price moved, or other fills spent the funds, since the order was accepted
+        if !can_afford(&tx, &ord.symbol, &self.wallet) {
+            info!("paper - canceling {}, not enough funds to fill it", ord.id);
+            self.events
+                .push_back(LiveEvent::OrderCanceled(ord.symbol.symbol.clone(), ord.id));
+            return;
+        }
+        update_wallet(&tx, &ord.symbol, &mut self.wallet);
+        info!("paper - filled {:?} {} at {}", tx.side, tx.volume, tx.avg_price);
+        self.events.push_back(LiveEvent::Transaction(tx));
+        self.events.push_back(LiveEvent::BalanceUpdate(SpotWallet {
+            assets: self.wallet.assets.clone(),
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::Side;
+    use crate::symbol::Symbol;
+    use chrono::NaiveDate;
+
+    fn broker(usdt: f64) -> PaperBroker {
+        let settings = serde_json::from_value(serde_json::json!({ "fees_perc": 0.1 })).unwrap();
+        let mut wallet = SpotWallet { assets: HashMap::new() };
+        wallet.assets.insert(String::from("USDT"), usdt);
+        PaperBroker::new(settings, wallet)
+    }
+
+    fn order(side: Side, o_type: Type, volume: f64) -> Order {
+        let mut sym = Symbol::new(String::from("BTCUSDT"));
+        sym.base = String::from("BTC");
+        sym.quote = String::from("USDT");
+        let mut ord = Order::new();
+        ord.symbol = sym;
+        ord.side = side;
+        ord.o_type = o_type;
+        ord.volume = volume;
+        ord
+    }
+
+    fn minute(price: f64) -> Candle {
+        Candle {
+            tstamp: NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            tframe: chrono::Duration::minutes(1),
+            open: price,
+            close: price,
+            low: price,
+            high: price,
+            volume: 1.0,
+        }
+    }
+
+    #[test]
+    fn rejects_orders_beyond_the_free_balance() {
+        let mut paper = broker(1000.0);
+        let first = paper.send_order(order(Side::Buy, Type::Limit(1000.0), 0.5));
+        assert!(matches!(first, OrderStatus::Accepted));
+        // the first order locks half of the wallet
+        let second = paper.send_order(order(Side::Buy, Type::Limit(1000.0), 0.6));
+        assert!(matches!(second, OrderStatus::Rejected(_)));
+        // nothing to sell
+        let sell = paper.send_order(order(Side::Sell, Type::Limit(1000.0), 0.1));
+        assert!(matches!(sell, OrderStatus::Rejected(_)));
+        assert_eq!(paper.outstanding_orders("BTCUSDT").len(), 1);
+    }
+
+    #[test]
+    fn market_orders_must_pay_the_fees() {
+        let mut paper = broker(1000.0);
+        paper.match_orders("BTCUSDT", &minute(1000.0));
+        let all_in = paper.send_order(order(Side::Buy, Type::Market, 1.0));
+        assert!(matches!(all_in, OrderStatus::Rejected(_)));
+        let fits = paper.send_order(order(Side::Buy, Type::Market, 0.99));
+        assert!(matches!(fits, OrderStatus::Accepted));
+        assert!(paper.wallet().assets["USDT"] >= 0.0);
+        assert!((paper.wallet().assets["BTC"] - 0.99).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unpaid_fills_are_canceled() {
+        let mut paper = broker(1000.0);
+        paper.send_order(order(Side::Buy, Type::Limit(1000.0), 0.9));
+        // spent by hand since the order was accepted
+        paper.wallet.assets.insert(String::from("USDT"), 100.0);
+        paper.match_orders("BTCUSDT", &minute(900.0));
+        assert!(matches!(paper.next_event(), Some(LiveEvent::NewOrder(_))));
+        assert!(matches!(paper.next_event(), Some(LiveEvent::OrderCanceled(_, _))));
+        assert!(paper.next_event().is_none());
+        assert_eq!(paper.wallet().assets["USDT"], 100.0);
+    }
+
+    #[test]
+    fn oco_legs_are_sent_together_or_not_at_all() {
+        let mut paper = broker(0.0);
+        paper.wallet.assets.insert(String::from("BTC"), 1.0);
+        let status = paper.send_oco_order(
+            order(Side::Sell, Type::Limit(1100.0), 1.0),
+            order(Side::Sell, Type::StopLoss(900.0), 1.0),
+        );
+        assert!(matches!(status, OrderStatus::Accepted));
+        assert_eq!(paper.outstanding_orders("BTCUSDT").len(), 2);
+
+        // the take profit leg is rejected, the stop loss one is not sent
+        let mut paper = broker(0.0);
+        paper.wallet.assets.insert(String::from("BTC"), 1.0);
+        let status = paper.send_oco_order(
+            order(Side::Sell, Type::Limit(1100.0), 2.0),
+            order(Side::Sell, Type::StopLoss(900.0), 1.0),
+        );
+        assert!(matches!(status, OrderStatus::Rejected(_)));
+        assert!(paper.outstanding_orders("BTCUSDT").is_empty());
+        assert!(paper.next_event().is_none());
+
+        // the stop loss leg is rejected, the take profit one is withdrawn
+        let mut paper = broker(0.0);
+        paper.wallet.assets.insert(String::from("BTC"), 1.0);
+        let status = paper.send_oco_order(
+            order(Side::Sell, Type::Limit(1100.0), 1.0),
+            order(Side::Sell, Type::StopLoss(900.0), 2.0),
+        );
+        assert!(matches!(status, OrderStatus::Rejected(_)));
+        assert!(paper.outstanding_orders("BTCUSDT").is_empty());
+        assert!(matches!(paper.next_event(), Some(LiveEvent::NewOrder(_))));
+        assert!(matches!(paper.next_event(), Some(LiveEvent::OrderCanceled(_, _))));
+    }
+}
@@ -1,5 +1,6 @@
-use crate::orders::{Order, Transaction};
+use crate::orders::{Order, OrderId, Transaction};
 use crate::wallets::SpotWallet;
+use chrono::NaiveDateTime;
 use std::collections::HashMap;
 
 #[derive(Debug)]
@@ -12,6 +13,26 @@ pub struct Statistics {
     pub highest_balance: f64,
     pub tx_history: Vec<Transaction>,
     pub trade_win_loss: Vec<f64>,
+    // fees paid, valued in the quote asset
+    pub fees: f64,
+    tx_fees: HashMap<OrderId, f64>,
+    pub equity: Vec<(NaiveDateTime, f64)>,
+}
+
+// the figures of a backtest run
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Summary {
+    pub orders: usize,
+    pub gain_perc: f64,
+    pub lowest_balance: f64,
+    pub highest_balance: f64,
+    pub transactions: usize,
+    pub trades: usize,
+    pub wins: usize,
+    pub losses: usize,
+    pub avg_win: f64,
+    pub avg_loss: f64,
+    pub fees: f64,
 }
 
 impl Statistics {
@@ -25,10 +46,13 @@ impl Statistics {
             highest_balance: balance_start,
             tx_history: Vec::new(),
             trade_win_loss: Vec::new(),
+            fees: 0.0,
+            tx_fees: HashMap::new(),
+            equity: Vec::new(),
         }
     }
 
-    pub fn report(&self) -> String {
+    pub fn summary(&self) -> Summary {
         let wins = self.trade_win_loss.iter().fold((0.0, 0), |(tot, count), trade| {
             if trade.is_sign_positive() {
                 return (tot + trade, count + 1);
@@ -41,8 +65,23 @@ impl Statistics {
             }
             (tot, count)
         });
-        let avg_win = if wins.1 == 0 { 0.0 } else { wins.0 / wins.1 as f64 };
-        let avg_loss = if losses.1 == 0 { 0.0 } else { losses.0 / losses.1 as f64 };
+        Summary {
+            orders: self.orders,
+            gain_perc: (self.balance - self.balance_start) / self.balance_start * 100.0,
+            lowest_balance: self.lowest_balance,
+            highest_balance: self.highest_balance,
+            transactions: self.tx_history.len(),
+            trades: self.trade_win_loss.len(),
+            wins: wins.1,
+            losses: losses.1,
+            avg_win: if wins.1 == 0 { 0.0 } else { wins.0 / wins.1 as f64 },
+            avg_loss: if losses.1 == 0 { 0.0 } else { losses.0 / losses.1 as f64 },
+            fees: self.fees,
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let summary = self.summary();
         format!(
             "num orders: {}
                  gain %: {}
@@ -50,23 +89,26 @@ impl Statistics {
                  total transactions: {}
                  total trades : {}
                  wins/losses: {:.3}/{:.3}
-                 avg win/loss: {:.3}/{:.3}",
-            self.orders,
-            (self.balance - self.balance_start) / self.balance_start * 100.0,
-            self.lowest_balance, self.highest_balance,
-            self.tx_history.len(),
-            self.trade_win_loss.len(),
-            wins.1,
-            losses.1,
-            avg_win,
-            avg_loss,
+                 avg win/loss: {:.3}/{:.3}
+                 fees: {:.3}",
+            summary.orders,
+            summary.gain_perc,
+            summary.lowest_balance, summary.highest_balance,
+            summary.transactions,
+            summary.trades,
+            summary.wins,
+            summary.losses,
+            summary.avg_win,
+            summary.avg_loss,
+            summary.fees,
         )
     }
-    pub fn update_with_last_prices(&mut self, wallet: &SpotWallet, prices: &HashMap<String, f64>) {
+    pub fn update_with_last_prices(&mut self, tstamp: NaiveDateTime, wallet: &SpotWallet, prices: &HashMap<String, f64>) {
         let balance = wallet.assets.iter().fold(0.0, |balance, (sym, price)| {
             balance + prices.get(sym).expect("coin in wallet missing from price list") * price
         });
         self.balance = balance;
+        self.equity.push((tstamp, balance));
         if balance < self.lowest_balance {
             self.lowest_balance = balance;
         }
@@ -74,15 +116,21 @@ impl Statistics {
             self.highest_balance = balance
         }
     }
-    pub fn update_with_transaction(&mut self, tx: &Transaction) {
+    // fees: value of the transaction fees in the quote asset
+    pub fn update_with_transaction(&mut self, tx: &Transaction, fees: f64) {
         self.tx_history.push(tx.clone());
+        self.fees += fees;
+        self.tx_fees.insert(tx.order.id, fees);
         if tx.order.tx_ref != 0 {
             let orig_tx = self
                 .tx_history
                 .iter()
                 .find(|past_tx| past_tx.order.id == tx.order.tx_ref)
                 .expect("orig_tx");
-            let perc = (tx.avg_price - orig_tx.avg_price) / orig_tx.avg_price;
+            // the trade is charged with the share of the opening fees matching the closed volume
+            let orig_fees = self.tx_fees.get(&orig_tx.order.id).unwrap_or(&0.0) * tx.volume / orig_tx.volume;
+            let cost = orig_tx.avg_price * tx.volume + orig_fees;
+            let perc = (tx.avg_price * tx.volume - fees - cost) / cost;
             self.trade_win_loss.push(perc);
         }
     }
@@ -92,4 +140,8 @@ impl Statistics {
     pub fn update_with_expired_order(&mut self, _ord: &Order) {
         self.canceled_orders += 1;
     }
+    // not filled for lack of funds
+    pub fn update_with_rejected_order(&mut self, _ord: &Order) {
+        self.canceled_orders += 1;
+    }
 }
@@ -1,29 +1,209 @@
 use super::candles;
-use super::orders::Transaction;
+use super::error;
+use super::file_storage;
+use super::orders::{order_instance, Order, OrderId, Side, Transaction};
+use super::symbol::Symbol;
+use async_trait::async_trait;
 use chrono::{Duration, NaiveDateTime};
 use futures_util::TryFutureExt;
-use log::{debug, error};
+use log::{debug, error, info};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-use tokio_postgres::{row, tls, Client, Error, NoTls, Socket};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::Type;
+use tokio_postgres::{row, tls, Client, Error, NoTls, Socket, Statement};
 
 type Connection = tokio_postgres::Connection<Socket, <NoTls as tls::MakeTlsConnect<Socket>>::Stream>;
 
+// versioned schema changes, applied in order and recorded in schema_migrations
+// never edit an applied migration, add a new one
+fn migrations() -> Vec<(i32, &'static str, String)> {
+    vec![
+        (
+            1,
+            "transactions",
+            String::from(
+                "CREATE TABLE IF NOT EXISTS transactions (
+                exchange varchar(32) NOT NULL,
+                symbol varchar(16) NOT NULL,
+                tstamp timestamp NOT NULL,
+                side varchar(16) NOT NULL,
+                price float4 NOT NULL,
+                volume float4 NOT NULL,
+                id bigint NOT NULL,
+                fees float4 NOT NULL,
+                fees_asset varchar(16) NOT NULL,
+                reference bigint NULL,
+                CONSTRAINT transactions_pkey PRIMARY KEY (exchange, symbol, tstamp, id)
+                );
+                CREATE TABLE IF NOT EXISTS paper_transactions (LIKE transactions INCLUDING ALL);",
+            ),
+        ),
+        (2, "binance candles", candle_table_ddl("binance")),
+        (
+            3,
+            "empty ranges",
+            String::from(
+                "CREATE TABLE IF NOT EXISTS empty_ranges (
+                exchange varchar(32) NOT NULL,
+                symbol varchar(16) NOT NULL,
+                tstamp_from timestamp NOT NULL,
+                tstamp_to timestamp NOT NULL,
+                CONSTRAINT empty_ranges_pkey PRIMARY KEY (exchange, symbol, tstamp_from)
+                );",
+            ),
+        ),
+        (
+            4,
+            "strategy snapshots",
+            String::from(
+                "CREATE TABLE IF NOT EXISTS strategy_snapshots (
+                source varchar(32) NOT NULL,
+                name varchar(128) NOT NULL,
+                fingerprint text NOT NULL,
+                state jsonb NOT NULL,
+                tstamp timestamp NOT NULL,
+                CONSTRAINT strategy_snapshots_pkey PRIMARY KEY (source, name)
+                );",
+            ),
+        ),
+    ]
+}
+
+// one candle table per exchange, named after it
+fn candle_table_ddl(exchange: &str) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {table} (
+        symbol varchar(16) NOT NULL,
+        tstamp timestamp NOT NULL,
+        \"open\" float4 NULL,
+        low float4 NULL,
+        high float4 NULL,
+        \"close\" float4 NULL,
+        volume float4 NULL,
+        CONSTRAINT {pkey} PRIMARY KEY (symbol, tstamp)
+        );",
+        table = table_name(exchange),
+        pkey = table_name(&format!("{}_pkey", exchange)),
+    )
+}
+
+// brings the database at host up to the last migration, returns the migrations applied
+pub async fn migrate(host: &str) -> Result<usize, Error> {
+    // candle files need no schema
+    if host.starts_with("file://") {
+        return Ok(0);
+    }
+    let (mut client, connection) = tokio_postgres::connect(host, NoTls).await?;
+    actix_rt::Arbiter::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("connection error: {}", e);
+        }
+    });
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version integer PRIMARY KEY,
+            name varchar(64) NOT NULL,
+            applied timestamp NOT NULL DEFAULT now()
+            );",
+        )
+        .await?;
+    let current: i32 = client
+        .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", &[])
+        .await?
+        .get(0);
+    let mut applied = 0;
+    for (version, name, ddl) in migrations().into_iter().filter(|(version, _, _)| *version > current) {
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(&ddl).await?;
+        transaction
+            .execute("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)", &[&version, &name])
+            .await?;
+        transaction.commit().await?;
+        info!("storage - applied migration {} {}", version, name);
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+// candle storages, picked by the scheme of the candle_storage url
+#[async_trait(?Send)]
+pub trait CandleStore {
+    async fn store(&self, exchange: &str, symbol: &str, candles: &[candles::Candle]) -> Result<u64, error::Error>;
+    // candles within [start, end]
+    async fn check(&self, exc: &str, sym: &str, start: &NaiveDateTime, end: &NaiveDateTime) -> usize;
+    // all the 1m candles within [start, end), oldest first
+    async fn get_minutes(&self, exc: &str, sym: &str, start: &NaiveDateTime, end: &NaiveDateTime) -> Vec<candles::Candle>;
+    // first candle within [start, end] going as low as price
+    async fn find_lower(
+        &self,
+        exc: &str,
+        sym: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        price: f64,
+    ) -> Option<NaiveDateTime>;
+    // first candle within [start, end] going as high as price
+    async fn find_higher(
+        &self,
+        exc: &str,
+        sym: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        price: f64,
+    ) -> Option<NaiveDateTime>;
+
+    // the newest candle stored
+    async fn last_tstamp(&self, exc: &str, sym: &str) -> Option<NaiveDateTime>;
+    // [from, to) has no candles on the exchange, i.e. during maintenance, no need to fetch it again
+    async fn mark_empty(&self, exc: &str, sym: &str, from: &NaiveDateTime, to: &NaiveDateTime) -> Result<(), error::Error>;
+    // empty ranges overlapping [start, end), sorted
+    async fn empty_ranges(
+        &self,
+        exc: &str,
+        sym: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+    ) -> Vec<(NaiveDateTime, NaiveDateTime)>;
+
+    // num candles of the interval from start, aggregated from the 1m ones like in backtests
+    async fn get(
+        &self,
+        exc: &str,
+        sym: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        interval: &Duration,
+        num: usize,
+    ) -> Vec<candles::Candle> {
+        let minutes = self.get_minutes(exc, sym, &candles::bucket_start(start, interval), end).await;
+        candles::aggregate(&minutes, interval).into_iter().take(num).collect()
+    }
+}
+
+// file:///some/dir for candle files, postgres urls or key=value connection strings otherwise
+pub async fn create_candle_store(url: &str) -> Result<Box<dyn CandleStore>, error::Error> {
+    if let Some(dir) = url.strip_prefix("file://") {
+        return Ok(Box::new(file_storage::Candles::new(dir)));
+    }
+    match url.find("://").map(|idx| &url[..idx]) {
+        None | Some("postgres") | Some("postgresql") => {
+            migrate(url).await.map_err(|e| error::Error::Unexpected(Box::new(e)))?;
+            Ok(Box::new(Candles::new(url).await))
+        }
+        Some(scheme) => Err(error::Error::ErrNotFound(format!("unknown candle storage scheme {}", scheme))),
+    }
+}
+
 pub struct Candles {
     client: Client,
+    statements: RefCell<HashMap<String, Statement>>,
+    // candle tables known to exist
+    tables: RefCell<HashSet<String>>,
 }
 
-/*
-CREATE TABLE binance (
-symbol varchar(16) NOT NULL,
-tstamp timestamp NOT NULL,
-"open" float4 NULL,
-low float4 NULL,
-high float4 NULL,
-"close" float4 NULL,
-volume float4 NULL,
-CONSTRAINT binance_pkey PRIMARY KEY (symbol, tstamp)
-);
-*/
 impl Candles {
     pub async fn new(host: &str) -> Self {
         let (client, connection) = tokio_postgres::connect(host, NoTls).await.expect("when connecting to postgres");
@@ -32,104 +212,130 @@ impl Candles {
                 eprintln!("connection error: {}", e);
             }
         });
-        Self { client }
+        Self {
+            client,
+            statements: RefCell::new(HashMap::new()),
+            tables: RefCell::new(HashSet::new()),
+        }
     }
 
-    pub async fn store(&self, exchange: &str, symbol: &str, candles: &[candles::Candle]) -> Result<u64, Error> {
-        let insert_statement = format!("INSERT INTO {} (symbol, tstamp, open, low, high, close, volume) VALUES", exchange);
-        let mut value_statements: String = candles.iter().fold(String::new(), |statement, cnd| {
-            format!(
-                "{},('{}','{}',{},{},{},{},{})",
-                statement, symbol, cnd.tstamp, cnd.open, cnd.low, cnd.high, cnd.close, cnd.volume
-            )
-        });
-        value_statements.remove(0);
-        let statement = format!("{} {} ON CONFLICT(symbol, tstamp) DO NOTHING;", insert_statement, &value_statements);
-        self.client.execute(statement.as_str(), &[]).await
+    // new exchanges get their candle table on the first import
+    async fn create_table(&self, exchange: &str) -> Result<(), Error> {
+        if self.tables.borrow().contains(exchange) {
+            return Ok(());
+        }
+        self.client.batch_execute(&candle_table_ddl(exchange)).await?;
+        self.tables.borrow_mut().insert(String::from(exchange));
+        Ok(())
     }
 
-    pub async fn check(&self, exc: &str, sym: &str, start: &NaiveDateTime, end: &NaiveDateTime) -> usize {
-        let statement = format!(
-            "SELECT COUNT(*) AS counter
-            FROM {exchange}
-            WHERE symbol = '{symbol}' AND tstamp BETWEEN '{start}' AND '{end}'",
-            exchange = exc,
-            symbol = sym,
-            start = start.format("%Y-%m-%d %H:%M:%S"),
-            end = end.format("%Y-%m-%d %H:%M:%S"),
-        );
-        let res = self.client.query_one(statement.as_str(), &[]).await.expect("no returned value");
+    async fn prepare(&self, query: &str) -> Result<Statement, Error> {
+        prepare_cached(&self.client, &self.statements, query).await
+    }
+
+    // candles are copied into a temporary table first, COPY can't skip the ones already stored
+    async fn copy_candles(&self, exchange: &str, symbol: &str, candles: &[candles::Candle]) -> Result<u64, Error> {
+        self.create_table(exchange).await?;
+        let table = table_name(exchange);
+        let import_table = table_name(&format!("import_{}", exchange));
+        self.client
+            .batch_execute(&format!(
+                "CREATE TEMPORARY TABLE IF NOT EXISTS {import} (LIKE {table} INCLUDING DEFAULTS);
+                TRUNCATE {import};",
+                import = import_table,
+                table = table,
+            ))
+            .await?;
+        let copy = self
+            .prepare(&format!(
+                "COPY {} (symbol, tstamp, open, low, high, close, volume) FROM STDIN BINARY",
+                import_table
+            ))
+            .await?;
+        let sink = self.client.copy_in(&copy).await?;
+        let types = [
+            Type::VARCHAR,
+            Type::TIMESTAMP,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+        ];
+        let writer = BinaryCopyInWriter::new(sink, &types);
+        futures_util::pin_mut!(writer);
+        for cnd in candles {
+            writer
+                .as_mut()
+                .write(&[
+                    &symbol,
+                    &cnd.tstamp,
+                    &(cnd.open as f32),
+                    &(cnd.low as f32),
+                    &(cnd.high as f32),
+                    &(cnd.close as f32),
+                    &(cnd.volume as f32),
+                ])
+                .await?;
+        }
+        writer.finish().await?;
+        let insert = self
+            .prepare(&format!(
+                "INSERT INTO {} SELECT * FROM {} ON CONFLICT(symbol, tstamp) DO NOTHING",
+                table, import_table
+            ))
+            .await?;
+        self.client.execute(&insert, &[]).await
+    }
+}
+
+#[async_trait(?Send)]
+impl CandleStore for Candles {
+    async fn store(&self, exchange: &str, symbol: &str, candles: &[candles::Candle]) -> Result<u64, error::Error> {
+        self.copy_candles(exchange, symbol, candles)
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))
+    }
+
+    async fn check(&self, exc: &str, sym: &str, start: &NaiveDateTime, end: &NaiveDateTime) -> usize {
+        let statement = self
+            .prepare(&format!(
+                "SELECT COUNT(*) AS counter FROM {} WHERE symbol = $1 AND tstamp BETWEEN $2 AND $3",
+                table_name(exc)
+            ))
+            .await
+            .expect("in preparing candle count");
+        let res = self
+            .client
+            .query_one(&statement, &[&sym, start, end])
+            .await
+            .expect("no returned value");
         res.get::<usize, i64>(0) as usize
     }
 
-    pub async fn get(
-        &self,
-        exc: &str,
-        sym: &str,
-        start: &NaiveDateTime,
-        end: &NaiveDateTime,
-        interval: &Duration,
-        num: usize,
-    ) -> Vec<candles::Candle> {
-        let statement: String;
-        let mut chunk_size: usize;
-        let mut tframe: Duration;
-        if interval.num_hours() == 0 {
-            tframe = Duration::minutes(1);
-            chunk_size = interval.num_minutes() as usize;
-            statement = format!(
+    // all the 1m candles within [start, end), oldest first
+    async fn get_minutes(&self, exc: &str, sym: &str, start: &NaiveDateTime, end: &NaiveDateTime) -> Vec<candles::Candle> {
+        let statement = self
+            .prepare(&format!(
                 "SELECT tstamp, open, low, high, close, volume
-                FROM {exchange} WHERE symbol = '{symbol}' AND tstamp BETWEEN '{start_time}' AND '{end_time}'
-                ORDER BY 1
-                LIMIT {num}",
-                exchange = exc,
-                symbol = sym,
-                start_time = start.format("%Y-%m-%d %H:%M:%S"),
-                end_time = end.format("%Y-%m-%d %H:%M:%S"),
-                num = num * chunk_size
-            );
-        } else {
-            tframe = Duration::hours(1);
-            chunk_size = interval.num_hours() as usize;
-            let mut date_part = "hour";
-            if interval.num_days() > 0 {
-                tframe = Duration::days(1);
-                chunk_size = interval.num_days() as usize;
-                date_part = "day";
-            }
-            statement = format!(
-                "SELECT tstamp_trunc AS tstamp, open, close, MIN(low) AS low, MAX(high) AS high, SUM(volume) AS volume
-                FROM ( SELECT tstamp, tstamp_trunc, low, high, volume,
-                    FIRST_VALUE(open) OVER(PARTITION BY tstamp_trunc ORDER BY tstamp) AS open,
-                    FIRST_VALUE(close) OVER(PARTITION BY tstamp_trunc ORDER BY tstamp DESC) AS close
-                    FROM (SELECT tstamp, DATE_TRUNC('{date_part}', tstamp) AS tstamp_trunc, open, low, high, close, volume
-                        FROM {exchange}
-                        WHERE symbol = '{symbol}' AND tstamp BETWEEN '{start_time}' AND '{end_time}'
-                    ) AS t1
-                ) AS t2
-                GROUP BY 1, 2, 3
-                ORDER BY 1
-                LIMIT {num}",
-                exchange = exc,
-                symbol = sym,
-                start_time = start.format("%Y-%m-%d %H:%M:%S"),
-                end_time = end.format("%Y-%m-%d %H:%M:%S"),
-                num = num * chunk_size,
-                date_part = date_part,
-            );
-        }
+                FROM {}
+                WHERE symbol = $1 AND tstamp >= $2 AND tstamp < $3
+                ORDER BY tstamp",
+                table_name(exc)
+            ))
+            .await
+            .expect("in preparing for minutes");
+        let tframe = Duration::minutes(1);
         self.client
-            .query(statement.as_str(), &[])
+            .query(&statement, &[&sym, start, end])
             .await
-            .expect("in querying for candles")
+            .expect("in querying for minutes")
             .drain(0..)
             .map(|row| row_to_candle(row, &tframe))
-            .collect::<Vec<candles::Candle>>()
-            .chunks(chunk_size)
-            .map(group_candles)
             .collect()
     }
-    pub async fn find_lower(
+
+    async fn find_lower(
         &self,
         exc: &str,
         sym: &str,
@@ -137,28 +343,25 @@ impl Candles {
         end: &NaiveDateTime,
         price: f64,
     ) -> Option<chrono::NaiveDateTime> {
-        let statement = format!(
-            "SELECT tstamp
-            FROM {exchange}
-            WHERE symbol = '{symbol}'
-                AND low <= {price}
-                AND tstamp BETWEEN '{start_time}' AND '{end_time}'
-            ORDER BY tstamp LIMIT 1",
-            exchange = exc,
-            symbol = sym,
-            start_time = start.format("%Y-%m-%d %H:%M:%S"),
-            end_time = end.format("%Y-%m-%d %H:%M:%S"),
-            price = price
-        );
+        let statement = self
+            .prepare(&format!(
+                "SELECT tstamp
+                FROM {}
+                WHERE symbol = $1 AND low <= $2 AND tstamp BETWEEN $3 AND $4
+                ORDER BY tstamp LIMIT 1",
+                table_name(exc)
+            ))
+            .await
+            .expect("in preparing for lower");
         self.client
-            .query(statement.as_str(), &[])
+            .query(&statement, &[&sym, &(price as f32), start, end])
             .await
             .expect("in querying for lower")
             .first()
             .map(|row| row.get(0))
     }
 
-    pub async fn find_higher(
+    async fn find_higher(
         &self,
         exc: &str,
         sym: &str,
@@ -166,26 +369,99 @@ impl Candles {
         end: &NaiveDateTime,
         price: f64,
     ) -> Option<chrono::NaiveDateTime> {
-        let statement = format!(
-            "SELECT tstamp
-            FROM {exchange}
-            WHERE symbol = '{symbol}'
-                AND high >= {price}
-                AND tstamp BETWEEN '{start_time}' AND '{end_time}'
-            ORDER BY tstamp LIMIT 1",
-            exchange = exc,
-            symbol = sym,
-            start_time = start.format("%Y-%m-%d %H:%M:%S"),
-            end_time = end.format("%Y-%m-%d %H:%M:%S"),
-            price = price
-        );
+        let statement = self
+            .prepare(&format!(
+                "SELECT tstamp
+                FROM {}
+                WHERE symbol = $1 AND high >= $2 AND tstamp BETWEEN $3 AND $4
+                ORDER BY tstamp LIMIT 1",
+                table_name(exc)
+            ))
+            .await
+            .expect("in preparing for higher");
         self.client
-            .query(statement.as_str(), &[])
+            .query(&statement, &[&sym, &(price as f32), start, end])
             .await
-            .expect("in querying for lower")
+            .expect("in querying for higher")
             .first()
             .map(|row| row.get(0))
     }
+
+    async fn last_tstamp(&self, exc: &str, sym: &str) -> Option<NaiveDateTime> {
+        self.create_table(exc).await.expect("in creating the candle table");
+        let statement = self
+            .prepare(&format!("SELECT MAX(tstamp) FROM {} WHERE symbol = $1", table_name(exc)))
+            .await
+            .expect("in preparing for the last candle");
+        self.client
+            .query_one(&statement, &[&sym])
+            .await
+            .expect("in querying for the last candle")
+            .get(0)
+    }
+
+    async fn mark_empty(&self, exc: &str, sym: &str, from: &NaiveDateTime, to: &NaiveDateTime) -> Result<(), error::Error> {
+        let statement = self
+            .prepare(
+                "INSERT INTO empty_ranges (exchange, symbol, tstamp_from, tstamp_to) VALUES ($1, $2, $3, $4)
+                ON CONFLICT(exchange, symbol, tstamp_from) DO UPDATE
+                SET tstamp_to = GREATEST(empty_ranges.tstamp_to, EXCLUDED.tstamp_to)",
+            )
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))?;
+        self.client
+            .execute(&statement, &[&exc, &sym, from, to])
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))?;
+        Ok(())
+    }
+
+    async fn empty_ranges(
+        &self,
+        exc: &str,
+        sym: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+    ) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        let statement = self
+            .prepare(
+                "SELECT tstamp_from, tstamp_to
+                FROM empty_ranges
+                WHERE exchange = $1 AND symbol = $2 AND tstamp_to > $3 AND tstamp_from < $4
+                ORDER BY tstamp_from",
+            )
+            .await
+            .expect("in preparing for empty ranges");
+        self.client
+            .query(&statement, &[&exc, &sym, start, end])
+            .await
+            .expect("in querying for empty ranges")
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect()
+    }
+}
+
+// table names can't be bound, they come from the config so only plain names are accepted
+fn table_name(name: &str) -> String {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        panic!("invalid table name {}", name);
+    }
+    format!("\"{}\"", name)
+}
+
+// statements are prepared once per connection
+async fn prepare_cached(
+    client: &Client,
+    statements: &RefCell<HashMap<String, Statement>>,
+    query: &str,
+) -> Result<Statement, Error> {
+    if let Some(statement) = statements.borrow().get(query) {
+        return Ok(statement.clone());
+    }
+    let statement = client.prepare(query).await?;
+    statements.borrow_mut().insert(String::from(query), statement.clone());
+    Ok(statement)
 }
 
 fn row_to_candle(row: row::Row, tframe: &chrono::Duration) -> candles::Candle {
@@ -224,48 +500,27 @@ fn row_to_candle(row: row::Row, tframe: &chrono::Duration) -> candles::Candle {
     cnd
 }
 
-fn group_candles(cnds: &[candles::Candle]) -> candles::Candle {
-    let cnd = candles::Candle {
-        tstamp: cnds.first().expect("can't be size 0").tstamp,
-        tframe: cnds.first().expect("can't be size 0").tframe * cnds.len() as i32,
-        low: std::f64::MAX,
-        high: std::f64::MIN,
-        open: cnds.first().expect("can't be size 0").open,
-        close: cnds.last().expect("can't be size 0").close,
-        volume: 0.0,
-    };
-    cnds.iter().fold(cnd, |mut folded, cnd| {
-        folded.low = folded.low.min(cnd.low);
-        folded.high = folded.high.max(cnd.high);
-        folded.volume += cnd.volume;
-        folded
-    })
+// transaction storages, the live loop stores its fills, open positions and strategy snapshots in one
+#[async_trait(?Send)]
+pub trait TransactionStore {
+    async fn store(&mut self, exchange: &str, tx: &Transaction) -> Result<u64, error::Error>;
+    // buys with no sell referencing them, oldest first
+    async fn open_positions(&self, exchange: &str, sym: &Symbol) -> Result<Vec<Transaction>, error::Error>;
+    async fn save_snapshot(&self, name: &str, fingerprint: &str, state: &serde_json::Value) -> Result<u64, error::Error>;
+    // a snapshot taken with other settings is ignored, the strategy starts fresh
+    async fn load_snapshot(&self, name: &str, fingerprint: &str) -> Result<Option<serde_json::Value>, error::Error>;
 }
 
 pub struct Transactions {
     host: String,
+    table: String,
     client: Client,
+    statements: RefCell<HashMap<String, Statement>>,
     sender: std::sync::mpsc::Sender<Connection>,
 }
 
-/*
-CREATE TABLE public.transactions (
-exchange varchar(32) NOT NULL,
-symbol varchar(16) NOT NULL,
-tstamp timestamp NOT NULL,
-side varchar(16) NOT NULL,
-price float4 NOT NULL,
-volume float4 NOT NULL,
-id bigint NOT NULL,
-fees float4 NOT NULL,
-fees_asset varchar(16) NOT NULL
-reference bigint NULL,
-CONSTRAINT transactions_pkey PRIMARY KEY (exchange, symbol, tstamp, id)
-);
-*/
-
 impl Transactions {
-    pub async fn new(host: &str, arbiter: &mut actix_rt::Arbiter) -> Self {
+    pub async fn new(host: &str, table: &str, arbiter: &mut actix_rt::Arbiter) -> Self {
         let (sender, receiver) = channel::<Connection>();
         let f = Box::pin(async move {
             loop {
@@ -288,34 +543,199 @@ impl Transactions {
         sender.send(connection).unwrap();
         Self {
             host: String::from(host),
+            table: String::from(table),
             client,
+            statements: RefCell::new(HashMap::new()),
             sender,
         }
     }
+}
 
-    pub async fn store(&mut self, exchange: &str, tx: &Transaction) -> Result<u64, Error> {
-        let statement = format!(
-            "INSERT INTO transactions (exchange, symbol, tstamp, side, price, volume, id, fees, fees_asset, reference)
-                                VALUES ('{}', '{}', '{}', '{}', {}, {}, {}, {}, '{}', {})",
-            exchange,
-            tx.symbol,
-            tx.tstamp,
-            tx.side.to_string(),
-            tx.avg_price,
-            tx.volume,
-            tx.order.id,
-            tx.fees,
-            tx.fees_asset,
-            tx.order.tx_ref,
+#[async_trait(?Send)]
+impl TransactionStore for Transactions {
+    async fn open_positions(&self, exchange: &str, sym: &Symbol) -> Result<Vec<Transaction>, error::Error> {
+        let query = format!(
+            "SELECT b.tstamp, b.price, b.volume, b.id, b.fees, b.fees_asset, b.reference
+            FROM {table} b
+            WHERE b.exchange = $1 AND b.symbol = $2 AND b.side = $3
+                AND NOT EXISTS (
+                    SELECT 1 FROM {table} s
+                    WHERE s.exchange = b.exchange AND s.symbol = b.symbol AND s.side = $4 AND s.reference = b.id
+                )
+            ORDER BY b.tstamp",
+            table = table_name(&self.table)
         );
+        let statement = prepare_cached(&self.client, &self.statements, &query)
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))?;
+        let rows = self
+            .client
+            .query(
+                &statement,
+                &[&exchange, &sym.symbol, &Side::Buy.to_string(), &Side::Sell.to_string()],
+            )
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let tstamp: NaiveDateTime = row.get(0);
+                let avg_price = row.get::<usize, f32>(1) as f64;
+                let volume = row.get::<usize, f32>(2) as f64;
+                Transaction {
+                    symbol: sym.symbol.clone(),
+                    side: Side::Buy,
+                    avg_price,
+                    volume,
+                    tstamp,
+                    fees: row.get::<usize, f32>(4) as f64,
+                    fees_asset: row.get(5),
+                    order: Order {
+                        tstamp: Some(tstamp),
+                        exchange: String::from(exchange),
+                        symbol: sym.clone(),
+                        side: Side::Buy,
+                        volume,
+                        id: row.get::<usize, i64>(3) as OrderId,
+                        tx_ref: row.get::<usize, Option<i64>>(6).unwrap_or(0) as OrderId,
+                        owner: order_instance(row.get::<usize, i64>(3) as OrderId),
+                        ..Order::new()
+                    },
+                }
+            })
+            .collect())
+    }
+
+    // snapshots are kept apart for live and paper trading, by the transaction table they go with
+    async fn save_snapshot(&self, name: &str, fingerprint: &str, state: &serde_json::Value) -> Result<u64, error::Error> {
+        let query = "INSERT INTO strategy_snapshots (source, name, fingerprint, state, tstamp)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (source, name) DO UPDATE
+            SET fingerprint = EXCLUDED.fingerprint, state = EXCLUDED.state, tstamp = EXCLUDED.tstamp";
+        let statement = prepare_cached(&self.client, &self.statements, query)
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))?;
+        let now = chrono::Utc::now().naive_utc();
+        self.client
+            .execute(&statement, &[&self.table, &name, &fingerprint, state, &now])
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))
+    }
+
+    async fn load_snapshot(&self, name: &str, fingerprint: &str) -> Result<Option<serde_json::Value>, error::Error> {
+        let query = "SELECT fingerprint, state, tstamp FROM strategy_snapshots WHERE source = $1 AND name = $2";
+        let statement = prepare_cached(&self.client, &self.statements, query)
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))?;
+        let row = match self
+            .client
+            .query_opt(&statement, &[&self.table, &name])
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let stored: String = row.get(0);
+        let tstamp: NaiveDateTime = row.get(2);
+        if stored != fingerprint {
+            info!("{} - settings changed since the snapshot of {}, not restoring it", name, tstamp);
+            return Ok(None);
+        }
+        info!("{} - restoring the snapshot of {}", name, tstamp);
+        Ok(Some(row.get(1)))
+    }
+
+    async fn store(&mut self, exchange: &str, tx: &Transaction) -> Result<u64, error::Error> {
         if self.client.is_closed() {
             let (client, connection) = tokio_postgres::connect(&self.host, NoTls)
                 .await
                 .expect("when connecting to postgres");
             self.sender.send(connection).unwrap();
             self.client = client;
+            self.statements.borrow_mut().clear();
         }
-        debug!("Transaction::store - {}", statement);
-        self.client.execute(statement.as_str(), &[]).await
+        let query = format!(
+            "INSERT INTO {} (exchange, symbol, tstamp, side, price, volume, id, fees, fees_asset, reference)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            table_name(&self.table)
+        );
+        let statement = prepare_cached(&self.client, &self.statements, &query)
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))?;
+        debug!("Transaction::store - {:?}", tx);
+        self.client
+            .execute(
+                &statement,
+                &[
+                    &exchange,
+                    &tx.symbol,
+                    &tx.tstamp,
+                    &tx.side.to_string(),
+                    &(tx.avg_price as f32),
+                    &(tx.volume as f32),
+                    &(tx.order.id as i64),
+                    &(tx.fees as f32),
+                    &tx.fees_asset,
+                    &(tx.order.tx_ref as i64),
+                ],
+            )
+            .await
+            .map_err(|e| error::Error::Unexpected(Box::new(e)))
+    }
+}
+
+// transactions and snapshots kept in memory, for simulations and tests
+#[derive(Default)]
+pub struct MemoryTransactions {
+    // exchange and transaction, in the order they were stored
+    transactions: Vec<(String, Transaction)>,
+    // fingerprint and state by name
+    snapshots: RefCell<HashMap<String, (String, serde_json::Value)>>,
+}
+
+impl MemoryTransactions {
+    pub fn transactions(&self) -> &[(String, Transaction)] {
+        &self.transactions
+    }
+}
+
+#[async_trait(?Send)]
+impl TransactionStore for MemoryTransactions {
+    async fn store(&mut self, exchange: &str, tx: &Transaction) -> Result<u64, error::Error> {
+        self.transactions.push((String::from(exchange), tx.clone()));
+        Ok(1)
+    }
+
+    async fn open_positions(&self, exchange: &str, sym: &Symbol) -> Result<Vec<Transaction>, error::Error> {
+        let on_symbol = || {
+            self.transactions
+                .iter()
+                .filter(|(exc, tx)| exc == exchange && tx.symbol == sym.symbol)
+                .map(|(_, tx)| tx)
+        };
+        let mut open: Vec<Transaction> = on_symbol()
+            .filter(|buy| buy.side == Side::Buy)
+            .filter(|buy| !on_symbol().any(|sell| sell.side == Side::Sell && sell.order.tx_ref == buy.order.id))
+            .cloned()
+            .collect();
+        open.sort_by_key(|tx| tx.tstamp);
+        Ok(open)
+    }
+
+    async fn save_snapshot(&self, name: &str, fingerprint: &str, state: &serde_json::Value) -> Result<u64, error::Error> {
+        self.snapshots
+            .borrow_mut()
+            .insert(String::from(name), (String::from(fingerprint), state.clone()));
+        Ok(1)
+    }
+
+    async fn load_snapshot(&self, name: &str, fingerprint: &str) -> Result<Option<serde_json::Value>, error::Error> {
+        Ok(self
+            .snapshots
+            .borrow()
+            .get(name)
+            .filter(|(stored, _)| stored == fingerprint)
+            .map(|(_, state)| state.clone()))
     }
 }
@@ -77,8 +77,8 @@ impl SpotSinglePairStrategy for BBBMfiScalp {
         format!(
             "BBBMfiScalp-{}-{}-{}",
             self.exchange,
-            self.sym.to_string(),
-            self.time_frame.to_string()
+            self.sym,
+            self.time_frame
         )
     }
 
@@ -109,7 +109,7 @@ impl SpotSinglePairStrategy for BBBMfiScalp {
         if  outstanding_orders.len() > self.max_outstanding_orders {
             return Action::None;
         } 
-        let youngest_order = outstanding_orders.last().map(|o| o.tstamp).flatten().unwrap_or(self.starting_time);
+        let youngest_order = outstanding_orders.last().and_then(|o| o.tstamp).unwrap_or(self.starting_time);
         if cnd.tstamp - youngest_order <  chrono::Duration::hours(12) {
             return Action::None;
         }
@@ -145,6 +145,17 @@ impl SpotSinglePairStrategy for BBBMfiScalp {
         }
     }
 
+    // the indicators are rebuilt by initialize, from fresher candles than any snapshot
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::json!({ "starting_time": self.starting_time })
+    }
+    fn restore(&mut self, state: serde_json::Value) {
+        match serde_json::from_value::<NaiveDateTime>(state["starting_time"].clone()) {
+            Ok(starting_time) => self.starting_time = starting_time,
+            Err(e) => error!("{} - can't restore state {}", self.name(), e),
+        }
+    }
+
     fn get_candles_history_size(&self) -> usize {
         1
     }
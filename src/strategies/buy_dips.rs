@@ -46,7 +46,7 @@ impl BuyDips {
 
 impl SpotSinglePairStrategy for BuyDips {
     fn name(&self) -> String {
-        format!("BuyDips-{}-{}-{}", self.exchange, self.sym.to_string(), self.time_frame.to_string())
+        format!("BuyDips-{}-{}-{}", self.exchange, self.sym, self.time_frame)
     }
     fn on_new_candle(&mut self, wallet: &SpotWallet, _outstanding_orders: &[Order], history: &[Candle]) -> Action {
         if self.ongoing_ops >= self.max_ops {
@@ -88,6 +88,18 @@ impl SpotSinglePairStrategy for BuyDips {
         order.tx_ref = tx.order.id;
         Action::NewOrder(order)
     }
+    // their take profit sells are still outstanding on the exchange
+    fn restore_position(&mut self, open: &[Transaction]) {
+        self.ongoing_ops = open.len();
+    }
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::json!({ "ongoing_ops": self.ongoing_ops })
+    }
+    fn restore(&mut self, state: serde_json::Value) {
+        if let Some(ongoing_ops) = state["ongoing_ops"].as_u64() {
+            self.ongoing_ops = ongoing_ops as usize;
+        }
+    }
     fn get_candles_history_size(&self) -> usize {
         self.period
     }
@@ -3,7 +3,8 @@ use crate::orders::{Order, Side, Transaction, Type};
 use crate::strategies::{Action, SpotSinglePairStrategy};
 use crate::symbol::Symbol;
 use crate::wallets::SpotWallet;
-use log::debug;
+use chrono::NaiveDateTime;
+use log::{debug, error};
 use std::collections::{VecDeque, HashMap};
 use ta::indicators::MovingAverageConvergenceDivergence;
 use ta::{Next, Reset};
@@ -22,6 +23,18 @@ pub struct Macd1 {
     signal_trend: VecDeque<f64>,
     histo_trend: VecDeque<f64>,
     last_tx: Option<Transaction>,
+    // start of the last candle seen
+    last_tstamp: Option<NaiveDateTime>,
+}
+
+// the open position is rebuilt from the stored transactions
+#[derive(serde::Serialize, serde::Deserialize)]
+struct State {
+    macd: MovingAverageConvergenceDivergence,
+    macd_trend: VecDeque<f64>,
+    signal_trend: VecDeque<f64>,
+    histo_trend: VecDeque<f64>,
+    last_tstamp: Option<NaiveDateTime>,
 }
 
 impl Macd1 {
@@ -53,18 +66,24 @@ impl Macd1 {
             signal_trend: VecDeque::new(),
             histo_trend: VecDeque::new(),
             last_tx: None,
+            last_tstamp: None,
         }
     }
 }
 
 impl SpotSinglePairStrategy for Macd1 {
     fn name(&self) -> String {
-        format!("Macd1-{}-{}-{}", self.exchange, self.sym.to_string(), self.time_frame.to_string())
+        format!("Macd1-{}-{}-{}", self.exchange, self.sym, self.time_frame)
     }
     fn on_new_candle(&mut self, wallet: &SpotWallet, outstanding_orders: &[Order], history: &[Candle]) -> Action {
         let last_cnd = history.first().unwrap();
         let last_price = last_cnd.close;
         let tstamp = last_cnd.tstamp;
+        // the indicator starts over from the history when candles were missed, i.e. while restarting
+        if self.last_tstamp.is_some_and(|last| last + self.time_frame != tstamp) {
+            self.macd_1st_start = true;
+        }
+        self.last_tstamp = Some(tstamp);
         if self.macd_1st_start {
             self.macd.reset();
             self.macd_trend.clear();
@@ -124,8 +143,7 @@ impl SpotSinglePairStrategy for Macd1 {
             return Action::None;
         }
 
-        let action = if sell_signal && self.last_tx.is_some() {
-            let tx = self.last_tx.as_ref().unwrap();
+        let action = if let Some(tx) = self.last_tx.as_ref().filter(|_| sell_signal) {
             let volume = tx.volume.min(tx.avg_price * tx.volume / last_price);
             if *wallet.assets.get(&self.sym.base).expect("no base") < volume {
                 panic!(
@@ -165,6 +183,38 @@ impl SpotSinglePairStrategy for Macd1 {
         Action::None
     }
 
+    fn restore_position(&mut self, open: &[Transaction]) {
+        self.last_tx = open.last().cloned();
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        if self.macd_1st_start {
+            return serde_json::Value::Null;
+        }
+        serde_json::to_value(State {
+            macd: self.macd.clone(),
+            macd_trend: self.macd_trend.clone(),
+            signal_trend: self.signal_trend.clone(),
+            histo_trend: self.histo_trend.clone(),
+            last_tstamp: self.last_tstamp,
+        })
+        .expect("in serializing Macd1 state")
+    }
+
+    fn restore(&mut self, state: serde_json::Value) {
+        match serde_json::from_value::<State>(state) {
+            Ok(state) => {
+                self.macd = state.macd;
+                self.macd_trend = state.macd_trend;
+                self.signal_trend = state.signal_trend;
+                self.histo_trend = state.histo_trend;
+                self.last_tstamp = state.last_tstamp;
+                self.macd_1st_start = false;
+            }
+            Err(e) => error!("{} - can't restore state {}", self.name(), e),
+        }
+    }
+
     fn get_candles_history_size(&self) -> usize {
         self.history_len
     }
@@ -4,7 +4,8 @@ use crate::orders::{Order, Side, Transaction, Type};
 use crate::strategies::{Action, SpotSinglePairStrategy};
 use crate::symbol::Symbol;
 use crate::wallets::SpotWallet;
-use log::debug;
+use chrono::NaiveDateTime;
+use log::{debug, error};
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use ta::indicators::MovingAverageConvergenceDivergence;
@@ -27,6 +28,22 @@ pub struct Macd2 {
     fast_signal_trend: VecDeque<f64>,
     fast_histo_trend: VecDeque<f64>,
     last_tx: Option<Transaction>,
+    // start of the last candle seen
+    last_tstamp: Option<NaiveDateTime>,
+}
+
+// the open position is rebuilt from the stored transactions
+#[derive(serde::Serialize, serde::Deserialize)]
+struct State {
+    slow_macd: MovingAverageConvergenceDivergence,
+    fast_macd: MovingAverageConvergenceDivergence,
+    slow_macd_trend: VecDeque<f64>,
+    slow_signal_trend: VecDeque<f64>,
+    slow_histo_trend: VecDeque<f64>,
+    fast_macd_trend: VecDeque<f64>,
+    fast_signal_trend: VecDeque<f64>,
+    fast_histo_trend: VecDeque<f64>,
+    last_tstamp: Option<NaiveDateTime>,
 }
 
 impl Macd2 {
@@ -77,18 +94,24 @@ impl Macd2 {
             fast_signal_trend: VecDeque::new(),
             fast_histo_trend: VecDeque::new(),
             last_tx: None,
+            last_tstamp: None,
         }
     }
 }
 
 impl SpotSinglePairStrategy for Macd2 {
     fn name(&self) -> String {
-        format!("macd2-{}-{}-{}", self.exchange, self.sym.to_string(), self.time_frame.to_string())
+        format!("macd2-{}-{}-{}", self.exchange, self.sym, self.time_frame)
     }
     fn on_new_candle(&mut self, wallet: &SpotWallet, outstanding_orders: &[Order], history: &[Candle]) -> Action {
         let last_cnd = history.first().unwrap();
         let last_price = last_cnd.close;
         let tstamp = last_cnd.tstamp;
+        // the indicators start over from the history when candles were missed, i.e. while restarting
+        if self.last_tstamp.is_some_and(|last| last + self.time_frame != tstamp) {
+            self.macd_1st_start = true;
+        }
+        self.last_tstamp = Some(tstamp);
         if self.macd_1st_start {
             self.slow_macd.reset();
             self.fast_macd.reset();
@@ -178,8 +201,7 @@ impl SpotSinglePairStrategy for Macd2 {
             return Action::None;
         }
 
-        let action = if sell_signal && self.last_tx.is_some() {
-            let tx = self.last_tx.as_ref().unwrap();
+        let action = if let Some(tx) = self.last_tx.as_ref().filter(|_| sell_signal) {
             let volume = tx.volume.min(tx.avg_price * tx.volume / last_price);
             if *wallet.assets.get(&self.sym.base).expect("no base") < volume {
                 panic!(
@@ -219,6 +241,46 @@ impl SpotSinglePairStrategy for Macd2 {
         Action::None
     }
 
+    fn restore_position(&mut self, open: &[Transaction]) {
+        self.last_tx = open.last().cloned();
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        if self.macd_1st_start {
+            return serde_json::Value::Null;
+        }
+        serde_json::to_value(State {
+            slow_macd: self.slow_macd.clone(),
+            fast_macd: self.fast_macd.clone(),
+            slow_macd_trend: self.slow_macd_trend.clone(),
+            slow_signal_trend: self.slow_signal_trend.clone(),
+            slow_histo_trend: self.slow_histo_trend.clone(),
+            fast_macd_trend: self.fast_macd_trend.clone(),
+            fast_signal_trend: self.fast_signal_trend.clone(),
+            fast_histo_trend: self.fast_histo_trend.clone(),
+            last_tstamp: self.last_tstamp,
+        })
+        .expect("in serializing Macd2 state")
+    }
+
+    fn restore(&mut self, state: serde_json::Value) {
+        match serde_json::from_value::<State>(state) {
+            Ok(state) => {
+                self.slow_macd = state.slow_macd;
+                self.fast_macd = state.fast_macd;
+                self.slow_macd_trend = state.slow_macd_trend;
+                self.slow_signal_trend = state.slow_signal_trend;
+                self.slow_histo_trend = state.slow_histo_trend;
+                self.fast_macd_trend = state.fast_macd_trend;
+                self.fast_signal_trend = state.fast_signal_trend;
+                self.fast_histo_trend = state.fast_histo_trend;
+                self.last_tstamp = state.last_tstamp;
+                self.macd_1st_start = false;
+            }
+            Err(e) => error!("{} - can't restore state {}", self.name(), e),
+        }
+    }
+
     fn get_candles_history_size(&self) -> usize {
         self.history_len
     }
@@ -1,6 +1,6 @@
 use crate::candles::Candle;
 use crate::error::Error;
-use crate::orders::{Order, Side, Transaction, Type};
+use crate::orders::{Order, OrderId, Side, Transaction, Type};
 use crate::symbol::Symbol;
 use crate::wallets::SpotWallet;
 use std::collections::HashMap;
@@ -17,11 +17,13 @@ pub use macd1::Macd1;
 pub use macd2::Macd2;
 pub use sample::Sample;
 
+// short lived, not worth boxing the order
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Action {
     None,
     NewOrder(Order),
-    CancelOrder(String, u32),
+    CancelOrder(String, OrderId),
 }
 
 // a 1-symbol strategy
@@ -34,6 +36,14 @@ pub trait SpotSinglePairStrategy {
     // history: 0 -> newest candle
     fn on_new_candle(&mut self, wallet: &SpotWallet, outstanding_orders: &[Order], history: &[Candle]) -> Action;
     fn on_new_transaction(&mut self, outstanding_orders: &[Order], tx: &Transaction) -> Action;
+    // buys not closed by a sell yet when the live loop starts, oldest first
+    fn restore_position(&mut self, _open: &[Transaction]) {}
+    // internal state kept across live restarts, null when there is nothing worth keeping
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+    // a snapshot taken with the same strategy settings
+    fn restore(&mut self, _state: serde_json::Value) {}
 
     fn get_candles_history_size(&self) -> usize;
     fn get_candles_init_size(&self) -> usize {
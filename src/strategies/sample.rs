@@ -25,7 +25,7 @@ impl Sample {
 
 impl SpotSinglePairStrategy for Sample {
     fn name(&self) -> String {
-        format!("Sample-{}-{}-{}", self.exchange, self.sym.to_string(), self.time_frame.to_string())
+        format!("Sample-{}-{}-{}", self.exchange, self.sym, self.time_frame)
     }
     fn on_new_candle(&mut self, _wallet: &SpotWallet, _outstanding_orders: &[Order], history: &[Candle]) -> Action {
         println!("at iteration {}", self.index);
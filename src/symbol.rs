@@ -29,9 +29,9 @@ impl Symbol {
     }
 }
 
-impl std::string::ToString for Symbol {
-    fn to_string(&self) -> String {
-        self.pretty.clone()
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.pretty)
     }
 }
 
@@ -7,3 +7,8 @@ pub fn generate_random_tstamp(start: &NaiveDateTime, end: &NaiveDateTime) -> Nai
     let rand_time = Duration::seconds(rng.gen_range(0..time_frame.num_seconds()));
     *start + rand_time
 }
+
+// utc time of a unix timestamp, none when out of range
+pub fn from_timestamp(secs: i64, nanos: u32) -> Option<NaiveDateTime> {
+    chrono::DateTime::from_timestamp(secs, nanos).map(|dt| dt.naive_utc())
+}
@@ -1,12 +1,6 @@
 use std::collections::HashMap;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct SpotWallet {
     pub assets: HashMap<String, f64>,
 }
-
-impl Default for SpotWallet {
-    fn default() -> Self {
-        Self { assets: HashMap::new() }
-    }
-}